@@ -1,26 +1,62 @@
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Command>,
+
+    /// Answer yes to every confirmation prompt, for scripts. This bypasses safety confirmations.
+    #[arg(short = 'y', long, global = true)]
+    pub yes: bool,
 }
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct StageArgs {
-    /// Path to a file to add or remove from the context stage.
+    #[command(subcommand)]
+    pub command: Option<StageSubcommand>,
+
+    /// Path to a file to add or remove from the context stage, or `-` to stage stdin as read-only content.
     pub file_path: Option<String>,
 
+    /// The name to label content staged from stdin with.
+    #[arg(long, requires = "file_path")]
+    pub name: Option<String>,
+
     /// Stage the file as read-only.
-    #[arg(short = 'r', long, requires = "file_path")]
+    #[arg(short = 'r', long, requires = "file_path", conflicts_with = "drop")]
     pub read_only: bool,
 
+    /// Stage the file as read-write, when `default_stage_read_only` is set.
+    #[arg(long = "rw", requires = "file_path", conflicts_with_all = &["drop", "read_only"])]
+    pub read_write: bool,
+
     /// Remove the file from the context stage.
     #[arg(long, short = 'd', requires = "file_path")]
     pub drop: bool,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum StageSubcommand {
+    /// Rename a path in the context, keeping its read-only/read-write mode
+    Mv {
+        /// The path currently in the context
+        old: String,
+        /// The path to use instead
+        new: String,
+    },
+    /// Show the context for the next message
+    Status {
+        /// One tab-separated line per file: mode, provenance, existence, hash, path
+        #[arg(long)]
+        porcelain: bool,
+    },
+}
+
+// Only one of these exists per run, so `Send` being much larger than the rest costs nothing.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// List all chats
@@ -28,8 +64,38 @@ pub enum Command {
     /// Manage chat tags
     #[command(subcommand)]
     Tag(TagSubcommand),
+    /// Copy the messages up to a tag into a new, separate chain, and tag its head
+    Copy {
+        /// The tag to copy
+        tag: String,
+        /// The tag for the copy's head
+        new_tag: String,
+    },
     /// Stage files for chat context
     Stage(StageArgs),
+    /// Set or show the title and notes of the chat a tag belongs to
+    Title {
+        /// The chat tag
+        tag: String,
+        /// The new title. Shows the current title and notes if omitted.
+        title: Option<String>,
+        /// Set freeform notes for the chat
+        #[arg(long)]
+        notes: Option<String>,
+    },
+    /// Set or show the prompt template a chat's system prompt is built from
+    Template {
+        /// The chat tag
+        tag: String,
+        /// A `prompt_templates` name, or `default` for the built-in prompt. Shows the current one if omitted.
+        name: Option<String>,
+    },
+    /// Save and load named sets of staged files
+    #[command(subcommand)]
+    Context(ContextSubcommand),
+    /// Manage the config file
+    #[command(subcommand)]
+    Config(ConfigSubcommand),
     /// Manage profiles
     Profile {
         /// Set the active chat tag for the default profile
@@ -52,21 +118,125 @@ pub enum Command {
         /// Explicitly treat the target as a message ID
         #[arg(short, long)]
         message: bool,
+
+        /// Print the messages as a JSON array of {role, content, created_at}
+        #[arg(long)]
+        json: bool,
+
+        /// Hide file-context turns and collapse long code blocks, leaving the conversation itself
+        #[arg(long)]
+        compact: bool,
+    },
+    /// Show a single message
+    Show {
+        /// The message ID to show
+        message: i64,
+        /// Also print the message's stored metadata as JSON
+        #[arg(long)]
+        metadata: bool,
+    },
+    /// List the alternatives to a message: every child of its parent
+    Siblings {
+        /// The message whose alternatives to list
+        message: i64,
+    },
+    /// List every file a chat's turns have had in context, with how many turns used each
+    LsFiles {
+        /// The chat tag
+        tag: String,
+    },
+    /// Check the files a message was sent with against disk: whether each still matches, differs or is missing
+    Verify {
+        /// A chat tag, or a message ID
+        target: String,
+    },
+    /// List the models available for a backend
+    Models {
+        /// The backend to list models for. Defaults to the configured backend.
+        #[arg(long)]
+        backend: Option<String>,
+    },
+    /// Diagnostic commands for bug reports
+    #[command(subcommand, hide = true)]
+    Debug(DebugSubcommand),
+    /// Check that the prompt templates load and render
+    Doctor {
+        /// Also check that the system prompts describe exactly the edit blocks the parser reads
+        #[arg(long)]
+        edit_format_probe: bool,
+    },
+    /// Show the active profile, chat, stage and backend at a glance
+    #[command(visible_alias = "whoami")]
+    Status,
+    /// Re-apply and commit the edits from a past assistant message
+    ReplayEdits {
+        /// The assistant message ID whose edits to re-apply
+        message: i64,
+        /// Apply the edits even if there are more than `max_edits_per_response`
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show how a file changed over a chat by replaying its edits onto a scratch copy
+    ReplayHistory {
+        /// The chat tag
+        tag: String,
+        /// The file whose versions to show
+        #[arg(long, value_name = "FILE")]
+        to: String,
+        /// A file holding the content to start from. Defaults to an empty file.
+        #[arg(long, value_name = "PATH")]
+        base: Option<PathBuf>,
+    },
+    /// Write the edits from a past assistant message as a patch, without touching the files
+    ExportCommit {
+        /// The assistant message ID whose edits to export
+        message: i64,
+        /// Write the patch to this file instead of stdout
+        #[arg(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Add a message directly, without calling the model
+    Add {
+        /// The message content
+        content: String,
+        /// The message role: system, user or assistant
+        #[arg(long, default_value = "user")]
+        role: String,
+        /// The parent message ID. Starts a new conversation if omitted.
+        #[arg(long)]
+        parent: Option<i64>,
+    },
+    /// Import conversations from a JSON dump
+    Import {
+        /// Path to the JSON file to import
+        path: PathBuf,
     },
     /// Send a prompt to the model
     Send {
         /// The prompt to send
         prompt: Option<String>,
 
-        /// The parent message ID to continue from. Creates a new branch and does not update any tags.
+        /// The parent message ID to continue from. Creates a new branch and does not update any tags unless --tag is given.
         #[arg(long, conflicts_with_all = &["new", "chat"])]
         parent: Option<i64>,
 
-        /// The chat tag to continue from.
-        #[arg(long, conflicts_with = "new")]
+        /// With --parent, point this tag at the new branch's reply, creating or moving it.
+        #[arg(long, value_name = "TAG", requires = "parent")]
+        tag: Option<String>,
+
+        /// Branch from the turn before the one a tag points to, redoing the tag's latest turn. Does not update any tags.
+        #[arg(long, value_name = "TAG", conflicts_with_all = &["parent", "new", "chat"])]
+        parent_of: Option<String>,
+
+        /// The chat tag to continue from. With --new, the tag for the new chat instead.
+        #[arg(long)]
         chat: Option<String>,
 
-        /// Start a new chat, ignoring the active chat tag.
+        /// Continue from the most recent message in any chat, updating its tag if it has one.
+        #[arg(long, conflicts_with_all = &["parent", "parent_of", "chat", "new"])]
+        latest: bool,
+
+        /// Start a new chat, ignoring the active chat tag. Tags it only if --chat is given.
         #[arg(long)]
         new: bool,
 
@@ -78,6 +248,14 @@ pub enum Command {
         #[arg(long)]
         no_stream: bool,
 
+        /// How hard the model should think before answering (overrides config).
+        #[arg(long, value_name = "EFFORT", value_parser = ["low", "medium", "high"])]
+        reasoning: Option<String>,
+
+        /// Stop generation at this sequence. May be repeated (overrides config).
+        #[arg(long = "stop", value_name = "SEQUENCE")]
+        stop_sequences: Vec<String>,
+
         /// Ignore the inherited file context from the parent message.
         #[arg(long, short = 'i', conflicts_with = "new")]
         ignore_inherited_stage: bool,
@@ -89,9 +267,157 @@ pub enum Command {
         /// Open an editor to write the prompt.
         #[arg(long, short = 'e', conflicts_with = "prompt")]
         editor: bool,
+
+        /// Read the prompt, the files to send with it, and optionally the model and temperature
+        /// from a YAML task file.
+        #[arg(long, value_name = "PATH", conflicts_with_all = &["prompt", "editor"])]
+        task: Option<PathBuf>,
+
+        /// Emit newline-delimited JSON events to stdout instead of human-readable output.
+        #[arg(long, conflicts_with = "confirm")]
+        json_events: bool,
+
+        /// Reuse the exact file set of another message's turn instead of the staged and inherited context.
+        #[arg(
+            long,
+            value_name = "MESSAGE_ID",
+            conflicts_with = "ignore_inherited_stage"
+        )]
+        context_from: Option<i64>,
+
+        /// Read a list of paths from stdin, one per line, and send them as read-write files
+        /// for this message without staging them, e.g. `rg -l TODO | retort send --stdin-files ...`.
+        #[arg(long, conflicts_with_all = &["confirm", "editor"])]
+        stdin_files: bool,
+
+        /// Include the output of `git diff` from the project root as read-only context.
+        #[arg(long)]
+        diff: bool,
+
+        /// With --diff, include only staged changes (`git diff --staged`).
+        #[arg(long, requires = "diff")]
+        staged: bool,
+
+        /// Assemble the prompt and print its estimated size and cost without sending it.
+        #[arg(long, conflicts_with_all = &["confirm", "json_events"])]
+        estimate: bool,
+
+        /// Leave out the README that `include_readme_in_context` would add.
+        #[arg(long)]
+        no_readme: bool,
+
+        /// Print the messages that would be sent, with the system prompt and context files, as JSON and exit
+        /// without sending. For frontends with their own confirmation; send again to go ahead.
+        #[arg(long, conflicts_with_all = &["confirm", "json_events", "estimate"])]
+        preview_json: bool,
+
+        /// Drop files, largest read-only ones first, until the prompt fits `context_token_budget`.
+        #[arg(long)]
+        fit_context: bool,
+
+        /// Open the response in `$EDITOR` before its edits are applied; the edited text is applied and stored.
+        #[arg(long, conflicts_with = "json_events")]
+        review: bool,
+
+        /// Print how long the model took, and roughly how fast and how costly the response was.
+        #[arg(long, conflicts_with = "json_events")]
+        timings: bool,
+
+        /// Show the response in `$PAGER` once it is complete, instead of printing it.
+        #[arg(long, conflicts_with = "json_events")]
+        pager: bool,
+
+        /// Use this commit message for the response's edits instead of the one the model wrote.
+        #[arg(long, short = 'm', value_name = "MESSAGE")]
+        commit_message: Option<String>,
+
+        /// Give the commit this conventional commit type, e.g. `feat`, unless its message already has one.
+        #[arg(long, value_name = "TYPE")]
+        commit_type: Option<String>,
+
+        /// The conventional commit scope to go with --commit-type, e.g. `parser`.
+        #[arg(long, value_name = "SCOPE", requires = "commit_type")]
+        commit_scope: Option<String>,
+
+        /// Apply the response's edits even if there are more than `max_edits_per_response`.
+        #[arg(long)]
+        force: bool,
+
+        /// When some edits can't be applied, commit the ones that can instead of reverting them all.
+        #[arg(long)]
+        continue_on_edit_error: bool,
+
+        /// Refuse to apply edits while other tracked files have uncommitted changes (overrides config).
+        #[arg(long)]
+        require_clean_tree: bool,
+
+        /// Truncate the largest past messages until the history is at most this many bytes (overrides config).
+        #[arg(long, value_name = "BYTES")]
+        max_history_bytes: Option<usize>,
+
+        /// Only send the history after this message, e.g. to start fresh after a summary without branching.
+        #[arg(long, value_name = "MESSAGE_ID", conflicts_with = "new")]
+        history_after: Option<i64>,
+
+        /// Build the system prompt from this `prompt_templates` entry instead of the chat's.
+        /// A new chat keeps using it.
+        #[arg(long, value_name = "NAME")]
+        template: Option<String>,
+
+        /// Use this file's contents as the system prompt, as-is, instead of rendering a template.
+        #[arg(long, value_name = "PATH", conflicts_with = "template")]
+        system_file: Option<PathBuf>,
+
+        /// Start the assistant's reply with this text, on backends that support prefill.
+        #[arg(long, value_name = "TEXT")]
+        assistant_prefill: Option<String>,
     },
 }
 
+#[derive(Subcommand, Debug)]
+pub enum ContextSubcommand {
+    /// Save the current prepared stage as a named preset
+    Save {
+        /// The preset name
+        name: String,
+    },
+    /// Stage the files from a preset
+    Load {
+        /// The preset name
+        name: String,
+    },
+    /// List all presets
+    List,
+    /// Delete a preset
+    Delete {
+        /// The preset to delete
+        name: String,
+    },
+    /// Compare the context the next message would get from two tags or message IDs
+    Diff {
+        /// The first tag or message ID
+        a: String,
+        /// The second tag or message ID
+        b: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigSubcommand {
+    /// Write a commented config file listing every key with its default
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DebugSubcommand {
+    /// Print the database schema, user_version, and row counts per table
+    Schema,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum TagSubcommand {
     /// Create or update a tag for a message
@@ -101,6 +427,12 @@ pub enum TagSubcommand {
         /// The message ID to tag
         #[arg(short, long, required = true)]
         message: i64,
+        /// Only move an existing tag forward, to a descendant of the message it points to
+        #[arg(long, visible_alias = "move-head")]
+        descendant_only: bool,
+        /// With --descendant-only, move the tag even if the message isn't a descendant
+        #[arg(long, requires = "descendant_only")]
+        force: bool,
     },
     /// Delete a tag
     Delete {
@@ -109,4 +441,9 @@ pub enum TagSubcommand {
     },
     /// List all tags
     List,
+    /// Show the message a tag points to
+    Show {
+        /// The tag to show
+        tag: String,
+    },
 }
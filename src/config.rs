@@ -1,28 +1,243 @@
+use crate::hooks::formatter::FormatterConfig;
+use crate::hooks::postprocessor::EditFormat;
+use crate::prompt::{ContextPosition, PromptOverrides};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::PathBuf;
+
+/// The commented config written by `retort config init`, listing every key.
+pub const CONFIG_TEMPLATE: &str = include_str!("config_template.yaml");
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// Where chats, tags and the stage are stored. Defaults to `data/retort.db` under
+    /// the retort home.
+    #[serde(default = "default_database_path")]
     pub database_path: String,
+    /// The `llm` crate backend to chat with, e.g. `google` or `openai`.
+    #[serde(default = "default_backend")]
+    pub backend: String,
+    /// The model to use. Defaults to a known model for the backend.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Overrides the backend's API URL, e.g. for an OpenAI-compatible server.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// The environment variable holding the API key. Defaults to `<BACKEND>_API_KEY`.
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    /// Force streaming on or off. When unset, responses stream only when stdout is a terminal.
     #[serde(default)]
     pub stream: Option<bool>,
+    /// How hard the model should think before answering: `low`, `medium` or `high`.
+    /// Only some backends support it; others ignore it with a warning.
+    #[serde(default)]
+    pub reasoning_effort: Option<String>,
+    /// Sampling temperature. Defaults to 0.7.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Write streamed responses to `retort-response-<id>.txt` in the temp directory as they
+    /// arrive, so a partial response survives a crash. Removed once the response is stored.
+    #[serde(default)]
+    pub stream_backup: bool,
+    /// Generation is cut off at the first occurrence of any of these sequences.
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    /// Regex substitutions applied, in order, to each response before its edits are parsed.
+    #[serde(default)]
+    pub response_filters: Vec<ResponseFilter>,
+    /// Tag new chats with a slug of their first prompt and make them the active chat.
+    #[serde(default)]
+    pub auto_tag_new_chats: bool,
+    /// What an empty answer to a confirmation prompt means.
+    #[serde(default = "default_confirm_default")]
+    pub confirm_default: bool,
+    /// Commands run on edited files before they are committed.
+    #[serde(default)]
+    pub formatters: Vec<FormatterConfig>,
+    /// If set, edits are only committed when this command succeeds, and reverted otherwise.
+    #[serde(default)]
+    pub test_command: Option<String>,
+    /// Whether files created by an edit end with a newline. Existing files keep whatever they had.
+    #[serde(default = "default_new_file_trailing_newline")]
+    pub new_file_trailing_newline: bool,
+    /// Price of prompt tokens, used by `send --estimate`.
+    #[serde(default)]
+    pub input_cost_per_million_tokens: Option<f64>,
+    /// Context files larger than this are left out of the prompt. `null` disables the limit.
+    #[serde(default = "default_max_staged_file_bytes")]
+    pub max_staged_file_bytes: Option<u64>,
+    /// The estimated prompt size, in tokens, that `send --fit-context` trims the context down to.
+    #[serde(default)]
+    pub context_token_budget: Option<usize>,
+    /// Ask before sending a message with more context files than this, even without `--confirm`.
+    #[serde(default)]
+    pub confirm_context_files: Option<usize>,
+    /// Ask before sending a message whose context files add up to more bytes than this.
+    #[serde(default)]
+    pub confirm_context_bytes: Option<usize>,
+    /// The most bytes of history sent with a message. Over it, the largest past messages are
+    /// truncated; the current prompt never is.
+    #[serde(default)]
+    pub max_history_bytes: Option<usize>,
+    /// Stage files as read-only unless `--rw` is passed, instead of the other way around.
+    #[serde(default)]
+    pub default_stage_read_only: bool,
+    /// Globs, like `*.lock` or `vendor/**`, for files that are always staged read-only unless
+    /// `--rw` is passed. A pattern without a `/` is matched against the file name alone.
+    #[serde(default)]
+    pub force_read_only_patterns: Vec<String>,
+    /// Add the project root's `README.md` to every send as a read-only file.
+    #[serde(default)]
+    pub include_readme_in_context: bool,
+    /// Replacements for parts of the built-in prompt text.
+    #[serde(default)]
+    pub prompt_overrides: PromptOverrides,
+    /// Where file blocks go in the prompt: `before_history` or `before_last_user`.
+    #[serde(default)]
+    pub context_position: ContextPosition,
+    /// Alternative system prompt templates, by name, as paths to `.j2` files. A chat
+    /// can be set to one with `retort template`; `default` is the built-in prompt.
+    #[serde(default)]
+    pub prompt_templates: HashMap<String, String>,
+    /// The edit blocks the model is told to use and responses are parsed for:
+    /// `search_replace_append` or `search_replace`.
+    #[serde(default)]
+    pub edit_format: EditFormat,
+    /// How many times a failed `git commit` is retried after re-staging the edited files.
+    #[serde(default = "default_commit_retries")]
+    pub commit_retries: u32,
+    /// Put in front of every commit message Retort makes, e.g. `ai:`.
+    #[serde(default)]
+    pub commit_prefix: Option<String>,
+    /// Refuse to apply edits while other tracked files have uncommitted changes.
+    #[serde(default)]
+    pub require_clean_tree: bool,
+    /// Refuse to apply a response with more edit blocks than this, unless `--force` is passed.
+    #[serde(default)]
+    pub max_edits_per_response: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseFilter {
+    /// Regex matched against the whole response.
+    pub pattern: String,
+    /// What each match is replaced with; `$1` and `${name}` refer to capture groups.
+    #[serde(default)]
+    pub replacement: String,
+}
+
+fn default_backend() -> String {
+    "google".to_string()
+}
+
+fn default_max_staged_file_bytes() -> Option<u64> {
+    Some(1024 * 1024)
+}
+
+fn default_commit_retries() -> u32 {
+    1
+}
+
+fn default_database_path() -> String {
+    format!("{}/data/retort.db", retort_home())
+}
+
+fn default_confirm_default() -> bool {
+    true
+}
+
+fn default_new_file_trailing_newline() -> bool {
+    true
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            database_path: "~/.retort/data/retort.db".to_string(),
+            database_path: default_database_path(),
+            backend: default_backend(),
+            model: None,
+            base_url: None,
+            api_key_env: None,
             stream: None,
+            reasoning_effort: None,
+            temperature: None,
+            auto_tag_new_chats: false,
+            stream_backup: false,
+            stop_sequences: Vec::new(),
+            response_filters: Vec::new(),
+            confirm_default: default_confirm_default(),
+            formatters: Vec::new(),
+            test_command: None,
+            new_file_trailing_newline: default_new_file_trailing_newline(),
+            input_cost_per_million_tokens: None,
+            max_staged_file_bytes: default_max_staged_file_bytes(),
+            context_token_budget: None,
+            confirm_context_files: None,
+            confirm_context_bytes: None,
+            max_history_bytes: None,
+            default_stage_read_only: false,
+            force_read_only_patterns: Vec::new(),
+            include_readme_in_context: false,
+            prompt_overrides: PromptOverrides::default(),
+            context_position: ContextPosition::default(),
+            prompt_templates: HashMap::new(),
+            edit_format: EditFormat::default(),
+            commit_retries: default_commit_retries(),
+            commit_prefix: None,
+            require_clean_tree: false,
+            max_edits_per_response: None,
         }
     }
 }
 
+/// The directory retort keeps its files in: `$RETORT_HOME` if set, or `~/.retort`.
+pub fn retort_home() -> String {
+    match std::env::var("RETORT_HOME") {
+        Ok(home) if !home.is_empty() => home.trim_end_matches('/').to_string(),
+        _ => "~/.retort".to_string(),
+    }
+}
+
+/// The config file path: `$RETORT_CONFIG` if set, or `config.yaml` in the retort home.
+pub fn config_path() -> PathBuf {
+    let config_path_str =
+        std::env::var("RETORT_CONFIG").unwrap_or_else(|_| format!("{}/config.yaml", retort_home()));
+    PathBuf::from(shellexpand::tilde(&config_path_str).as_ref())
+}
+
+/// Writes the commented config template to the config path, returning the path.
+/// An existing config is only replaced with `force`.
+pub fn init(force: bool) -> Result<PathBuf> {
+    let config_path = config_path();
+    if config_path.exists() && !force {
+        anyhow::bail!(
+            "{} already exists; pass --force to overwrite it.",
+            config_path.display()
+        );
+    }
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&config_path, CONFIG_TEMPLATE)?;
+    Ok(config_path)
+}
+
+/// Loads the config from `$RETORT_CONFIG` if set, or `config.yaml` in the retort home.
+/// A missing default config falls back to defaults, but an explicit one must exist.
 pub fn load() -> Result<Config> {
-    let config_path_str = "~/.retort/config.yaml";
-    let expanded_config_path = shellexpand::tilde(config_path_str);
-    let config_path = Path::new(expanded_config_path.as_ref());
+    let env_config_path = std::env::var("RETORT_CONFIG").ok();
+    let config_path = config_path();
+    let config_path = config_path.as_path();
+
+    if env_config_path.is_some() && !config_path.exists() {
+        anyhow::bail!(
+            "Config file {} (from RETORT_CONFIG) not found.",
+            config_path.display()
+        );
+    }
 
     let mut config: Config = if config_path.exists() {
         let file_contents = fs::read_to_string(config_path)?;
@@ -1,9 +1,12 @@
 use anyhow::Result;
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 
+pub const VALID_ROLES: &[&str] = &["system", "user", "assistant"];
+
 // Internal struct for serialization to avoid breaking changes to the public API
 // and to handle DB data format migration gracefully.
 #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
@@ -11,6 +14,8 @@ struct PreparedContext {
     read_write_files: Vec<String>,
     read_only_files: Vec<String>,
     dropped_files: Vec<String>,
+    #[serde(default)]
+    inline_files: Vec<InlineFile>,
 }
 
 pub fn setup(db_path_str: &str) -> Result<Connection> {
@@ -35,6 +40,8 @@ pub fn setup(db_path_str: &str) -> Result<Connection> {
             FOREIGN KEY (parent_id) REFERENCES messages (id)
         );
 
+        -- Finding leaves and children looks messages up by parent; without this, `list` is quadratic.
+        CREATE INDEX IF NOT EXISTS idx_messages_parent_id ON messages (parent_id);
 
         CREATE TABLE IF NOT EXISTS chat_tags (
             tag TEXT PRIMARY KEY NOT NULL,
@@ -58,12 +65,82 @@ pub fn setup(db_path_str: &str) -> Result<Connection> {
         );
 
         INSERT OR IGNORE INTO context_stages (name, read_write_files, read_only_files) VALUES ('default', '[]', '[]');
+
+        CREATE TABLE IF NOT EXISTS context_presets (
+            name TEXT PRIMARY KEY NOT NULL,
+            files TEXT NOT NULL -- JSON PreparedContext
+        );
+
+        -- Titles and notes belong to a conversation's root message, so every branch shares them.
+        CREATE TABLE IF NOT EXISTS conversations (
+            root_id INTEGER PRIMARY KEY NOT NULL,
+            title TEXT,
+            notes TEXT,
+            FOREIGN KEY (root_id) REFERENCES messages (id)
+        );
         ",
     )?;
 
+    // Columns added after their table was first created.
+    add_column_if_missing(&conn, "conversations", "prompt_template", "TEXT")?;
+    restore_compacted_chains(&conn)?;
+
     Ok(conn)
 }
 
+/// Moves messages folded into the `compacted_chains` table by the removed `retort gc`
+/// back to rows of their own, with their IDs and parents, and drops the table.
+fn restore_compacted_chains(conn: &Connection) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'compacted_chains')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(());
+    }
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(
+        "
+        INSERT INTO messages (id, parent_id, role, content, metadata, created_at)
+        SELECT json_extract(j.value, '$.id'), json_extract(j.value, '$.parent_id'),
+               json_extract(j.value, '$.role'), json_extract(j.value, '$.content'),
+               json_extract(j.value, '$.metadata'), json_extract(j.value, '$.created_at')
+        FROM compacted_chains c, json_each(c.messages) j
+        ORDER BY c.head_id, j.key;
+        -- Each run's head was stored without a parent; its parent is the run's last message.
+        UPDATE messages SET parent_id = (
+            SELECT json_extract(c.messages, '$[#-1].id') FROM compacted_chains c
+            WHERE c.head_id = messages.id
+        )
+        WHERE id IN (SELECT head_id FROM compacted_chains);
+        DROP TABLE compacted_chains;
+        ",
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for existing in columns {
+        if existing? == column {
+            return Ok(());
+        }
+    }
+    conn.execute_batch(&format!(
+        "ALTER TABLE {} ADD COLUMN {} {}",
+        table, column, definition
+    ))?;
+    Ok(())
+}
+
 pub struct Tag {
     pub name: String,
     pub message_id: i64,
@@ -74,22 +151,67 @@ pub struct Leaf {
     pub created_at: String,
     pub content: String,
     pub tag: Option<String>,
+    /// The content of the nearest user message at or above the leaf, if any.
+    pub last_user_content: Option<String>,
+    pub root_id: i64,
+    /// The title of the leaf's conversation, if it has one.
+    pub title: Option<String>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub id: i64,
+    pub parent_id: Option<i64>,
+    pub role: String,
+    pub content: String,
+    pub created_at: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct HistoryMessage {
     pub role: String,
     pub content: String,
     pub created_at: String,
 }
 
+/// Opens a fresh in-memory database with the full schema, for tests.
+pub fn setup_in_memory() -> Result<Connection> {
+    // Passing ":memory:" to rusqlite creates an in-memory database.
+    setup(":memory:")
+}
+
 pub fn get_leaf_messages(conn: &Connection) -> Result<Vec<Leaf>> {
     let mut stmt = conn.prepare(
         "
-        SELECT m1.id, m1.created_at, m1.content, ct.tag
+        WITH RECURSIVE
+        leaves AS (
+            SELECT m1.id FROM messages m1
+            WHERE NOT EXISTS (SELECT 1 FROM messages m2 WHERE m2.parent_id = m1.id)
+        ),
+        -- Walks up from each leaf, stopping at the first user message.
+        walk(leaf_id, parent_id, role, content) AS (
+            SELECT m.id, m.parent_id, m.role, m.content
+            FROM messages m JOIN leaves l ON m.id = l.id
+            UNION ALL
+            SELECT w.leaf_id, p.parent_id, p.role, p.content
+            FROM walk w JOIN messages p ON p.id = w.parent_id
+            WHERE w.role != 'user'
+        ),
+        -- Walks up from each leaf to its root by ID alone, for the conversation's title.
+        up(leaf_id, id, parent_id) AS (
+            SELECT m.id, m.id, m.parent_id
+            FROM messages m JOIN leaves l ON m.id = l.id
+            UNION ALL
+            SELECT u.leaf_id, p.id, p.parent_id
+            FROM up u JOIN messages p ON p.id = u.parent_id
+        )
+        SELECT m1.id, COALESCE(m1.created_at, ''), m1.content, ct.tag, u.content, r.id, cv.title
         FROM messages m1
+        JOIN leaves l ON m1.id = l.id
         LEFT JOIN chat_tags ct ON m1.id = ct.message_id
-        WHERE NOT EXISTS (SELECT 1 FROM messages m2 WHERE m2.parent_id = m1.id)
+        LEFT JOIN walk u ON u.leaf_id = m1.id AND u.role = 'user'
+        JOIN up r ON r.leaf_id = m1.id AND r.parent_id IS NULL
+        LEFT JOIN conversations cv ON cv.root_id = r.id
         ORDER BY m1.created_at DESC, m1.id DESC;
         ",
     )?;
@@ -100,6 +222,9 @@ pub fn get_leaf_messages(conn: &Connection) -> Result<Vec<Leaf>> {
             created_at: row.get(1)?,
             content: row.get(2)?,
             tag: row.get(3)?,
+            last_user_content: row.get(4)?,
+            root_id: row.get(5)?,
+            title: row.get(6)?,
         })
     })?;
 
@@ -114,15 +239,19 @@ pub fn get_conversation_history(conn: &Connection, leaf_id: i64) -> Result<Vec<H
     let mut stmt = conn.prepare(
         "
         WITH RECURSIVE ancestors AS (
-            SELECT id, parent_id, role, content, created_at
+            SELECT id, parent_id, role, content, created_at, 0 AS depth
             FROM messages
             WHERE id = ?1
             UNION ALL
-            SELECT m.id, m.parent_id, m.role, m.content, m.created_at
+            SELECT m.id, m.parent_id, m.role, m.content, m.created_at, a.depth + 1
             FROM messages m
             JOIN ancestors a ON m.id = a.parent_id
         )
-        SELECT role, content, created_at FROM ancestors ORDER BY created_at ASC, id ASC;
+        -- Order by position in the tree rather than by timestamp, so rows with a NULL or
+        -- malformed created_at (e.g. from manual edits or imports) can't scramble the turns.
+        SELECT role, content, COALESCE(created_at, '')
+        FROM ancestors
+        ORDER BY depth DESC;
         ",
     )?;
 
@@ -141,6 +270,27 @@ pub fn get_conversation_history(conn: &Connection, leaf_id: i64) -> Result<Vec<H
     Ok(messages)
 }
 
+/// Returns the IDs of `leaf_id` and its ancestors, root first, in the same order
+/// as `get_conversation_history`.
+pub fn get_ancestor_ids(conn: &Connection, leaf_id: i64) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare(
+        "
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id, 0 AS depth FROM messages WHERE id = ?1
+            UNION ALL
+            SELECT m.id, m.parent_id, a.depth + 1
+            FROM messages m
+            JOIN ancestors a ON m.id = a.parent_id
+        )
+        SELECT id FROM ancestors ORDER BY depth DESC;
+        ",
+    )?;
+    let ids = stmt
+        .query_map([leaf_id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+    Ok(ids)
+}
+
 pub fn add_message(
     conn: &Connection,
     parent_id: Option<i64>,
@@ -148,13 +298,71 @@ pub fn add_message(
     content: &str,
     metadata: Option<&str>,
 ) -> Result<i64> {
+    add_message_at(conn, parent_id, role, content, metadata, None)
+}
+
+/// Like `add_message`, but stores `created_at` (a `YYYY-MM-DD HH:MM:SS` UTC timestamp)
+/// instead of the current time when given. Used by imports and deterministic tests.
+pub fn add_message_at(
+    conn: &Connection,
+    parent_id: Option<i64>,
+    role: &str,
+    content: &str,
+    metadata: Option<&str>,
+    created_at: Option<&str>,
+) -> Result<i64> {
+    // Set created_at explicitly rather than relying on the column default, so every
+    // message gets a well-formed `YYYY-MM-DD HH:MM:SS` UTC timestamp.
     conn.execute(
-        "INSERT INTO messages (parent_id, role, content, metadata) VALUES (?1, ?2, ?3, ?4)",
-        (parent_id, role, content, metadata),
+        "INSERT INTO messages (parent_id, role, content, metadata, created_at)
+         VALUES (?1, ?2, ?3, ?4, COALESCE(?5, strftime('%Y-%m-%d %H:%M:%S', 'now')))",
+        (parent_id, role, content, metadata, created_at),
     )?;
     Ok(conn.last_insert_rowid())
 }
 
+/// Copies `head_id` and its ancestors into new rows, keeping their content, metadata and
+/// timestamps, and returns the new head's ID. The copy is a separate conversation, so the
+/// original root's title, notes and prompt template are copied to the new root.
+pub fn copy_chain(conn: &Connection, head_id: i64) -> Result<i64> {
+    let ids = get_ancestor_ids(conn, head_id)?;
+    if ids.is_empty() {
+        anyhow::bail!("Message with ID '{}' not found.", head_id);
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    let mut new_ids = Vec::with_capacity(ids.len());
+    for id in &ids {
+        let (role, content, metadata, created_at): (
+            String,
+            String,
+            Option<String>,
+            Option<String>,
+        ) = tx.query_row(
+            "SELECT role, content, metadata, created_at FROM messages WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        new_ids.push(add_message_at(
+            &tx,
+            new_ids.last().copied(),
+            &role,
+            &content,
+            metadata.as_deref(),
+            created_at.as_deref(),
+        )?);
+    }
+
+    tx.execute(
+        "INSERT INTO conversations (root_id, title, notes, prompt_template)
+         SELECT ?2, title, notes, prompt_template FROM conversations WHERE root_id = ?1",
+        (ids[0], new_ids[0]),
+    )?;
+
+    tx.commit()?;
+    Ok(new_ids[new_ids.len() - 1])
+}
+
 pub fn get_message_id_by_tag(conn: &Connection, tag: &str) -> Result<Option<i64>> {
     let mut stmt = conn.prepare("SELECT message_id FROM chat_tags WHERE tag = ?1")?;
     let mut rows = stmt.query_map([tag], |row| row.get(0))?;
@@ -219,6 +427,123 @@ pub fn message_exists(conn: &Connection, id: i64) -> Result<bool> {
     Ok(stmt.exists([id])?)
 }
 
+/// Returns the ID of the root message of the conversation containing `message_id`.
+pub fn get_root_id(conn: &Connection, message_id: i64) -> Result<i64> {
+    conn.query_row(
+        "
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id FROM messages WHERE id = ?1
+            UNION ALL
+            SELECT m.id, m.parent_id FROM messages m JOIN ancestors a ON m.id = a.parent_id
+        )
+        SELECT id FROM ancestors WHERE parent_id IS NULL;
+        ",
+        [message_id],
+        |row| row.get(0),
+    )
+    .map_err(Into::into)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Conversation {
+    pub root_id: i64,
+    pub title: Option<String>,
+    pub notes: Option<String>,
+    /// The `prompt_templates` entry to build the system prompt from, if not the built-in one.
+    pub prompt_template: Option<String>,
+}
+
+pub fn get_conversation(conn: &Connection, root_id: i64) -> Result<Option<Conversation>> {
+    let mut stmt =
+        conn.prepare("SELECT title, notes, prompt_template FROM conversations WHERE root_id = ?1")?;
+    let mut rows = stmt.query_map([root_id], |row| {
+        Ok(Conversation {
+            root_id,
+            title: row.get(0)?,
+            notes: row.get(1)?,
+            prompt_template: row.get(2)?,
+        })
+    })?;
+    rows.next().transpose().map_err(Into::into)
+}
+
+pub fn set_conversation_title(conn: &Connection, root_id: i64, title: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO conversations (root_id, title) VALUES (?1, ?2)
+         ON CONFLICT(root_id) DO UPDATE SET title = excluded.title",
+        (root_id, title),
+    )?;
+    Ok(())
+}
+
+pub fn set_conversation_notes(conn: &Connection, root_id: i64, notes: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO conversations (root_id, notes) VALUES (?1, ?2)
+         ON CONFLICT(root_id) DO UPDATE SET notes = excluded.notes",
+        (root_id, notes),
+    )?;
+    Ok(())
+}
+
+/// Sets the prompt template a conversation uses, or clears it with `None`.
+pub fn set_conversation_prompt_template(
+    conn: &Connection,
+    root_id: i64,
+    prompt_template: Option<&str>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO conversations (root_id, prompt_template) VALUES (?1, ?2)
+         ON CONFLICT(root_id) DO UPDATE SET prompt_template = excluded.prompt_template",
+        (root_id, prompt_template),
+    )?;
+    Ok(())
+}
+
+pub fn get_message(conn: &Connection, id: i64) -> Result<Option<Message>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, parent_id, role, content, COALESCE(created_at, '') FROM messages WHERE id = ?1",
+    )?;
+    let mut rows = stmt.query_map([id], |row| {
+        Ok(Message {
+            id: row.get(0)?,
+            parent_id: row.get(1)?,
+            role: row.get(2)?,
+            content: row.get(3)?,
+            created_at: row.get(4)?,
+        })
+    })?;
+    if let Some(message_result) = rows.next() {
+        Ok(Some(message_result?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the children of `parent_id` (or the root messages for `None`), oldest first.
+pub fn get_children(conn: &Connection, parent_id: Option<i64>) -> Result<Vec<Message>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, parent_id, role, content, COALESCE(created_at, '') FROM messages WHERE parent_id IS ?1 ORDER BY id ASC",
+    )?;
+    let messages = stmt
+        .query_map([parent_id], |row| {
+            Ok(Message {
+                id: row.get(0)?,
+                parent_id: row.get(1)?,
+                role: row.get(2)?,
+                content: row.get(3)?,
+                created_at: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Message>>>()?;
+    Ok(messages)
+}
+
+/// Returns true if no message has `id` as its parent.
+pub fn is_leaf(conn: &Connection, id: i64) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT 1 FROM messages WHERE parent_id = ?1")?;
+    Ok(!stmt.exists([id])?)
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Profile {
     pub name: String,
@@ -241,12 +566,20 @@ pub fn get_profile_by_name(conn: &Connection, name: &str) -> Result<Profile> {
     .map_err(Into::into)
 }
 
+/// Content piped to `retort stage -`, sent as a read-only block labelled `name`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct InlineFile {
+    pub name: String,
+    pub content: String,
+}
+
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct ContextStage {
     pub name: String,
     pub read_write_files: Vec<String>,
     pub read_only_files: Vec<String>,
     pub dropped_files: Vec<String>,
+    pub inline_files: Vec<InlineFile>,
 }
 
 pub fn get_context_stage(conn: &Connection, name: &str) -> Result<ContextStage> {
@@ -262,6 +595,7 @@ pub fn get_context_stage(conn: &Connection, name: &str) -> Result<ContextStage>
                     read_write_files: prepared.read_write_files,
                     read_only_files: prepared.read_only_files,
                     dropped_files: prepared.dropped_files,
+                    inline_files: prepared.inline_files,
                 });
             }
 
@@ -275,6 +609,7 @@ pub fn get_context_stage(conn: &Connection, name: &str) -> Result<ContextStage>
                 read_write_files,
                 read_only_files,
                 dropped_files: Vec::new(),
+                inline_files: Vec::new(),
             })
         },
     )
@@ -286,6 +621,7 @@ pub fn update_context_stage(conn: &Connection, stage: &ContextStage) -> Result<(
         read_write_files: stage.read_write_files.clone(),
         read_only_files: stage.read_only_files.clone(),
         dropped_files: stage.dropped_files.clone(),
+        inline_files: stage.inline_files.clone(),
     };
     let prepared_json = serde_json::to_string(&prepared)?;
 
@@ -329,6 +665,26 @@ pub fn add_file_to_stage(
     update_context_stage(conn, &stage)
 }
 
+/// Stages piped content under `file_name`, replacing any earlier content with that name.
+pub fn add_inline_file_to_stage(
+    conn: &Connection,
+    name: &str,
+    file_name: &str,
+    content: &str,
+) -> Result<()> {
+    let mut stage = get_context_stage(conn, name)?;
+    let inline_file = InlineFile {
+        name: file_name.to_string(),
+        content: content.to_string(),
+    };
+    if let Some(existing) = stage.inline_files.iter_mut().find(|f| f.name == file_name) {
+        *existing = inline_file;
+    } else {
+        stage.inline_files.push(inline_file);
+    }
+    update_context_stage(conn, &stage)
+}
+
 pub fn get_message_metadata(conn: &Connection, message_id: i64) -> Result<Option<String>> {
     let mut stmt = conn.prepare("SELECT metadata FROM messages WHERE id = ?1")?;
     let mut rows = stmt.query_map([message_id], |row| row.get(0))?;
@@ -372,6 +728,7 @@ pub fn remove_file_from_stage(conn: &Connection, name: &str, file_path: &str) ->
     // Remove from any addition lists.
     stage.read_write_files.retain(|f| f != &file_path_string);
     stage.read_only_files.retain(|f| f != &file_path_string);
+    stage.inline_files.retain(|f| f.name != file_path_string);
 
     // Add to the dropped list to ensure it's removed from inherited context.
     if !stage.dropped_files.contains(&file_path_string) {
@@ -380,3 +737,303 @@ pub fn remove_file_from_stage(conn: &Connection, name: &str, file_path: &str) ->
 
     update_context_stage(conn, &stage)
 }
+
+/// Renames `old` to `new` in the prepared stage, keeping its position and mode.
+/// Returns whether the file is read-only, or `None` if `old` isn't in the prepared stage.
+pub fn rename_staged_file(
+    conn: &Connection,
+    name: &str,
+    old: &str,
+    new: &str,
+) -> Result<Option<bool>> {
+    let mut stage = get_context_stage(conn, name)?;
+    if !stage.read_write_files.iter().any(|f| f == old)
+        && !stage.read_only_files.iter().any(|f| f == old)
+    {
+        return Ok(None);
+    }
+
+    // Drop any existing entry for the new path so it only appears once.
+    stage.read_write_files.retain(|f| f != new);
+    stage.read_only_files.retain(|f| f != new);
+    stage.dropped_files.retain(|f| f != new);
+
+    let read_only = if let Some(pos) = stage.read_write_files.iter().position(|f| f == old) {
+        stage.read_write_files[pos] = new.to_string();
+        false
+    } else {
+        let pos = stage.read_only_files.iter().position(|f| f == old).unwrap();
+        stage.read_only_files[pos] = new.to_string();
+        true
+    };
+
+    update_context_stage(conn, &stage)?;
+    Ok(Some(read_only))
+}
+
+/// Saves `stage`'s files as a named preset, replacing any preset with that name.
+/// Returns whether an existing preset was replaced.
+pub fn save_context_preset(conn: &Connection, name: &str, stage: &ContextStage) -> Result<bool> {
+    let existed = get_context_preset(conn, name)?.is_some();
+    let prepared = PreparedContext {
+        read_write_files: stage.read_write_files.clone(),
+        read_only_files: stage.read_only_files.clone(),
+        dropped_files: stage.dropped_files.clone(),
+        // Piped content is one-off, so presets only keep paths.
+        inline_files: Vec::new(),
+    };
+    conn.execute(
+        "INSERT OR REPLACE INTO context_presets (name, files) VALUES (?1, ?2)",
+        (name, serde_json::to_string(&prepared)?),
+    )?;
+    Ok(existed)
+}
+
+/// Gets a saved preset as a `ContextStage` named after the preset.
+pub fn get_context_preset(conn: &Connection, name: &str) -> Result<Option<ContextStage>> {
+    let mut stmt = conn.prepare("SELECT files FROM context_presets WHERE name = ?1")?;
+    let mut rows = stmt.query_map([name], |row| row.get::<_, String>(0))?;
+    match rows.next() {
+        Some(files_json) => {
+            let prepared: PreparedContext = serde_json::from_str(&files_json?)?;
+            Ok(Some(ContextStage {
+                name: name.to_string(),
+                read_write_files: prepared.read_write_files,
+                read_only_files: prepared.read_only_files,
+                dropped_files: prepared.dropped_files,
+                inline_files: Vec::new(),
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn list_context_presets(conn: &Connection) -> Result<Vec<ContextStage>> {
+    let mut stmt = conn.prepare("SELECT name FROM context_presets ORDER BY name")?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    let mut presets = Vec::new();
+    for name in names {
+        if let Some(preset) = get_context_preset(conn, &name)? {
+            presets.push(preset);
+        }
+    }
+    Ok(presets)
+}
+
+/// Deletes a preset, returning whether it existed.
+pub fn delete_context_preset(conn: &Connection, name: &str) -> Result<bool> {
+    let deleted = conn.execute("DELETE FROM context_presets WHERE name = ?1", [name])?;
+    Ok(deleted > 0)
+}
+
+/// Replays a preset into a stage, as if each of its files had been staged (or dropped) in turn.
+pub fn load_context_preset(
+    conn: &Connection,
+    stage_name: &str,
+    preset: &ContextStage,
+) -> Result<()> {
+    for path in &preset.read_write_files {
+        add_file_to_stage(conn, stage_name, path, false)?;
+    }
+    for path in &preset.read_only_files {
+        add_file_to_stage(conn, stage_name, path, true)?;
+    }
+    for path in &preset.dropped_files {
+        remove_file_from_stage(conn, stage_name, path)?;
+    }
+    Ok(())
+}
+
+/// A portable dump of conversations, used by `retort import`.
+/// IDs are only meaningful within the dump and are remapped on import.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ChatDump {
+    pub messages: Vec<DumpMessage>,
+    #[serde(default)]
+    pub tags: Vec<DumpTag>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DumpMessage {
+    pub id: i64,
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+    pub role: String,
+    pub content: String,
+    #[serde(default)]
+    pub metadata: Option<String>,
+    /// The original `YYYY-MM-DD HH:MM:SS` timestamp. Defaults to the import time.
+    #[serde(default)]
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DumpTag {
+    pub tag: String,
+    pub message_id: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ImportSummary {
+    pub messages: usize,
+    pub tags: usize,
+}
+
+pub fn import_dump(conn: &Connection, dump: &ChatDump) -> Result<ImportSummary> {
+    // Validate everything up front so a bad dump doesn't leave a partial import behind.
+    let timestamp_re = regex::Regex::new(r"^\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}$")?;
+    let mut ids = HashSet::new();
+    for message in &dump.messages {
+        if !VALID_ROLES.contains(&message.role.as_str()) {
+            anyhow::bail!(
+                "Invalid role '{}' for message {} in import.",
+                message.role,
+                message.id
+            );
+        }
+        if let Some(created_at) = &message.created_at {
+            if !timestamp_re.is_match(created_at) {
+                anyhow::bail!(
+                    "Invalid created_at '{}' for message {} in import; expected YYYY-MM-DD HH:MM:SS.",
+                    created_at,
+                    message.id
+                );
+            }
+        }
+        if let Some(metadata) = message.metadata.as_deref().filter(|m| !m.is_empty()) {
+            let parsed = match message.role.as_str() {
+                "user" => serde_json::from_str::<crate::MessageMetadata>(metadata).map(|_| ()),
+                "assistant" => {
+                    serde_json::from_str::<crate::AssistantMetadata>(metadata).map(|_| ())
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = parsed {
+                anyhow::bail!(
+                    "Invalid metadata for message {} in import: {}",
+                    message.id,
+                    e
+                );
+            }
+        }
+        if !ids.insert(message.id) {
+            anyhow::bail!("Duplicate message ID {} in import.", message.id);
+        }
+    }
+    for message in &dump.messages {
+        if let Some(parent_id) = message.parent_id {
+            if !ids.contains(&parent_id) {
+                anyhow::bail!(
+                    "Message {} references unknown parent {} in import.",
+                    message.id,
+                    parent_id
+                );
+            }
+        }
+    }
+    let mut tag_names = HashSet::new();
+    for tag in &dump.tags {
+        if !ids.contains(&tag.message_id) {
+            anyhow::bail!(
+                "Tag '{}' references unknown message {} in import.",
+                tag.tag,
+                tag.message_id
+            );
+        }
+        if !tag_names.insert(tag.tag.as_str()) {
+            anyhow::bail!("Duplicate tag '{}' in import.", tag.tag);
+        }
+        if get_message_id_by_tag(conn, &tag.tag)?.is_some() {
+            anyhow::bail!("Tag '{}' already exists.", tag.tag);
+        }
+    }
+
+    let tx = conn.unchecked_transaction()?;
+
+    // Insert parents before children, mapping dump IDs to newly assigned IDs.
+    let mut id_map: HashMap<i64, i64> = HashMap::new();
+    let mut remaining: Vec<&DumpMessage> = dump.messages.iter().collect();
+    while !remaining.is_empty() {
+        let mut deferred = Vec::new();
+        for message in &remaining {
+            let new_parent_id = match message.parent_id {
+                None => None,
+                Some(parent_id) => match id_map.get(&parent_id) {
+                    Some(new_id) => Some(*new_id),
+                    None => {
+                        deferred.push(*message);
+                        continue;
+                    }
+                },
+            };
+            let new_id = add_message_at(
+                &tx,
+                new_parent_id,
+                &message.role,
+                &message.content,
+                message.metadata.as_deref(),
+                message.created_at.as_deref(),
+            )?;
+            id_map.insert(message.id, new_id);
+        }
+        if deferred.len() == remaining.len() {
+            anyhow::bail!("Import contains a cycle in parent references.");
+        }
+        remaining = deferred;
+    }
+
+    for tag in &dump.tags {
+        set_chat_tag(&tx, &tag.tag, id_map[&tag.message_id])?;
+    }
+
+    tx.commit()?;
+
+    Ok(ImportSummary {
+        messages: dump.messages.len(),
+        tags: dump.tags.len(),
+    })
+}
+
+#[derive(Debug)]
+pub struct SchemaInfo {
+    pub user_version: i64,
+    /// The `CREATE` statements from `sqlite_master`.
+    pub statements: Vec<String>,
+    pub table_row_counts: Vec<(String, i64)>,
+}
+
+pub fn get_schema_info(conn: &Connection) -> Result<SchemaInfo> {
+    let user_version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    let mut stmt = conn.prepare(
+        "SELECT sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY type DESC, name ASC",
+    )?;
+    let statements = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name ASC",
+    )?;
+    let table_names = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut table_row_counts = Vec::new();
+    for name in table_names {
+        let count: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\"", name.replace('"', "\"\"")),
+            [],
+            |row| row.get(0),
+        )?;
+        table_row_counts.push((name, count));
+    }
+
+    Ok(SchemaInfo {
+        user_version,
+        statements,
+        table_row_counts,
+    })
+}
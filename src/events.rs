@@ -0,0 +1,38 @@
+use crate::hooks::postprocessor::EditResult;
+use serde::Serialize;
+use std::io::{stdout, Write};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ContextFile {
+    pub path: String,
+    pub read_only: bool,
+}
+
+/// A machine-readable event emitted by `retort send --json-events`.
+/// Each event is written to stdout as a single line of JSON.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Context {
+        files: Vec<ContextFile>,
+    },
+    Chunk {
+        text: String,
+    },
+    /// Whether an edit block was applied, and why not if it wasn't.
+    Edit(EditResult),
+    Commit {
+        hash: String,
+    },
+    Done {
+        assistant_message_id: i64,
+    },
+}
+
+pub fn emit(event: &Event) -> anyhow::Result<()> {
+    let line = serde_json::to_string(event)?;
+    let mut out = stdout().lock();
+    writeln!(out, "{}", line)?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Markdown code fences, shared by the prompt builder that wraps files in them and the
+//! response parser that strips them back out.
+
+/// The shortest fence, and the one used when the content has no backticks of its own.
+pub const DEFAULT_FENCE: &str = "```";
+
+/// The length of the backtick fence `line` opens or closes, if it is a fence line.
+pub fn fence_len(line: &str) -> Option<usize> {
+    let len = line.trim_start().chars().take_while(|c| *c == '`').count();
+    (len >= DEFAULT_FENCE.len()).then_some(len)
+}
+
+/// A backtick fence longer than any run of backticks in `contents`, so nothing inside
+/// a block fenced with it can close the block early.
+pub fn fence_for<'a>(contents: impl IntoIterator<Item = &'a str>) -> String {
+    let longest_run = contents
+        .into_iter()
+        .flat_map(|content| content.split(|c| c != '`'))
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    "`".repeat((longest_run + 1).max(DEFAULT_FENCE.len()))
+}
+
+/// The index of the line that closes the block opened at `lines[open]`: the next line
+/// that is only a fence at least as long as the opening one, as in CommonMark.
+pub fn closing_fence(lines: &[&str], open: usize) -> Option<usize> {
+    let len = fence_len(lines[open])?;
+    lines[open + 1..]
+        .iter()
+        .position(|line| {
+            fence_len(line).is_some_and(|close_len| close_len >= len)
+                && line.trim().chars().all(|c| c == '`')
+        })
+        .map(|offset| open + 1 + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fence_for() {
+        assert_eq!(fence_for(["no backticks"]), "```");
+        assert_eq!(fence_for(["inline `code`", "a ``` fence"]), "````");
+        assert_eq!(fence_for(["`````"]), "``````");
+        assert_eq!(fence_for([]), "```");
+    }
+
+    #[test]
+    fn test_closing_fence() {
+        let lines = ["````markdown", "```rust", "inner", "```", "````", "after"];
+        assert_eq!(closing_fence(&lines, 0), Some(4));
+        assert_eq!(closing_fence(&lines, 1), Some(3));
+        // A longer fence closes a shorter one, but a fence with an info string doesn't close.
+        assert_eq!(closing_fence(&["```", "text", "`````"], 0), Some(2));
+        assert_eq!(closing_fence(&["```", "```rust"], 0), None);
+        assert_eq!(closing_fence(&["not a fence", "```"], 0), None);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::hooks::postprocessor::FileChange;
+use crate::hooks::EditHook;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormatterConfig {
+    /// Regex matched against each changed file path.
+    pub pattern: String,
+    /// Shell command run with the matching files appended as arguments.
+    pub command: String,
+}
+
+/// Runs configured formatters/linters on edited files so the committed diff is clean.
+pub struct FormatterHook {
+    formatters: Vec<(Regex, String)>,
+}
+
+impl FormatterHook {
+    pub fn new(configs: &[FormatterConfig]) -> anyhow::Result<Self> {
+        let mut formatters = Vec::new();
+        for config in configs {
+            let pattern = Regex::new(&config.pattern).map_err(|e| {
+                anyhow::anyhow!("Invalid formatter pattern '{}': {}", config.pattern, e)
+            })?;
+            formatters.push((pattern, config.command.clone()));
+        }
+        Ok(Self { formatters })
+    }
+}
+
+impl EditHook for FormatterHook {
+    fn post_edit(&self, changes: &[FileChange]) -> anyhow::Result<()> {
+        for (pattern, command) in &self.formatters {
+            let mut files: Vec<&str> = changes
+                .iter()
+                .map(|change| change.path.as_str())
+                .filter(|path| pattern.is_match(path))
+                .collect();
+            // A file edited by several blocks only needs formatting once.
+            files.sort();
+            files.dedup();
+            if files.is_empty() {
+                continue;
+            }
+
+            // `sh -c '<command> "$@"' sh <files...>` passes the files as separate arguments.
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$@\"", command))
+                .arg("sh")
+                .args(&files)
+                .output()?;
+
+            // A failing formatter shouldn't block the commit, so only warn.
+            if !output.status.success() {
+                eprintln!(
+                    "Warning: formatter '{}' failed ({}).",
+                    command, output.status
+                );
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if !stderr.trim().is_empty() {
+                    eprintln!("{}", stderr.trim_end());
+                }
+            }
+        }
+        Ok(())
+    }
+}
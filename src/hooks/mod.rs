@@ -1,38 +1,68 @@
+pub mod formatter;
 pub mod postprocessor;
+pub mod test_runner;
 
+use postprocessor::{EditFormat, EditResult, FileChange, ParsedResponse, PostprocessorHook};
 use std::path::PathBuf;
 
+/// State from the send that hooks may need to act on a response.
+#[derive(Debug, Default)]
+pub struct HookContext {
+    pub project_root: Option<PathBuf>,
+    /// Files sent to the model as read-only references, which must not be edited.
+    pub read_only_files: Vec<String>,
+}
+
 pub trait Hook {
-    fn post_send(&self, llm_response: &str, project_root: &Option<PathBuf>) -> anyhow::Result<()>;
+    /// Acts on a response, returning the outcome of any edits it applied.
+    fn post_send(
+        &self,
+        llm_response: &str,
+        parsed: &ParsedResponse,
+        context: &HookContext,
+    ) -> anyhow::Result<Vec<EditResult>>;
+}
+
+/// Runs after the postprocessor writes edits to disk and before it commits them.
+pub trait EditHook {
+    fn post_edit(&self, changes: &[FileChange]) -> anyhow::Result<()>;
 }
 
 pub struct HookManager {
     hooks: Vec<Box<dyn Hook>>,
+    /// The edit blocks responses are parsed for.
+    edit_format: EditFormat,
 }
 
 impl Default for HookManager {
     fn default() -> Self {
-        Self::new()
+        Self::new(EditFormat::default())
     }
 }
 
 impl HookManager {
-    pub fn new() -> Self {
-        Self { hooks: Vec::new() }
+    pub fn new(edit_format: EditFormat) -> Self {
+        Self {
+            hooks: Vec::new(),
+            edit_format,
+        }
     }
 
     pub fn register(&mut self, hook: Box<dyn Hook>) {
         self.hooks.push(hook);
     }
 
+    /// Runs every hook on the response and returns their edit results, in order.
     pub fn run_post_send_hooks(
         &self,
         llm_response: &str,
-        project_root: &Option<PathBuf>,
-    ) -> anyhow::Result<()> {
+        context: &HookContext,
+    ) -> anyhow::Result<Vec<EditResult>> {
+        let parsed = PostprocessorHook::parse_response(llm_response, self.edit_format)?;
+        let mut results = Vec::new();
         for hook in &self.hooks {
-            hook.post_send(llm_response, project_root)?;
+            results.extend(hook.post_send(llm_response, &parsed, context)?);
         }
-        Ok(())
+        Ok(results)
     }
 }
@@ -1,30 +1,336 @@
-use crate::hooks::Hook;
+use crate::events::{self, Event};
+use crate::fence;
+use crate::hooks::{EditHook, Hook, HookContext};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 
-#[derive(Debug)]
+// The lines that open, divide and close edit blocks. The system prompt is rendered
+// with these, so what the model is told and what is parsed can't drift apart.
+pub const SEARCH_MARKER: &str = "<<<<<<< SEARCH";
+pub const DIVIDER_MARKER: &str = "=======";
+pub const REPLACE_MARKER: &str = ">>>>>>> REPLACE";
+pub const APPEND_MARKER: &str = "<<<<<<< APPEND";
+pub const APPEND_END_MARKER: &str = ">>>>>>> APPEND";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Find and replace `search_content`, or replace the whole file if it is empty.
+    Replace,
+    /// Append `replace_content` to the end of the file, creating it if absent.
+    Append,
+}
+
+impl ChangeKind {
+    /// What the prompt calls this kind of block.
+    pub fn block_name(self) -> &'static str {
+        match self {
+            Self::Replace => "SEARCH/REPLACE",
+            Self::Append => "APPEND",
+        }
+    }
+
+    /// The marker lines of this kind of block, in the order they appear.
+    pub fn markers(self) -> &'static [&'static str] {
+        match self {
+            Self::Replace => &[SEARCH_MARKER, DIVIDER_MARKER, REPLACE_MARKER],
+            Self::Append => &[APPEND_MARKER, APPEND_END_MARKER],
+        }
+    }
+}
+
+/// Which kinds of edit block the model is told about and the parser reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EditFormat {
+    /// SEARCH/REPLACE blocks, and APPEND blocks for adding to the end of a file.
+    #[default]
+    SearchReplaceAppend,
+    /// SEARCH/REPLACE blocks only.
+    SearchReplace,
+}
+
+impl EditFormat {
+    pub fn kinds(self) -> &'static [ChangeKind] {
+        match self {
+            Self::SearchReplaceAppend => &[ChangeKind::Replace, ChangeKind::Append],
+            Self::SearchReplace => &[ChangeKind::Replace],
+        }
+    }
+
+    /// The markers the system prompt template describes blocks with. `append` is unset
+    /// when the format has no APPEND blocks, so the template leaves them out.
+    pub fn markers(self) -> EditMarkers {
+        EditMarkers {
+            search: SEARCH_MARKER,
+            divider: DIVIDER_MARKER,
+            replace: REPLACE_MARKER,
+            append: self
+                .kinds()
+                .contains(&ChangeKind::Append)
+                .then_some(AppendMarkers {
+                    start: APPEND_MARKER,
+                    end: APPEND_END_MARKER,
+                }),
+        }
+    }
+
+    /// A response with one block of each kind the format has, for checking that the parser
+    /// reads what the prompt describes.
+    pub fn example_response(self) -> String {
+        let mut response = String::from("docs: example edits\n");
+        for kind in self.kinds() {
+            let block = match kind {
+                ChangeKind::Replace => format!(
+                    "example.txt\n{}\nold\n{}\nnew\n{}",
+                    SEARCH_MARKER, DIVIDER_MARKER, REPLACE_MARKER
+                ),
+                ChangeKind::Append => format!(
+                    "example.txt\n{}\nmore\n{}",
+                    APPEND_MARKER, APPEND_END_MARKER
+                ),
+            };
+            response.push_str(&format!(
+                "\n{}\n{}\n{}\n",
+                fence::DEFAULT_FENCE,
+                block,
+                fence::DEFAULT_FENCE
+            ));
+        }
+        response
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EditMarkers {
+    pub search: &'static str,
+    pub divider: &'static str,
+    pub replace: &'static str,
+    pub append: Option<AppendMarkers>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AppendMarkers {
+    pub start: &'static str,
+    pub end: &'static str,
+}
+
+#[derive(Debug, Clone)]
 pub struct FileChange {
     pub path: String,
+    pub kind: ChangeKind,
     pub search_content: String,
     pub replace_content: String,
 }
 
-pub struct PostprocessorHook {}
+/// Why an edit block couldn't be applied, with enough context for a tool to help resolve it.
+#[derive(Debug, Clone, Serialize)]
+pub struct EditConflict {
+    pub reason: ConflictReason,
+    /// The SEARCH block that didn't match.
+    pub search: String,
+    /// For `not_found`, the run of file lines most like the SEARCH block, if any look
+    /// alike. For `ambiguous`, the first line of each match.
+    pub nearest_lines: Vec<NumberedLine>,
+    #[serde(skip)]
+    path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictReason {
+    /// The SEARCH block doesn't appear in the file.
+    NotFound,
+    /// The SEARCH block appears more than once.
+    Ambiguous,
+}
+
+/// A line of a file with its 1-based line number.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NumberedLine {
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for EditConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            ConflictReason::NotFound => write!(f, "SEARCH block not found in file {}", self.path),
+            ConflictReason::Ambiguous => write!(
+                f,
+                "SEARCH block appears {} times in file {}. Ambiguous which one to replace.",
+                self.nearest_lines.len(),
+                self.path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EditConflict {}
+
+/// The outcome of applying one edit block.
+#[derive(Debug, Clone, Serialize)]
+pub struct EditResult {
+    pub path: String,
+    pub applied: bool,
+    /// Why the edit failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Set when the edit failed because its SEARCH block didn't match exactly once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<EditConflict>,
+}
+
+/// An LLM response split into its file changes and the remaining text, which
+/// becomes the commit message. Parsed once and shared with every hook.
+#[derive(Debug, Default)]
+pub struct ParsedResponse {
+    pub commit_message: String,
+    pub changes: Vec<FileChange>,
+}
+
+pub struct PostprocessorHook {
+    /// Emit `edit` and `commit` events instead of human-readable progress.
+    pub json_events: bool,
+    /// Hooks run on the changed files after they are written and before they are committed.
+    pub edit_hooks: Vec<Box<dyn EditHook>>,
+    /// End new (or empty) files with a newline. Other files keep their original trailing newline state.
+    pub new_file_trailing_newline: bool,
+    /// How many times to re-stage and retry a failed `git commit`, e.g. after a
+    /// pre-commit hook reformats the files and rejects the first attempt.
+    pub commit_retries: u32,
+    /// Used verbatim as the commit message instead of the one parsed from the response.
+    pub commit_message_override: Option<String>,
+    /// A conventional commit type, with its scope if any (e.g. `feat(parser)`), for
+    /// commit messages that don't already have one.
+    pub commit_type: Option<String>,
+    /// Text put in front of every commit message, e.g. `ai:`.
+    pub commit_prefix: Option<String>,
+    /// Refuse to apply edits while tracked files other than the edited ones have uncommitted changes.
+    pub require_clean_tree: bool,
+    /// Refuse to apply a response with more changes than this, before writing anything.
+    pub max_edits: Option<usize>,
+    /// Commit the edits that applied when others fail, instead of reverting them all.
+    pub continue_on_edit_error: bool,
+}
 
 impl PostprocessorHook {
-    fn parse_changes(&self, response: &str) -> anyhow::Result<(String, Vec<FileChange>)> {
+    /// Bails if tracked files other than those in `changes` have uncommitted changes,
+    /// which would otherwise end up mixed into the commit. Untracked files are ignored.
+    fn check_clean_tree(changes: &[FileChange]) -> anyhow::Result<()> {
+        let toplevel = Command::new("git")
+            .args(["rev-parse", "--show-toplevel"])
+            .output()?;
+        if !toplevel.status.success() {
+            anyhow::bail!(
+                "git rev-parse --show-toplevel failed:\n{}",
+                String::from_utf8_lossy(&toplevel.stderr).trim_end()
+            );
+        }
+        let toplevel = PathBuf::from(String::from_utf8(toplevel.stdout)?.trim()).canonicalize()?;
+
+        let output = Command::new("git")
+            .args(["status", "--porcelain", "-z"])
+            .output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "git status failed:\n{}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+
+        let mut edited_paths = Vec::new();
+        for change in changes {
+            edited_paths.push(resolve_path(&change.path)?);
+        }
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let mut entries = stdout.split('\0').filter(|entry| !entry.is_empty());
+        let mut dirty = Vec::new();
+        while let Some(entry) = entries.next() {
+            let (status, path) = entry.split_at(3.min(entry.len()));
+            // Renames and copies are followed by their original path.
+            if status.starts_with('R') || status.starts_with('C') {
+                entries.next();
+            }
+            if status.starts_with("??") || edited_paths.contains(&toplevel.join(path)) {
+                continue;
+            }
+            dirty.push(path.to_string());
+        }
+
+        if !dirty.is_empty() {
+            anyhow::bail!(
+                "Not applying edits: the working tree has uncommitted changes to {}. Commit or stash them first, or turn off require_clean_tree.",
+                dirty.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    fn git_add(changes: &[FileChange]) -> anyhow::Result<()> {
+        for change in changes {
+            let output = Command::new("git").arg("add").arg(&change.path).output()?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "git add failed for {}:\n{}",
+                    change.path,
+                    String::from_utf8_lossy(&output.stderr).trim_end()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits `response` into the edit blocks `format` has and the commit message. Blocks of
+    /// other kinds are left in the message with a warning.
+    pub fn parse_response(response: &str, format: EditFormat) -> anyhow::Result<ParsedResponse> {
         let lines: Vec<&str> = response.lines().collect();
         let mut changes = Vec::new();
         let mut block_line_indices = std::collections::HashSet::new();
 
         for i in 0..lines.len() {
+            // An append block starts with a file path on one line, and "<<<<<<< APPEND" on the next
+            if lines.get(i + 1) == Some(&APPEND_MARKER) {
+                let path = lines[i].trim();
+                if !Self::looks_like_path(path) {
+                    continue;
+                }
+                if !format.kinds().contains(&ChangeKind::Append) {
+                    eprintln!(
+                        "Warning: ignoring an APPEND block for {}; the edit_format doesn't include them.",
+                        path
+                    );
+                    continue;
+                }
+
+                let mut j = i + 2;
+                while j < lines.len() && lines[j] != APPEND_END_MARKER {
+                    j += 1;
+                }
+
+                if j < lines.len() {
+                    for k in i..=j {
+                        block_line_indices.insert(k);
+                    }
+                    changes.push(FileChange {
+                        path: path.to_string(),
+                        kind: ChangeKind::Append,
+                        search_content: String::new(),
+                        replace_content: lines[i + 2..j].join("\n"),
+                    });
+                }
+                continue;
+            }
+
             // A block starts with a file path on one line, and "<<<<<<< SEARCH" on the next
-            if lines.get(i + 1) == Some(&"<<<<<<< SEARCH") {
+            if lines.get(i + 1) == Some(&SEARCH_MARKER) {
                 let path = lines[i].trim();
-                // Basic heuristic to ensure the path looks like a path
-                if path.is_empty() || path.contains(' ') || path.starts_with('#') {
+                if !Self::looks_like_path(path) {
                     continue;
                 }
 
@@ -36,9 +342,9 @@ impl PostprocessorHook {
                 // Start searching from after the "<<<<<<< SEARCH" line
                 let mut j = i + 2;
                 while j < lines.len() {
-                    if lines[j] == "=======" {
+                    if lines[j] == DIVIDER_MARKER {
                         in_search_section = false;
-                    } else if lines[j] == ">>>>>>> REPLACE" {
+                    } else if lines[j] == REPLACE_MARKER {
                         block_found = true;
                         break;
                     } else if in_search_section {
@@ -56,6 +362,7 @@ impl PostprocessorHook {
                     }
                     changes.push(FileChange {
                         path: path.to_string(),
+                        kind: ChangeKind::Replace,
                         search_content: search_content_lines.join("\n"),
                         replace_content: replace_content_lines.join("\n"),
                     });
@@ -63,58 +370,79 @@ impl PostprocessorHook {
             }
         }
 
+        let remaining_lines: Vec<&str> = lines
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !block_line_indices.contains(i))
+            .map(|(_, line)| *line)
+            .collect();
+
+        // Clean up any markdown code blocks that ended up in the commit message, such as the
+        // now-empty fences around each edit block, whatever length of fence they use.
         let mut commit_message_parts = Vec::new();
-        for (i, line) in lines.iter().enumerate() {
-            if !block_line_indices.contains(&i) {
-                commit_message_parts.push(*line);
+        let mut i = 0;
+        while i < remaining_lines.len() {
+            match fence::closing_fence(&remaining_lines, i) {
+                Some(close) => i = close + 1,
+                None => {
+                    commit_message_parts.push(remaining_lines[i]);
+                    i += 1;
+                }
             }
         }
 
-        let commit_message = commit_message_parts.join("\n");
-        // Clean up any markdown code blocks that ended up in the commit message
-        let re = Regex::new(r"(?s)```[a-zA-Z]*\n?.*?\n?```")?;
-        let cleaned_commit_message = re.replace_all(&commit_message, "");
+        Ok(ParsedResponse {
+            commit_message: commit_message_parts.join("\n").trim().to_string(),
+            changes,
+        })
+    }
 
-        Ok((cleaned_commit_message.trim().to_string(), changes))
+    /// Basic heuristic to ensure the line preceding a block looks like a path.
+    fn looks_like_path(path: &str) -> bool {
+        !(path.is_empty() || path.contains(' ') || path.starts_with('#'))
     }
 
+    /// Applies and commits `changes`, returning how each block fared. With `--json-events`,
+    /// the results are emitted once it is settled whether the applied edits are kept, so
+    /// edits rolled back afterwards are never reported as applied.
     fn apply_and_commit_changes(
         &self,
         commit_message: &str,
         changes: &[FileChange],
-        project_root: &Option<PathBuf>,
-    ) -> anyhow::Result<()> {
+        context: &HookContext,
+    ) -> anyhow::Result<Vec<EditResult>> {
         if changes.is_empty() {
-            return Ok(());
+            return Ok(Vec::new());
+        }
+
+        if let Some(max_edits) = self.max_edits {
+            if changes.len() > max_edits {
+                anyhow::bail!(
+                    "Not applying edits: the response has {} edit blocks, over max_edits_per_response of {}. Pass --force to apply them anyway.",
+                    changes.len(),
+                    max_edits
+                );
+            }
         }
 
-        if let Some(root) = project_root {
+        if !context.read_only_files.is_empty() {
+            let mut read_only_paths = Vec::new();
+            for path in &context.read_only_files {
+                read_only_paths.push(resolve_path(path)?);
+            }
             for change in changes {
-                let path = PathBuf::from(&change.path);
-                let absolute_path = if path.is_absolute() {
-                    path.clone()
-                } else {
-                    std::env::current_dir()?.join(path)
-                };
-                let canonical_path = if absolute_path.exists() {
-                    absolute_path.canonicalize()?
-                } else {
-                    // For a new file, canonicalize the parent and append the filename.
-                    let parent = absolute_path.parent().ok_or_else(|| {
-                        anyhow::anyhow!(
-                            "Could not get parent directory for {}",
-                            absolute_path.display()
-                        )
-                    })?;
-                    if !parent.exists() {
-                        fs::create_dir_all(parent)?;
-                    }
-                    let canonical_parent = parent.canonicalize()?;
-                    let file_name = absolute_path.file_name().ok_or_else(|| {
-                        anyhow::anyhow!("Could not get file name for {}", absolute_path.display())
-                    })?;
-                    canonical_parent.join(file_name)
-                };
+                if read_only_paths.contains(&resolve_path(&change.path)?) {
+                    anyhow::bail!(
+                        "Refusing to modify file {} which was provided as read-only.",
+                        change.path
+                    );
+                }
+            }
+        }
+
+        if let Some(root) = &context.project_root {
+            for change in changes {
+                let canonical_path = resolve_path(&change.path)?;
                 if !canonical_path.starts_with(root) {
                     anyhow::bail!(
                         "Attempted to modify file {} which is outside the project root {}.",
@@ -125,79 +453,652 @@ impl PostprocessorHook {
             }
         }
 
+        if self.require_clean_tree {
+            Self::check_clean_tree(changes)?;
+        }
+
+        // Keep the pre-edit content so a failing edit hook can roll the files back.
+        // `None` marks a file that didn't exist before.
+        let mut originals: HashMap<String, Option<String>> = HashMap::new();
+        for change in changes {
+            if !originals.contains_key(&change.path) {
+                let original = if Path::new(&change.path).exists() {
+                    Some(fs::read_to_string(&change.path)?)
+                } else {
+                    None
+                };
+                originals.insert(change.path.clone(), original);
+            }
+        }
+
+        // Every block is tried, so all of a response's conflicts are reported at once.
+        let mut results = Vec::new();
         for change in changes {
-            println!("Applying changes to {}", change.path);
+            if !self.json_events {
+                println!("Applying changes to {}", change.path);
+            }
+            let result = match self.apply_change(change) {
+                Ok(()) => EditResult {
+                    path: change.path.clone(),
+                    applied: true,
+                    error: None,
+                    conflict: None,
+                },
+                Err(e) => EditResult {
+                    path: change.path.clone(),
+                    applied: false,
+                    error: Some(e.to_string()),
+                    conflict: e.downcast_ref::<EditConflict>().cloned(),
+                },
+            };
+            // JSON events are emitted below, once the edits are kept or reverted.
+            if let (false, Some(conflict)) = (self.json_events, &result.conflict) {
+                if conflict.reason == ConflictReason::NotFound && !conflict.nearest_lines.is_empty()
+                {
+                    eprintln!("The closest lines in {} are:", change.path);
+                    for line in &conflict.nearest_lines {
+                        eprintln!("{:>5}: {}", line.line, line.text);
+                    }
+                }
+            }
+            results.push(result);
+        }
+        let failures: Vec<String> = results
+            .iter()
+            .filter_map(|result| result.error.clone())
+            .collect();
+        let applied: Vec<FileChange> = changes
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.applied)
+            .map(|(change, _)| change.clone())
+            .collect();
+        if !failures.is_empty() && self.continue_on_edit_error && !applied.is_empty() {
+            // A failed block never writes its file, so the applied ones can go ahead on their own.
+            eprintln!(
+                "Warning: skipping {} of {} edits that could not be applied:\n{}",
+                failures.len(),
+                changes.len(),
+                failures.join("\n")
+            );
+        } else if !failures.is_empty() {
+            // Don't leave a half-applied response behind when one block conflicts.
+            Self::revert(&originals)?;
+            eprintln!("Reverted edits to {} file(s).", originals.len());
+            self.emit_results(&Self::mark_reverted(
+                results,
+                "another edit in the response could not be applied",
+            ))?;
+            if let [failure] = failures.as_slice() {
+                anyhow::bail!("{}", failure);
+            }
+            anyhow::bail!(
+                "{} of {} edits could not be applied:\n{}",
+                failures.len(),
+                changes.len(),
+                failures.join("\n")
+            );
+        }
+        let changes = applied.as_slice();
 
-            let new_content = if change.search_content.is_empty() {
-                // An empty search block means replace the entire file.
-                change.replace_content.clone()
+        for hook in &self.edit_hooks {
+            if let Err(e) = hook.post_edit(changes) {
+                Self::revert(&originals)?;
+                eprintln!("Reverted edits to {} file(s).", originals.len());
+                self.emit_results(&Self::mark_reverted(results, &e.to_string()))?;
+                return Err(e);
+            }
+        }
+        self.emit_results(&results)?;
+
+        if !self.json_events {
+            println!("Staging changes...");
+        }
+        Self::git_add(changes)?;
+
+        let final_commit_message = prefix_commit_message(
+            if commit_message.is_empty() {
+                "Apply changes from LLM"
             } else {
-                // A non-empty search block means find and replace a specific part of the file.
-                let original_content = fs::read_to_string(&change.path)?;
-                let occurrences = original_content.matches(&change.search_content).count();
+                commit_message
+            },
+            self.commit_type.as_deref(),
+            self.commit_prefix.as_deref(),
+        );
 
-                if occurrences == 0 {
-                    anyhow::bail!("SEARCH block not found in file {}", &change.path);
-                }
-                if occurrences > 1 {
-                    anyhow::bail!(
-                        "SEARCH block appears {} times in file {}. Ambiguous which one to replace.",
-                        occurrences,
-                        &change.path
-                    );
+        if !self.json_events {
+            println!("Committing changes with message: {}", final_commit_message);
+        }
+        let mut retries_left = self.commit_retries;
+        loop {
+            // `--only` leaves anything else already in the index out of the commit.
+            let output = Command::new("git")
+                .arg("commit")
+                .arg("-m")
+                .arg(&final_commit_message)
+                .arg("--only")
+                .arg("--")
+                .args(changes.iter().map(|change| &change.path))
+                .stdin(Stdio::null())
+                .output()?;
+            let git_output = format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            if output.status.success() {
+                // Keep stdout reserved for JSON events.
+                if !self.json_events {
+                    print!("{}", git_output);
                 }
+                break;
+            }
+            if retries_left == 0 {
+                anyhow::bail!("git commit failed:\n{}", git_output.trim_end());
+            }
+            retries_left -= 1;
+            eprintln!(
+                "git commit failed; re-staging the changed files and retrying, since a pre-commit hook may have modified them.\n{}",
+                git_output.trim_end()
+            );
+            Self::git_add(changes)?;
+        }
 
-                original_content.replacen(&change.search_content, &change.replace_content, 1)
-            };
-
-            if let Some(parent) = Path::new(&change.path).parent() {
-                fs::create_dir_all(parent)?;
+        if self.json_events {
+            let output = Command::new("git").args(["rev-parse", "HEAD"]).output()?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "git rev-parse HEAD failed:\n{}",
+                    String::from_utf8_lossy(&output.stderr).trim_end()
+                );
             }
+            let hash = String::from_utf8(output.stdout)?.trim().to_string();
+            events::emit(&Event::Commit { hash })?;
+        } else {
+            println!("Changes committed successfully.");
+        }
+
+        Ok(results)
+    }
 
-            let mut final_content = new_content;
-            if !final_content.is_empty() && !final_content.ends_with('\n') {
-                final_content.push('\n');
+    /// Marks the applied results as not applied, for edits that were rolled back because of `reason`.
+    fn mark_reverted(results: Vec<EditResult>, reason: &str) -> Vec<EditResult> {
+        results
+            .into_iter()
+            .map(|result| {
+                if !result.applied {
+                    return result;
+                }
+                EditResult {
+                    applied: false,
+                    error: Some(format!("Reverted: {}", reason)),
+                    ..result
+                }
+            })
+            .collect()
+    }
+
+    /// Emits an `edit` event per result with `--json-events`.
+    fn emit_results(&self, results: &[EditResult]) -> anyhow::Result<()> {
+        if self.json_events {
+            for result in results {
+                events::emit(&Event::Edit(result.clone()))?;
             }
-            fs::write(&change.path, final_content)?;
         }
+        Ok(())
+    }
 
-        println!("Staging changes...");
-        for change in changes {
-            let status = Command::new("git").arg("add").arg(&change.path).status()?;
-            if !status.success() {
-                anyhow::bail!("git add failed for {}", change.path);
+    fn revert(originals: &HashMap<String, Option<String>>) -> anyhow::Result<()> {
+        for (path, original) in originals {
+            match original {
+                Some(content) => fs::write(path, content)?,
+                None => {
+                    if Path::new(path).exists() {
+                        fs::remove_file(path)?;
+                    }
+                }
             }
         }
+        Ok(())
+    }
 
-        let final_commit_message = if commit_message.is_empty() {
-            "Apply changes from LLM".to_string()
+    fn apply_change(&self, change: &FileChange) -> anyhow::Result<()> {
+        let path = Path::new(&change.path);
+        let original_content = if path.exists() {
+            Some(fs::read_to_string(path)?)
         } else {
-            commit_message.to_string()
+            None
         };
+        let final_content = apply_to_file(
+            change,
+            original_content.as_deref(),
+            self.new_file_trailing_newline,
+        )?;
 
-        println!("Committing changes with message: {}", final_commit_message);
-        let status = Command::new("git")
-            .arg("commit")
-            .arg("-m")
-            .arg(&final_commit_message)
-            .status()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, final_content)?;
+        Ok(())
+    }
+}
 
-        if !status.success() {
-            anyhow::bail!("git commit failed");
+/// Applies `change` to a file's content as read from disk, or to a missing file if `None`,
+/// and returns what should be written back.
+pub fn apply_to_file(
+    change: &FileChange,
+    content: Option<&str>,
+    new_file_trailing_newline: bool,
+) -> anyhow::Result<String> {
+    // Edits are parsed with LF line endings, so match against an LF-normalized copy
+    // of the file and restore its dominant line ending when writing it back.
+    let use_crlf = content.is_some_and(uses_crlf);
+    let content = content.map(|content| content.replace("\r\n", "\n"));
+    let trailing_newline = match content.as_deref() {
+        Some(content) if !content.is_empty() => content.ends_with('\n'),
+        _ => new_file_trailing_newline,
+    };
+
+    let mut final_content = apply_to_content(change, content.as_deref())?;
+    if trailing_newline && !final_content.is_empty() && !final_content.ends_with('\n') {
+        final_content.push('\n');
+    }
+    if use_crlf {
+        final_content = final_content.replace('\n', "\r\n");
+    }
+    Ok(final_content)
+}
+
+/// Adds the conventional commit type unless the message already has one, then
+/// `prefix` unless the message already starts with it.
+fn prefix_commit_message(message: &str, commit_type: Option<&str>, prefix: Option<&str>) -> String {
+    let conventional_re = Regex::new(r"^[a-zA-Z]+(\([^)]*\))?!?: ").unwrap();
+    let mut message = message.to_string();
+    if let Some(commit_type) = commit_type {
+        if !conventional_re.is_match(&message) {
+            message = format!("{}: {}", commit_type, message);
+        }
+    }
+    if let Some(prefix) = prefix.map(str::trim).filter(|prefix| !prefix.is_empty()) {
+        if !message.starts_with(prefix) {
+            message = format!("{} {}", prefix, message);
         }
+    }
+    message
+}
 
-        println!("Changes committed successfully.");
+/// Applies `change` to a file's LF-normalized `content`, or to a missing file if `None`,
+/// and returns the new content. Trailing newline and line ending fixes are left to the caller.
+pub fn apply_to_content(change: &FileChange, content: Option<&str>) -> anyhow::Result<String> {
+    if change.kind == ChangeKind::Append {
+        let mut content = content.unwrap_or_default().to_string();
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&change.replace_content);
+        return Ok(content);
+    }
+    if change.search_content.is_empty() {
+        // An empty search block means replace the entire file.
+        return Ok(change.replace_content.clone());
+    }
 
-        Ok(())
+    // A non-empty search block means find and replace a specific part of the file.
+    let content = content.unwrap_or_default();
+    let occurrences = content.matches(&change.search_content).count();
+    if occurrences == 0 {
+        // Models sometimes cut the first or last line of a SEARCH block short.
+        // Accept that only when it identifies a single span.
+        let spans = anchored_spans(content, &change.search_content);
+        if let [span] = spans.as_slice() {
+            eprintln!(
+                "Warning: SEARCH block for {} matched only after treating its first and last lines as prefixes.",
+                change.path
+            );
+            let mut content = content.to_string();
+            content.replace_range(span.clone(), &change.replace_content);
+            return Ok(content);
+        }
+        return Err(EditConflict {
+            reason: ConflictReason::NotFound,
+            search: change.search_content.clone(),
+            nearest_lines: nearest_lines(content, &change.search_content),
+            path: change.path.clone(),
+        }
+        .into());
+    }
+    if occurrences > 1 {
+        let match_lines = content
+            .match_indices(&change.search_content)
+            .map(|(offset, _)| {
+                let line = content[..offset].matches('\n').count();
+                NumberedLine {
+                    line: line + 1,
+                    text: content.lines().nth(line).unwrap_or_default().to_string(),
+                }
+            })
+            .collect();
+        return Err(EditConflict {
+            reason: ConflictReason::Ambiguous,
+            search: change.search_content.clone(),
+            nearest_lines: match_lines,
+            path: change.path.clone(),
+        }
+        .into());
+    }
+    Ok(content.replacen(&change.search_content, &change.replace_content, 1))
+}
+
+/// The run of lines in `content`, as long as `search`, with the most lines equal to
+/// `search`'s once surrounding whitespace is ignored. Empty if no line matches.
+fn nearest_lines(content: &str, search: &str) -> Vec<NumberedLine> {
+    let search_lines: Vec<&str> = search.lines().map(str::trim).collect();
+    let lines: Vec<&str> = content.lines().collect();
+    let score = |start: usize| {
+        search_lines
+            .iter()
+            .zip(&lines[start..])
+            .filter(|(search_line, line)| !search_line.is_empty() && line.trim() == **search_line)
+            .count()
+    };
+    // The first of several equally good runs wins.
+    let best =
+        (0..lines.len())
+            .map(|start| (score(start), start))
+            .fold((0, 0), |best, candidate| {
+                if candidate.0 > best.0 {
+                    candidate
+                } else {
+                    best
+                }
+            });
+    let (best_score, start) = best;
+    if best_score == 0 {
+        return Vec::new();
     }
+    lines
+        .iter()
+        .enumerate()
+        .skip(start)
+        .take(search_lines.len())
+        .map(|(index, text)| NumberedLine {
+            line: index + 1,
+            text: text.to_string(),
+        })
+        .collect()
+}
+
+/// Finds the byte ranges of runs of whole lines in `content` that match `search`
+/// line by line, except that the first and last lines of `search` only need to be
+/// prefixes of the lines they match. Those two lines must have some text, since a
+/// blank prefix would match any line.
+fn anchored_spans(content: &str, search: &str) -> Vec<std::ops::Range<usize>> {
+    let search_lines: Vec<&str> = search.lines().collect();
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        lines.push((offset, line));
+        offset += line.len();
+    }
+    if search_lines.is_empty() || search_lines.len() > lines.len() {
+        return Vec::new();
+    }
+    let last = search_lines.len() - 1;
+    if search_lines[0].trim().is_empty() || search_lines[last].trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut spans = Vec::new();
+    for start in 0..=lines.len() - search_lines.len() {
+        let matches = search_lines.iter().enumerate().all(|(i, search_line)| {
+            let line = lines[start + i].1.trim_end_matches('\n');
+            if i == 0 || i == last {
+                line.starts_with(search_line)
+            } else {
+                line == *search_line
+            }
+        });
+        if matches {
+            let (end_offset, end_line) = lines[start + last];
+            // Keep the last line's newline unless the SEARCH block included it.
+            let end = if search.ends_with('\n') {
+                end_offset + end_line.len()
+            } else {
+                end_offset + end_line.trim_end_matches('\n').len()
+            };
+            spans.push(lines[start].0..end);
+        }
+    }
+    spans
+}
+
+/// Resolves `path` (relative to the current directory) to a canonical absolute path
+/// without touching the filesystem. For a path that doesn't exist yet, its nearest
+/// existing ancestor is canonicalized and the rest joined on, so the checks made before
+/// writing see where the file would go.
+fn resolve_path(path: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(path);
+    let absolute_path = if path.is_absolute() {
+        path
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+
+    let mut existing = absolute_path.as_path();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        missing.extend(existing.components().next_back());
+        existing = existing
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("No part of {} exists", absolute_path.display()))?;
+    }
+    let mut resolved = existing.canonicalize()?;
+    // The missing directories can't be symlinks, so `..` among them is resolved as written.
+    for component in missing.into_iter().rev() {
+        match component {
+            std::path::Component::ParentDir => {
+                resolved.pop();
+            }
+            std::path::Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    Ok(resolved)
+}
+
+/// Returns true if most line endings in `content` are CRLF.
+fn uses_crlf(content: &str) -> bool {
+    let crlf = content.matches("\r\n").count();
+    let lf = content.matches('\n').count() - crlf;
+    crlf > lf
 }
 
 impl Hook for PostprocessorHook {
-    fn post_send(&self, llm_response: &str, project_root: &Option<PathBuf>) -> anyhow::Result<()> {
-        let (commit_message, changes) = self.parse_changes(llm_response)?;
-        if !changes.is_empty() {
-            self.apply_and_commit_changes(&commit_message, &changes, project_root)?;
+    fn post_send(
+        &self,
+        _llm_response: &str,
+        parsed: &ParsedResponse,
+        context: &HookContext,
+    ) -> anyhow::Result<Vec<EditResult>> {
+        let commit_message = self
+            .commit_message_override
+            .as_deref()
+            .unwrap_or(&parsed.commit_message);
+        self.apply_and_commit_changes(commit_message, &parsed.changes, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_path_does_not_create_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = dir.path().join("new/deeper/../x.rs");
+
+        let resolved = resolve_path(path.to_str().unwrap()).unwrap();
+        assert_eq!(resolved, root.join("new/x.rs"));
+        assert!(!dir.path().join("new").exists());
+        assert_eq!(
+            resolve_path(dir.path().join("../outside/y.rs").to_str().unwrap()).unwrap(),
+            root.parent().unwrap().join("outside/y.rs")
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = "feat: two edits\n\nsrc/a.rs\n<<<<<<< SEARCH\nold\n=======\nnew\n>>>>>>> REPLACE\n\nCHANGELOG.md\n<<<<<<< APPEND\n- entry\n>>>>>>> APPEND\n";
+
+        let parsed = PostprocessorHook::parse_response(response, EditFormat::default()).unwrap();
+
+        assert_eq!(parsed.commit_message, "feat: two edits");
+        assert_eq!(parsed.changes.len(), 2);
+        assert_eq!(parsed.changes[0].path, "src/a.rs");
+        assert_eq!(parsed.changes[0].kind, ChangeKind::Replace);
+        assert_eq!(parsed.changes[0].search_content, "old");
+        assert_eq!(parsed.changes[0].replace_content, "new");
+        assert_eq!(parsed.changes[1].path, "CHANGELOG.md");
+        assert_eq!(parsed.changes[1].kind, ChangeKind::Append);
+        assert_eq!(parsed.changes[1].replace_content, "- entry");
+
+        // Without APPEND blocks in the format, the block is left in the message.
+        let parsed =
+            PostprocessorHook::parse_response(response, EditFormat::SearchReplace).unwrap();
+        assert_eq!(parsed.changes.len(), 1);
+        assert!(parsed.commit_message.contains("<<<<<<< APPEND"));
+    }
+
+    #[test]
+    fn test_example_response_parses() {
+        for format in [EditFormat::SearchReplaceAppend, EditFormat::SearchReplace] {
+            let parsed =
+                PostprocessorHook::parse_response(&format.example_response(), format).unwrap();
+            let kinds: Vec<ChangeKind> = parsed.changes.iter().map(|change| change.kind).collect();
+            assert_eq!(kinds, format.kinds());
+            assert_eq!(parsed.commit_message, "docs: example edits");
         }
-        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_commit_message() {
+        assert_eq!(
+            prefix_commit_message("Add a parser", Some("feat(parser)"), None),
+            "feat(parser): Add a parser"
+        );
+        // The model already wrote a conventional message.
+        assert_eq!(
+            prefix_commit_message("fix(lexer)!: Drop tabs", Some("feat(parser)"), None),
+            "fix(lexer)!: Drop tabs"
+        );
+        assert_eq!(
+            prefix_commit_message("Add a parser", Some("feat"), Some("ai:")),
+            "ai: feat: Add a parser"
+        );
+        assert_eq!(
+            prefix_commit_message("ai: Add a parser", None, Some("ai:")),
+            "ai: Add a parser"
+        );
+        assert_eq!(
+            prefix_commit_message("Add a parser", None, None),
+            "Add a parser"
+        );
+    }
+
+    #[test]
+    fn test_anchored_spans() {
+        let content =
+            "fn main() {\n    let total = compute(1, 2);\n    println!(\"{}\", total);\n}\n";
+
+        // The first and last lines are cut short, the middle one is exact.
+        let spans = anchored_spans(
+            content,
+            "fn main\n    let total = compute(1, 2);\n    println!",
+        );
+        assert_eq!(spans.len(), 1);
+        assert_eq!(
+            &content[spans[0].clone()],
+            "fn main() {\n    let total = compute(1, 2);\n    println!(\"{}\", total);"
+        );
+
+        // Inner lines must match exactly.
+        assert!(anchored_spans(content, "fn main\n    let total\n    println!").is_empty());
+
+        // A prefix that fits several lines is ambiguous and yields every candidate.
+        assert_eq!(anchored_spans("a1\na2\n", "a").len(), 2);
+
+        // A blank first or last line would anchor anywhere, so it doesn't match.
+        assert!(
+            anchored_spans(content, "\n    let total = compute(1, 2);\n    println!").is_empty()
+        );
+        assert!(anchored_spans(content, "fn main\n    let total = compute(1, 2);\n  ").is_empty());
+    }
+
+    #[test]
+    fn test_apply_to_content_conflicts() {
+        let content = "fn a() {\n    let x = 1;\n    x\n}\nfn b() {\n    let x = 1;\n}\n";
+        let change = |search: &str| FileChange {
+            path: "lib.rs".to_string(),
+            kind: ChangeKind::Replace,
+            search_content: search.to_string(),
+            replace_content: "replaced".to_string(),
+        };
+
+        // The closest run of lines is the one with the most lines in common.
+        let err = apply_to_content(&change("fn a() {\n  let x = 2;\n    x\n}"), Some(content))
+            .unwrap_err();
+        let conflict = err.downcast_ref::<EditConflict>().unwrap();
+        assert_eq!(conflict.reason, ConflictReason::NotFound);
+        assert_eq!(err.to_string(), "SEARCH block not found in file lib.rs");
+        let lines: Vec<usize> = conflict.nearest_lines.iter().map(|l| l.line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 4]);
+        assert_eq!(conflict.nearest_lines[1].text, "    let x = 1;");
+
+        let err = apply_to_content(&change("nothing like it"), Some(content)).unwrap_err();
+        let conflict = err.downcast_ref::<EditConflict>().unwrap();
+        assert!(conflict.nearest_lines.is_empty());
+
+        let err = apply_to_content(&change("let x = 1;"), Some(content)).unwrap_err();
+        let conflict = err.downcast_ref::<EditConflict>().unwrap();
+        assert_eq!(conflict.reason, ConflictReason::Ambiguous);
+        assert_eq!(
+            conflict.nearest_lines,
+            vec![
+                NumberedLine {
+                    line: 2,
+                    text: "    let x = 1;".to_string()
+                },
+                NumberedLine {
+                    line: 6,
+                    text: "    let x = 1;".to_string()
+                },
+            ]
+        );
+        assert!(err.to_string().contains("appears 2 times"));
+    }
+
+    #[test]
+    fn test_parse_response_with_longer_fences() {
+        let response = "Document the flag\n\n````markdown\nREADME.md\n<<<<<<< SEARCH\n## Usage\n=======\n## Usage\n\n```bash\nretort send\n```\n>>>>>>> REPLACE\n````\n\n```bash\ncargo test\n```\n";
+
+        let parsed = PostprocessorHook::parse_response(response, EditFormat::default()).unwrap();
+
+        assert_eq!(parsed.commit_message, "Document the flag");
+        assert_eq!(parsed.changes.len(), 1);
+        assert_eq!(
+            parsed.changes[0].replace_content,
+            "## Usage\n\n```bash\nretort send\n```"
+        );
+    }
+
+    #[test]
+    fn test_parse_response_without_changes() {
+        let parsed =
+            PostprocessorHook::parse_response("Just an explanation.", EditFormat::default())
+                .unwrap();
+
+        assert_eq!(parsed.commit_message, "Just an explanation.");
+        assert!(parsed.changes.is_empty());
     }
 }
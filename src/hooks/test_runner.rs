@@ -0,0 +1,25 @@
+use crate::hooks::postprocessor::FileChange;
+use crate::hooks::EditHook;
+use std::process::Command;
+
+/// Runs a test command after edits are written. If it fails, the postprocessor
+/// reverts the edits and the commit is aborted.
+pub struct TestRunnerHook {
+    pub command: String,
+}
+
+impl EditHook for TestRunnerHook {
+    fn post_edit(&self, _changes: &[FileChange]) -> anyhow::Result<()> {
+        let output = Command::new("sh").arg("-c").arg(&self.command).output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Test command '{}' failed ({}).\n{}{}",
+                self.command,
+                output.status,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,29 @@
 use ::llm::chat::ChatMessage;
 use clap::Parser;
+use colored::Colorize;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::io::{stdout, Write};
-use std::path::PathBuf;
+use std::io::{stdout, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 
 pub mod cli;
 pub mod config;
 pub mod db;
+pub mod events;
+pub mod fence;
 pub mod hooks;
 pub mod llm;
+pub mod patch;
 pub mod prompt;
 
-use cli::{Cli, Command, TagSubcommand};
+use cli::{
+    Cli, Command, ConfigSubcommand, ContextSubcommand, DebugSubcommand, StageSubcommand,
+    TagSubcommand,
+};
+use events::Event;
 use hooks::HookManager;
 
 fn calculate_final_context(
@@ -56,6 +64,393 @@ fn calculate_final_context(
     final_context_map
 }
 
+/// Estimates the size of an assembled prompt.
+fn prompt_tokens(messages: &[prompt::Message]) -> usize {
+    messages
+        .iter()
+        .map(|message| llm::estimate_tokens(&message.content))
+        .sum()
+}
+
+/// The index of the largest prompt file that came from the file context, so diffs
+/// and piped content are never dropped.
+fn largest_file(files: &[(String, String)], context: &HashMap<String, bool>) -> Option<usize> {
+    files
+        .iter()
+        .enumerate()
+        .filter(|(_, (path, _))| context.contains_key(path))
+        .max_by_key(|(_, (_, content))| content.len())
+        .map(|(index, _)| index)
+}
+
+/// Why the files about to be sent need confirming under `confirm_context_files` and
+/// `confirm_context_bytes`, or nothing if they're within both.
+fn large_context_reasons(files: &[&(String, String)], config: &config::Config) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if let Some(max_files) = config.confirm_context_files {
+        if files.len() > max_files {
+            reasons.push(format!(
+                "{} files, over confirm_context_files of {}",
+                files.len(),
+                max_files
+            ));
+        }
+    }
+    if let Some(max_bytes) = config.confirm_context_bytes {
+        let bytes: usize = files.iter().map(|(_, content)| content.len()).sum();
+        if bytes > max_bytes {
+            reasons.push(format!(
+                "{} bytes, over confirm_context_bytes of {}",
+                bytes, max_bytes
+            ));
+        }
+    }
+    reasons
+}
+
+/// Generates an unused tag for a new chat from the first few words of its prompt,
+/// e.g. `fix-the-login-bug`, adding `-2`, `-3`, ... if it's taken.
+fn new_chat_tag(conn: &rusqlite::Connection, prompt: &str) -> anyhow::Result<String> {
+    let words: Vec<String> = prompt
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .take(4)
+        .map(str::to_lowercase)
+        .collect();
+    let slug: String = words.join("-").chars().take(30).collect();
+    let mut base = slug.trim_end_matches('-').to_string();
+    if base.is_empty() {
+        base = "chat".to_string();
+    }
+
+    let mut tag = base.clone();
+    let mut suffix = 2;
+    while db::get_message_id_by_tag(conn, &tag)?.is_some() {
+        tag = format!("{}-{}", base, suffix);
+        suffix += 1;
+    }
+    Ok(tag)
+}
+
+/// Formats a `[role]` header, colored by role. `colored` turns this off for
+/// non-terminal output and when `NO_COLOR` is set.
+fn role_header(role: &str) -> colored::ColoredString {
+    let header = format!("[{}]", role);
+    match role {
+        "user" => header.green().bold(),
+        "assistant" => header.cyan().bold(),
+        _ => header.yellow().bold(),
+    }
+}
+
+/// Asks a yes/no question on stdin. An empty answer (or EOF) returns `default`,
+/// and unrecognized answers ask again. With `assume_yes` (the global `--yes`), the
+/// question is shown already answered and nothing is read.
+fn prompt_yes_no(question: &str, default: bool, assume_yes: bool) -> anyhow::Result<bool> {
+    let choices = if default { "[Y/n]" } else { "[y/N]" };
+    if assume_yes {
+        println!("{} {} y (--yes)", question, choices);
+        return Ok(true);
+    }
+    loop {
+        print!("{} {} ", question, choices);
+        stdout().flush()?;
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input)? == 0 {
+            println!();
+            return Ok(default);
+        }
+        match input.trim().to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer 'y' or 'n'."),
+        }
+    }
+}
+
+/// Resolves a command argument that is either a tag or a message ID, preferring the tag.
+fn resolve_target(conn: &rusqlite::Connection, target: &str) -> anyhow::Result<i64> {
+    match db::get_message_id_by_tag(conn, target)? {
+        Some(id) => Ok(id),
+        None => match target.parse::<i64>() {
+            Ok(id) if db::message_exists(conn, id)? => Ok(id),
+            _ => anyhow::bail!("'{}' is neither a tag nor a message ID.", target),
+        },
+    }
+}
+
+/// Loads the file context metadata stored on a message, if it has any.
+fn load_message_metadata(
+    conn: &rusqlite::Connection,
+    message_id: i64,
+) -> anyhow::Result<Option<MessageMetadata>> {
+    match db::get_message_metadata(conn, message_id)? {
+        Some(metadata_json) if !metadata_json.is_empty() => {
+            Ok(Some(serde_json::from_str(&metadata_json)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The SHA-256 hex digest recorded for a context file's content.
+fn hash_content(content: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(content.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// The context inherited by a message continuing from `head_id`. Context metadata lives
+/// on user messages, so an assistant head inherits from the user message it answered,
+/// and a user head (e.g. a tagged root prompt) from itself.
+fn inherited_context(conn: &rusqlite::Connection, head_id: i64) -> anyhow::Result<MessageMetadata> {
+    let head = db::get_message(conn, head_id)?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID '{}' not found.", head_id))?;
+    let user_message_id = match head.role.as_str() {
+        "user" => Some(head.id),
+        "assistant" => head.parent_id,
+        _ => None,
+    };
+    match user_message_id {
+        Some(id) => Ok(load_message_metadata(conn, id)?.unwrap_or_default()),
+        None => Ok(MessageMetadata::default()),
+    }
+}
+
+/// The context the active chat's next message inherits.
+fn active_inherited_context(conn: &rusqlite::Connection) -> anyhow::Result<MessageMetadata> {
+    if let Some(tag) = db::get_active_chat_tag(conn)? {
+        if let Some(head_id) = db::get_message_id_by_tag(conn, &tag)? {
+            return inherited_context(conn, head_id);
+        }
+    }
+    Ok(MessageMetadata::default())
+}
+
+/// Resolves the exact file set used by another turn, for `send --context-from`.
+/// Context metadata lives on user messages, so for an assistant reply the
+/// context of the user message it answered is used.
+fn context_from_message(
+    conn: &rusqlite::Connection,
+    message_id: i64,
+) -> anyhow::Result<HashMap<String, bool>> {
+    let message = db::get_message(conn, message_id)?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID '{}' not found.", message_id))?;
+    let user_message_id = if message.role == "assistant" {
+        message.parent_id
+    } else {
+        Some(message.id)
+    };
+    let metadata = match user_message_id {
+        Some(id) => load_message_metadata(conn, id)?,
+        None => None,
+    }
+    .ok_or_else(|| anyhow::anyhow!("Message {} has no file context to reuse.", message_id))?;
+
+    let mut context_map = HashMap::new();
+    for file in metadata.read_write_files {
+        context_map.insert(file.path, false);
+    }
+    for file in metadata.read_only_files {
+        context_map.insert(file.path, true);
+    }
+    Ok(context_map)
+}
+
+/// Resolves the message to branch from to redo the latest turn of `tag`: the assistant
+/// reply that preceded the turn the tag points to, or `None` if that turn was the first.
+fn previous_turn(conn: &rusqlite::Connection, tag: &str) -> anyhow::Result<Option<i64>> {
+    let target_id = db::get_message_id_by_tag(conn, tag)?
+        .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", tag))?;
+    let target = db::get_message(conn, target_id)?
+        .ok_or_else(|| anyhow::anyhow!("Tag '{}' points to missing message {}.", tag, target_id))?;
+    // A turn is a user message and the assistant reply to it, so skip past the user message.
+    let turn_start = if target.role == "assistant" {
+        target.parent_id
+    } else {
+        Some(target_id)
+    };
+    match turn_start {
+        Some(user_message_id) => db::get_parent_id(conn, user_message_id),
+        None => Ok(None),
+    }
+}
+
+/// Shows `text` in `$PAGER` (default `less`), or prints it if the pager can't be started
+/// or exits with an error, e.g. when `sh` can't find the program `$PAGER` names.
+fn page(text: &str) -> anyhow::Result<()> {
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+    let child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(&pager)
+        .stdin(std::process::Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Warning: could not start pager '{}' ({}).", pager, e);
+            println!("{}", text);
+            return Ok(());
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // Quitting the pager early closes the pipe, which isn't an error.
+        match stdin.write_all(text.as_bytes()) {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        eprintln!("Warning: pager '{}' failed ({}).", pager, status);
+        println!("{}", text);
+    }
+    Ok(())
+}
+
+/// Whether two relative paths name the same file, ignoring `./` components.
+fn same_path(a: &str, b: &str) -> bool {
+    let not_cur_dir = |c: &std::path::Component| *c != std::path::Component::CurDir;
+    Path::new(a)
+        .components()
+        .filter(not_cur_dir)
+        .eq(Path::new(b).components().filter(not_cur_dir))
+}
+
+/// Reads the source of a `prompt_templates` entry, or `None` for the built-in `default`.
+fn load_prompt_template(config: &config::Config, name: &str) -> anyhow::Result<Option<String>> {
+    if name == "default" {
+        return Ok(None);
+    }
+    let path = config.prompt_templates.get(name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Unknown prompt template '{}'; add it under prompt_templates in the config.",
+            name
+        )
+    })?;
+    let path = shellexpand::tilde(path);
+    let source = fs::read_to_string(path.as_ref()).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to read prompt template '{}' ({}): {}",
+            name,
+            path,
+            e
+        )
+    })?;
+    Ok(Some(source))
+}
+
+/// Whether `path` matches a `force_read_only_patterns` glob. `**` matches across
+/// directories, `*` and `?` within one.
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    let target = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex::Regex::new(&regex).is_ok_and(|re| re.is_match(target))
+}
+
+/// The file and line range of a `path:start-end` context entry. A file whose name really
+/// does end like that is taken as the whole file.
+fn line_range(path: &str) -> Option<(&str, usize, usize)> {
+    if Path::new(path).exists() {
+        return None;
+    }
+    prompt::split_line_range(path)
+}
+
+/// Reads a context file, or only the lines a `path:start-end` entry asks for.
+fn read_context_file(path: &str) -> anyhow::Result<String> {
+    let Some((file, start, end)) = line_range(path) else {
+        return Ok(fs::read_to_string(path)?);
+    };
+    let content = fs::read_to_string(file)?;
+    let lines: Vec<&str> = content.lines().collect();
+    if start > lines.len() {
+        anyhow::bail!(
+            "{} has only {} lines, so {} is empty.",
+            file,
+            lines.len(),
+            path
+        );
+    }
+    Ok(lines[start - 1..end.min(lines.len())].join("\n"))
+}
+
+/// The files edits may not touch: read-only files, and the files behind line ranges unless
+/// they're also in the context whole as read-write.
+fn protected_paths(context: &HashMap<String, bool>) -> Vec<String> {
+    context
+        .iter()
+        .filter_map(|(path, is_readonly)| match line_range(path) {
+            Some((file, _, _)) if context.get(file) != Some(&false) => Some(file.to_string()),
+            Some(_) => None,
+            None => is_readonly.then(|| path.clone()),
+        })
+        .collect()
+}
+
+/// Adds the project's `README.md` to the context as read-only, unless it's missing,
+/// already in the context under some path, or dropped on the stage.
+fn add_readme(
+    final_context_map: &mut HashMap<String, bool>,
+    project_root: Option<&Path>,
+    dropped_files: &[String],
+) {
+    let readme = match project_root {
+        Some(root) => root.join("README.md"),
+        None => PathBuf::from("README.md"),
+    };
+    let Ok(readme_canonical) = readme.canonicalize() else {
+        return;
+    };
+    let same_file = |path: &String| {
+        Path::new(path)
+            .canonicalize()
+            .is_ok_and(|canonical| canonical == readme_canonical)
+    };
+    if final_context_map.keys().any(same_file) || dropped_files.iter().any(same_file) {
+        return;
+    }
+    final_context_map.insert(readme.to_string_lossy().into_owned(), true);
+}
+
+/// Runs `git diff` (or `git diff --staged`) in `project_root`, or the current directory if unset.
+fn git_diff(project_root: Option<&Path>, staged: bool) -> anyhow::Result<String> {
+    let mut command = std::process::Command::new("git");
+    command.arg("diff");
+    if staged {
+        command.arg("--staged");
+    }
+    if let Some(root) = project_root {
+        command.current_dir(root);
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct FileMetadata {
     pub path: String,
@@ -68,23 +463,206 @@ pub struct MessageMetadata {
     pub read_only_files: Vec<FileMetadata>,
 }
 
+/// A reproducible request for `send --task`: the prompt, the files to send with it, and
+/// optionally the model settings to use.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TaskFile {
+    prompt: String,
+    #[serde(default)]
+    files: Vec<TaskFileEntry>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    temperature: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TaskFileEntry {
+    path: String,
+    #[serde(default)]
+    read_only: bool,
+}
+
+fn load_task_file(path: &Path) -> anyhow::Result<TaskFile> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read task file {}: {}", path.display(), e))?;
+    serde_yaml::from_str(&contents)
+        .map_err(|e| anyhow::anyhow!("Failed to parse task file {}: {}", path.display(), e))
+}
+
+/// Metadata stored on assistant messages, for diagnosing how a response was produced.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AssistantMetadata {
+    /// Whether the response came from the streaming path rather than the buffered one.
+    pub streamed: bool,
+    /// Whether the stream failed part way, leaving only the start of the response.
+    #[serde(default)]
+    pub partial: bool,
+    /// Wall-clock milliseconds from sending the request to having the whole response.
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+}
+
+/// Stores what arrived of a response whose stream failed, so it isn't lost, and returns
+/// the stream error with a note saying where it went. No edits are applied from it.
+fn save_partial_response(
+    conn: &rusqlite::Connection,
+    user_message_id: i64,
+    partial: &str,
+    err: anyhow::Error,
+) -> anyhow::Result<anyhow::Error> {
+    let metadata_json = serde_json::to_string(&AssistantMetadata {
+        streamed: true,
+        partial: true,
+        latency_ms: None,
+    })?;
+    let message_id = db::add_message(
+        conn,
+        Some(user_message_id),
+        "assistant",
+        partial,
+        Some(&metadata_json),
+    )?;
+    Ok(err.context(format!(
+        "The response stream failed after {} bytes. They were saved as message {}, and no edits were applied.",
+        partial.len(),
+        message_id
+    )))
+}
+
+/// The temp file a streamed response for `user_message_id` is backed up to.
+fn stream_backup_path(user_message_id: i64) -> PathBuf {
+    std::env::temp_dir().join(format!("retort-response-{}.txt", user_message_id))
+}
+
+/// The backup of a streamed response, created when the first chunk arrives. It is removed
+/// when dropped, so a stream that fails or is abandoned leaves no file behind, unless
+/// `keep` hands it over to be removed once the response is stored.
+struct StreamBackup {
+    path: PathBuf,
+    file: Option<fs::File>,
+    keep: bool,
+}
+
+impl StreamBackup {
+    fn new(user_message_id: i64) -> Self {
+        Self {
+            path: stream_backup_path(user_message_id),
+            file: None,
+            keep: false,
+        }
+    }
+
+    fn write(&mut self, text: &str) -> anyhow::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(fs::File::create(&self.path)?),
+        };
+        file.write_all(text.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn keep(mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for StreamBackup {
+    fn drop(&mut self) {
+        if !self.keep && self.file.is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Builds the hook manager whose postprocessor applies, checks and commits the edits in
+/// a response. The flags are the ones `send` and `replay-edits` take on top of the config.
+fn edit_hook_manager(
+    config: &config::Config,
+    json_events: bool,
+    commit_message_override: Option<String>,
+    commit_type: Option<String>,
+    require_clean_tree: bool,
+    force: bool,
+    continue_on_edit_error: bool,
+) -> anyhow::Result<HookManager> {
+    let mut edit_hooks: Vec<Box<dyn hooks::EditHook>> = Vec::new();
+    if !config.formatters.is_empty() {
+        edit_hooks.push(Box::new(hooks::formatter::FormatterHook::new(
+            &config.formatters,
+        )?));
+    }
+    // Tests run last so they see formatted code.
+    if let Some(command) = &config.test_command {
+        edit_hooks.push(Box::new(hooks::test_runner::TestRunnerHook {
+            command: command.clone(),
+        }));
+    }
+
+    let mut hook_manager = HookManager::new(config.edit_format);
+    hook_manager.register(Box::new(hooks::postprocessor::PostprocessorHook {
+        json_events,
+        edit_hooks,
+        new_file_trailing_newline: config.new_file_trailing_newline,
+        commit_retries: config.commit_retries,
+        commit_message_override,
+        commit_type,
+        commit_prefix: config.commit_prefix.clone(),
+        require_clean_tree: config.require_clean_tree || require_clean_tree,
+        max_edits: config.max_edits_per_response.filter(|_| !force),
+        continue_on_edit_error,
+    }));
+    Ok(hook_manager)
+}
+
 pub async fn run() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let assume_yes = cli.yes;
+    // Runs before loading the config, so a missing or broken one can be replaced.
+    if let Some(Command::Config(ConfigSubcommand::Init { force })) = cli.command {
+        let config_path = config::init(force)?;
+        println!("Wrote {}.", config_path.display());
+        return Ok(());
+    }
     let config = config::load()?;
     let expanded_path = shellexpand::tilde(&config.database_path);
     let conn = db::setup(&expanded_path)?;
 
-    let mut hook_manager = HookManager::new();
-    hook_manager.register(Box::new(hooks::postprocessor::PostprocessorHook {}));
-
     if let Some(command) = cli.command {
         match command {
             Command::Tag(tag_command) => match tag_command {
-                TagSubcommand::Set { tag, message } => {
+                TagSubcommand::Set {
+                    tag,
+                    message,
+                    descendant_only,
+                    force,
+                } => {
                     if !db::message_exists(&conn, message)? {
                         anyhow::bail!("Message with ID '{}' not found.", message);
                     }
                     let old_message_id = db::get_message_id_by_tag(&conn, &tag)?;
+                    if let (Some(old_id), true, false) = (old_message_id, descendant_only, force) {
+                        if old_id != message
+                            && !db::get_ancestor_ids(&conn, message)?.contains(&old_id)
+                        {
+                            let relation =
+                                if db::get_ancestor_ids(&conn, old_id)?.contains(&message) {
+                                    "an ancestor"
+                                } else {
+                                    "not a descendant"
+                                };
+                            anyhow::bail!(
+                                "Message {} is {} of message {}, which tag '{}' points to; pass --force to move the tag anyway.",
+                                message,
+                                relation,
+                                old_id,
+                                tag
+                            );
+                        }
+                    }
                     match old_message_id {
                         Some(old_id) if old_id == message => {
                             println!("Tag '{}' already points to message {}.", tag, message);
@@ -124,41 +702,343 @@ pub async fn run() -> anyhow::Result<()> {
                         }
                     }
                 }
+                TagSubcommand::Show { tag } => {
+                    let message_id = db::get_message_id_by_tag(&conn, &tag)?
+                        .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", tag))?;
+                    let message = db::get_message(&conn, message_id)?.ok_or_else(|| {
+                        anyhow::anyhow!("Tag '{}' points to missing message {}.", tag, message_id)
+                    })?;
+                    let is_leaf = db::is_leaf(&conn, message_id)?;
+
+                    let truncated_content: String = message.content.chars().take(70).collect();
+                    let one_line_content = truncated_content.replace('\n', " ");
+
+                    println!("Tag:        {}", tag);
+                    println!("Message ID: {}", message.id);
+                    println!("Role:       {}", message.role);
+                    println!("Created At: {}", message.created_at);
+                    println!("Leaf:       {}", if is_leaf { "yes" } else { "no" });
+                    println!("Preview:    {}", one_line_content);
+                }
+            },
+            Command::Title { tag, title, notes } => {
+                let message_id = db::get_message_id_by_tag(&conn, &tag)?
+                    .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", tag))?;
+                let root_id = db::get_root_id(&conn, message_id)?;
+                if title.is_none() && notes.is_none() {
+                    let conversation = db::get_conversation(&conn, root_id)?.unwrap_or_default();
+                    println!(
+                        "Title: {}",
+                        conversation.title.as_deref().unwrap_or("(none)")
+                    );
+                    println!(
+                        "Notes: {}",
+                        conversation.notes.as_deref().unwrap_or("(none)")
+                    );
+                }
+                if let Some(title) = title {
+                    db::set_conversation_title(&conn, root_id, &title)?;
+                    println!("Set title of the chat tagged '{}' to '{}'.", tag, title);
+                }
+                if let Some(notes) = notes {
+                    db::set_conversation_notes(&conn, root_id, &notes)?;
+                    println!("Updated notes for the chat tagged '{}'.", tag);
+                }
+            }
+            Command::Copy { tag, new_tag } => {
+                let head_id = db::get_message_id_by_tag(&conn, &tag)?
+                    .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", tag))?;
+                if let Some(existing_id) = db::get_message_id_by_tag(&conn, &new_tag)? {
+                    anyhow::bail!(
+                        "Tag '{}' already exists, pointing to message {}.",
+                        new_tag,
+                        existing_id
+                    );
+                }
+                let copied = db::get_ancestor_ids(&conn, head_id)?.len();
+                let new_head_id = db::copy_chain(&conn, head_id)?;
+                db::set_chat_tag(&conn, &new_tag, new_head_id)?;
+                println!(
+                    "Copied {} messages from '{}' to '{}', with its head at message {}.",
+                    copied, tag, new_tag, new_head_id
+                );
+            }
+            Command::Template { tag, name } => {
+                let message_id = db::get_message_id_by_tag(&conn, &tag)?
+                    .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", tag))?;
+                let root_id = db::get_root_id(&conn, message_id)?;
+                match name {
+                    None => {
+                        let conversation =
+                            db::get_conversation(&conn, root_id)?.unwrap_or_default();
+                        println!(
+                            "{}",
+                            conversation.prompt_template.as_deref().unwrap_or("default")
+                        );
+                    }
+                    Some(name) => {
+                        // Check it now rather than on the chat's next send.
+                        load_prompt_template(&config, &name)?;
+                        let stored = Some(name.as_str()).filter(|name| *name != "default");
+                        db::set_conversation_prompt_template(&conn, root_id, stored)?;
+                        println!(
+                            "The chat tagged '{}' now uses the '{}' prompt template.",
+                            tag, name
+                        );
+                    }
+                }
+            }
+            Command::Context(context_command) => match context_command {
+                ContextSubcommand::Save { name } => {
+                    let stage = db::get_context_stage(&conn, "default")?;
+                    if stage.read_write_files.is_empty()
+                        && stage.read_only_files.is_empty()
+                        && stage.dropped_files.is_empty()
+                    {
+                        anyhow::bail!("The stage is empty; nothing to save.");
+                    }
+                    let replaced = db::save_context_preset(&conn, &name, &stage)?;
+                    let file_count = stage.read_write_files.len()
+                        + stage.read_only_files.len()
+                        + stage.dropped_files.len();
+                    if replaced {
+                        println!("Updated preset '{}' with {} file(s).", name, file_count);
+                    } else {
+                        println!("Saved preset '{}' with {} file(s).", name, file_count);
+                    }
+                }
+                ContextSubcommand::Load { name } => {
+                    let preset = db::get_context_preset(&conn, &name)?
+                        .ok_or_else(|| anyhow::anyhow!("Preset '{}' not found.", name))?;
+                    db::load_context_preset(&conn, "default", &preset)?;
+                    for path in &preset.read_write_files {
+                        println!("Staged {} as read-write.", path);
+                    }
+                    for path in &preset.read_only_files {
+                        println!("Staged {} as read-only.", path);
+                    }
+                    for path in &preset.dropped_files {
+                        println!("Marked {} to be dropped from context.", path);
+                    }
+                }
+                ContextSubcommand::List => {
+                    let presets = db::list_context_presets(&conn)?;
+                    if presets.is_empty() {
+                        println!("No presets found.");
+                    } else {
+                        println!("{:<30} Files", "Preset");
+                        println!("{:-<30} {:-<10}", "", "");
+                        for preset in presets {
+                            let mut files: Vec<String> = preset.read_write_files.clone();
+                            files.extend(
+                                preset.read_only_files.iter().map(|f| format!("{} (ro)", f)),
+                            );
+                            files.extend(
+                                preset.dropped_files.iter().map(|f| format!("{} (drop)", f)),
+                            );
+                            println!("{:<30} {}", preset.name, files.join(", "));
+                        }
+                    }
+                }
+                ContextSubcommand::Delete { name } => {
+                    if db::delete_context_preset(&conn, &name)? {
+                        println!("Deleted preset '{}'.", name);
+                    } else {
+                        println!("Preset '{}' not found.", name);
+                    }
+                }
+                ContextSubcommand::Diff { a, b } => {
+                    // Each side is what a message continuing from it would be sent with.
+                    let prepared_stage = db::get_context_stage(&conn, "default")?;
+                    let context_a = calculate_final_context(
+                        &inherited_context(&conn, resolve_target(&conn, &a)?)?,
+                        &prepared_stage,
+                    );
+                    let context_b = calculate_final_context(
+                        &inherited_context(&conn, resolve_target(&conn, &b)?)?,
+                        &prepared_stage,
+                    );
+                    let mode = |read_only: bool| if read_only { "RO" } else { "RW" };
+
+                    let mut paths: Vec<&String> =
+                        context_a.keys().chain(context_b.keys()).collect();
+                    paths.sort();
+                    paths.dedup();
+                    let (mut added, mut removed, mut changed) = (0, 0, 0);
+                    for path in paths {
+                        match (context_a.get(path), context_b.get(path)) {
+                            (None, Some(ro)) => {
+                                added += 1;
+                                println!("+ {} {}", mode(*ro), path);
+                            }
+                            (Some(ro), None) => {
+                                removed += 1;
+                                println!("- {} {}", mode(*ro), path);
+                            }
+                            (Some(ro_a), Some(ro_b)) if ro_a != ro_b => {
+                                changed += 1;
+                                println!("~ {} {} -> {}", path, mode(*ro_a), mode(*ro_b));
+                            }
+                            _ => {}
+                        }
+                    }
+                    if added + removed + changed == 0 {
+                        println!("'{}' and '{}' have the same context.", a, b);
+                    } else {
+                        println!(
+                            "{} added, {} removed, {} changed mode.",
+                            added, removed, changed
+                        );
+                    }
+                }
             },
             Command::Stage(args) => {
-                if let Some(file_path) = args.file_path {
-                    if args.drop {
+                if let Some(StageSubcommand::Mv { old, new }) = args.command {
+                    let read_only = match db::rename_staged_file(&conn, "default", &old, &new)? {
+                        Some(read_only) => read_only,
+                        None => {
+                            // Not staged for the next message, but it may be inherited.
+                            let inherited_stage = active_inherited_context(&conn)?;
+                            let read_only = if inherited_stage
+                                .read_write_files
+                                .iter()
+                                .any(|file| file.path == old)
+                            {
+                                false
+                            } else if inherited_stage
+                                .read_only_files
+                                .iter()
+                                .any(|file| file.path == old)
+                            {
+                                true
+                            } else {
+                                anyhow::bail!("{} is not in the context.", old);
+                            };
+                            db::remove_file_from_stage(&conn, "default", &old)?;
+                            db::add_file_to_stage(&conn, "default", &new, read_only)?;
+                            read_only
+                        }
+                    };
+                    let file_type = if read_only { "read-only" } else { "read-write" };
+                    println!("Renamed {} to {} ({}).", old, new, file_type);
+                } else if let Some(StageSubcommand::Status { porcelain: true }) = args.command {
+                    let inherited_stage = active_inherited_context(&conn)?;
+                    let prepared_stage = db::get_context_stage(&conn, "default")?;
+                    let final_context_map =
+                        calculate_final_context(&inherited_stage, &prepared_stage);
+
+                    let prepared_paths: HashSet<&String> = prepared_stage
+                        .read_write_files
+                        .iter()
+                        .chain(prepared_stage.read_only_files.iter())
+                        .collect();
+                    let mut lines: Vec<(String, &str, &str)> = final_context_map
+                        .iter()
+                        .map(|(path, is_readonly)| {
+                            let mode = if *is_readonly { "RO" } else { "RW" };
+                            let provenance = if prepared_paths.contains(path) {
+                                "prepared"
+                            } else {
+                                "inherited"
+                            };
+                            (path.clone(), mode, provenance)
+                        })
+                        .collect();
+                    for path in &prepared_stage.dropped_files {
+                        if !final_context_map.contains_key(path) {
+                            lines.push((path.clone(), "-", "dropped"));
+                        }
+                    }
+                    for inline_file in &prepared_stage.inline_files {
+                        lines.push((inline_file.name.clone(), "RO", "stdin"));
+                    }
+                    lines.sort();
+
+                    for (path, mode, provenance) in lines {
+                        let (exists, hash) = if provenance == "stdin" {
+                            let inline_file = prepared_stage
+                                .inline_files
+                                .iter()
+                                .find(|f| f.name == path)
+                                .unwrap();
+                            ("inline", hash_content(&inline_file.content))
+                        } else {
+                            let file = line_range(&path).map_or(path.as_str(), |(file, _, _)| file);
+                            match read_context_file(&path) {
+                                Ok(content) => ("exists", hash_content(&content)),
+                                Err(_) if Path::new(file).exists() => ("exists", "-".to_string()),
+                                Err(_) => ("missing", "-".to_string()),
+                            }
+                        };
+                        println!("{}\t{}\t{}\t{}\t{}", mode, provenance, exists, hash, path);
+                    }
+                } else if let Some(file_path) = args.file_path {
+                    if args.name.is_some() && (file_path != "-" || args.drop) {
+                        anyhow::bail!(
+                            "--name only applies when staging stdin with `retort stage -`."
+                        );
+                    }
+                    if file_path == "-" && !args.drop {
+                        let Some(name) = args.name else {
+                            anyhow::bail!("Staging stdin needs --name to label the content.");
+                        };
+                        let mut content = String::new();
+                        std::io::stdin().read_to_string(&mut content)?;
+                        if let Some(max_bytes) = config.max_staged_file_bytes {
+                            if content.len() as u64 > max_bytes {
+                                anyhow::bail!(
+                                    "stdin is {} bytes, over max_staged_file_bytes of {}.",
+                                    content.len(),
+                                    max_bytes
+                                );
+                            }
+                        }
+                        db::add_inline_file_to_stage(&conn, "default", &name, &content)?;
+                        println!(
+                            "Staged {} bytes from stdin as {} (read-only).",
+                            content.len(),
+                            name
+                        );
+                    } else if args.drop {
                         db::remove_file_from_stage(&conn, "default", &file_path)?;
                         println!("Marked {} to be dropped from context.", file_path);
                     } else {
-                        db::add_file_to_stage(&conn, "default", &file_path, args.read_only)?;
-                        let file_type = if args.read_only {
-                            "read-only"
-                        } else {
-                            "read-write"
-                        };
+                        if let (Some(max_bytes), Ok(file_metadata)) =
+                            (config.max_staged_file_bytes, fs::metadata(&file_path))
+                        {
+                            if file_metadata.len() > max_bytes {
+                                eprintln!(
+                                    "Warning: {} is {} bytes, over max_staged_file_bytes of {}; it will be skipped at send time unless it shrinks or the limit is raised.",
+                                    file_path,
+                                    file_metadata.len(),
+                                    max_bytes
+                                );
+                            }
+                        }
+                        let forced_by = config
+                            .force_read_only_patterns
+                            .iter()
+                            .find(|pattern| glob_matches(pattern, &file_path))
+                            .filter(|_| !args.read_only && !args.read_write);
+                        if let Some(pattern) = forced_by {
+                            println!(
+                                "{} matches force_read_only_patterns entry '{}'; pass --rw to stage it read-write.",
+                                file_path, pattern
+                            );
+                        }
+                        // Edits to a fragment can't be matched against the whole file.
+                        let read_only = args.read_only
+                            || (config.default_stage_read_only && !args.read_write)
+                            || forced_by.is_some()
+                            || line_range(&file_path).is_some();
+                        db::add_file_to_stage(&conn, "default", &file_path, read_only)?;
+                        let file_type = if read_only { "read-only" } else { "read-write" };
                         println!("Staged {} as {}.", file_path, file_type);
                     }
                 } else {
                     // --- Display all contexts ---
                     // 1. Get inherited context
-                    let mut inherited_stage: MessageMetadata = Default::default();
-                    if let Some(tag) = db::get_active_chat_tag(&conn)? {
-                        if let Some(assistant_message_id) = db::get_message_id_by_tag(&conn, &tag)?
-                        {
-                            if let Some(user_message_id) =
-                                db::get_parent_id(&conn, assistant_message_id)?
-                            {
-                                if let Some(metadata_json) =
-                                    db::get_message_metadata(&conn, user_message_id)?
-                                {
-                                    if !metadata_json.is_empty() {
-                                        inherited_stage = serde_json::from_str(&metadata_json)?;
-                                    }
-                                }
-                            }
-                        }
-                    }
+                    let inherited_stage = active_inherited_context(&conn)?;
                     // 2. Get prepared context
                     let prepared_stage = db::get_context_stage(&conn, "default")?;
 
@@ -166,7 +1046,7 @@ pub async fn run() -> anyhow::Result<()> {
                     let final_context_map =
                         calculate_final_context(&inherited_stage, &prepared_stage);
                     println!("Final Context (for next message):");
-                    if final_context_map.is_empty() {
+                    if final_context_map.is_empty() && prepared_stage.inline_files.is_empty() {
                         println!("  (empty)");
                     } else {
                         let mut final_rw: Vec<String> = Vec::new();
@@ -193,6 +1073,12 @@ pub async fn run() -> anyhow::Result<()> {
                                 println!("    - {}", file);
                             }
                         }
+                        if !prepared_stage.inline_files.is_empty() {
+                            println!("  Stdin (read-only):");
+                            for file in &prepared_stage.inline_files {
+                                println!("    - {}", file.name);
+                            }
+                        }
                     }
 
                     // 4. Display Inherited Context
@@ -221,6 +1107,7 @@ pub async fn run() -> anyhow::Result<()> {
                     if prepared_stage.read_write_files.is_empty()
                         && prepared_stage.read_only_files.is_empty()
                         && prepared_stage.dropped_files.is_empty()
+                        && prepared_stage.inline_files.is_empty()
                     {
                         println!("  (empty)");
                     } else {
@@ -242,6 +1129,12 @@ pub async fn run() -> anyhow::Result<()> {
                                 println!("    - {}", file);
                             }
                         }
+                        if !prepared_stage.inline_files.is_empty() {
+                            println!("  Stdin (read-only):");
+                            for file in &prepared_stage.inline_files {
+                                println!("    - {}", file.name);
+                            }
+                        }
                     }
                 }
             }
@@ -250,12 +1143,10 @@ pub async fn run() -> anyhow::Result<()> {
                 println!("{:<5} {:<20} Last User Message", "ID", "Tag");
                 println!("{:-<5} {:-<20} {:-<70}", "", "", "");
                 for leaf in leaves {
-                    let history = db::get_conversation_history(&conn, leaf.id)?;
-                    let last_user_message = history.iter().filter(|m| m.role == "user").next_back();
-
-                    let preview_content = last_user_message
-                        .map(|m| m.content.clone())
-                        .unwrap_or(leaf.content);
+                    let mut preview_content = leaf.last_user_content.unwrap_or(leaf.content);
+                    if let Some(title) = leaf.title {
+                        preview_content = format!("[{}] {}", title, preview_content);
+                    }
 
                     let truncated_content: String = preview_content.chars().take(70).collect();
                     let one_line_content = truncated_content.replace('\n', " ");
@@ -304,31 +1195,280 @@ pub async fn run() -> anyhow::Result<()> {
                     );
                 }
             }
-            Command::History {
-                target,
-                tag,
-                message,
-            } => {
-                let leaf_id = match (target, tag, message) {
-                    // `retort history`
-                    (None, false, false) => {
-                        let active_tag = db::get_active_chat_tag(&conn)?.ok_or_else(|| {
-                            anyhow::anyhow!(
-                                "No active chat tag set. Use `retort profile --active-chat <tag>`."
-                            )
-                        })?;
-                        db::get_message_id_by_tag(&conn, &active_tag)?.ok_or_else(|| {
-                            anyhow::anyhow!(
-                                "Active chat tag '{}' does not point to a valid message.",
-                                active_tag
-                            )
-                        })?
+            Command::ReplayHistory { tag, to, base } => {
+                let leaf_id = db::get_message_id_by_tag(&conn, &tag)?
+                    .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", tag))?;
+                // Nothing is written to disk; each turn's edits apply to the previous version.
+                let mut content = match &base {
+                    Some(path) => Some(fs::read_to_string(path)?.replace("\r\n", "\n")),
+                    None => None,
+                };
+                let mut versions = 0;
+                for message_id in db::get_ancestor_ids(&conn, leaf_id)? {
+                    let Some(message) = db::get_message(&conn, message_id)? else {
+                        continue;
+                    };
+                    if message.role != "assistant" {
+                        continue;
                     }
-                    // `retort history <value>` or `retort history -t <value>`
-                    (Some(value), _, false) => db::get_message_id_by_tag(&conn, &value)?
-                        .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", value))?,
-                    // `retort history -m <value>`
-                    (Some(value), false, true) => {
+                    let parsed = hooks::postprocessor::PostprocessorHook::parse_response(
+                        &message.content,
+                        config.edit_format,
+                    )?;
+                    let mut applied = 0;
+                    for change in parsed.changes.iter().filter(|c| same_path(&c.path, &to)) {
+                        match hooks::postprocessor::apply_to_content(change, content.as_deref()) {
+                            Ok(new_content) => {
+                                content = Some(new_content);
+                                applied += 1;
+                            }
+                            Err(e) => eprintln!(
+                                "Warning: skipping an edit from message {}: {}",
+                                message_id, e
+                            ),
+                        }
+                    }
+                    if applied > 0 {
+                        versions += 1;
+                        println!(
+                            "{}",
+                            format!(
+                                "=== {} after message {} ({} edit(s)) ===",
+                                to, message_id, applied
+                            )
+                            .bold()
+                        );
+                        print!("{}", content.as_deref().unwrap_or_default());
+                        if !content.as_deref().unwrap_or_default().ends_with('\n') {
+                            println!();
+                        }
+                    }
+                }
+                if versions == 0 {
+                    println!("No edits to {} in the history of '{}'.", to, tag);
+                }
+            }
+            Command::Add {
+                content,
+                role,
+                parent,
+            } => {
+                if !db::VALID_ROLES.contains(&role.as_str()) {
+                    anyhow::bail!(
+                        "Invalid role '{}'; use one of {}.",
+                        role,
+                        db::VALID_ROLES.join(", ")
+                    );
+                }
+                if let Some(parent_id) = parent {
+                    if !db::message_exists(&conn, parent_id)? {
+                        anyhow::bail!("Message with ID '{}' not found.", parent_id);
+                    }
+                }
+                let message_id = db::add_message(&conn, parent, &role, &content, None)?;
+                println!("Added {} message with ID: {}", role, message_id);
+            }
+            Command::Show { message, metadata } => {
+                let message = db::get_message(&conn, message)?
+                    .ok_or_else(|| anyhow::anyhow!("Message with ID '{}' not found.", message))?;
+                println!("{} {}", role_header(&message.role), message.created_at);
+                println!("{}", message.content);
+                if metadata {
+                    println!("---");
+                    match db::get_message_metadata(&conn, message.id)? {
+                        Some(json) if !json.is_empty() => {
+                            let value: serde_json::Value = serde_json::from_str(&json)?;
+                            println!("{}", serde_json::to_string_pretty(&value)?);
+                        }
+                        _ => println!("(no metadata)"),
+                    }
+                }
+            }
+            Command::Siblings { message } => {
+                let message = db::get_message(&conn, message)?
+                    .ok_or_else(|| anyhow::anyhow!("Message with ID '{}' not found.", message))?;
+                let siblings = db::get_children(&conn, message.parent_id)?;
+                let tags = db::get_all_tags(&conn)?;
+
+                println!("  {:<5} {:<20} Preview", "ID", "Tags");
+                println!("  {:-<5} {:-<20} {:-<60}", "", "", "");
+                for sibling in siblings {
+                    // Tags on the sibling or anywhere in its branch follow that alternative.
+                    let mut branch_tags = Vec::new();
+                    for tag in &tags {
+                        if db::get_ancestor_ids(&conn, tag.message_id)?.contains(&sibling.id) {
+                            branch_tags.push(tag.name.as_str());
+                        }
+                    }
+                    let tag_display = if branch_tags.is_empty() {
+                        "-".to_string()
+                    } else {
+                        branch_tags.join(",")
+                    };
+                    let marker = if sibling.id == message.id { "*" } else { " " };
+                    let truncated_content: String = sibling.content.chars().take(60).collect();
+                    println!(
+                        "{} {:<5} {:<20} {}",
+                        marker,
+                        sibling.id,
+                        tag_display,
+                        truncated_content.replace('\n', " ")
+                    );
+                }
+            }
+            Command::LsFiles { tag } => {
+                let leaf_id = db::get_message_id_by_tag(&conn, &tag)?
+                    .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", tag))?;
+                // Only user messages carry file context.
+                let mut turn_counts: BTreeMap<String, usize> = BTreeMap::new();
+                for message_id in db::get_ancestor_ids(&conn, leaf_id)? {
+                    let is_user_message = db::get_message(&conn, message_id)?
+                        .is_some_and(|message| message.role == "user");
+                    if !is_user_message {
+                        continue;
+                    }
+                    let Some(metadata) = load_message_metadata(&conn, message_id)? else {
+                        continue;
+                    };
+                    let paths: BTreeSet<String> = metadata
+                        .read_write_files
+                        .into_iter()
+                        .chain(metadata.read_only_files)
+                        .map(|file| file.path)
+                        .collect();
+                    for path in paths {
+                        *turn_counts.entry(path).or_default() += 1;
+                    }
+                }
+
+                if turn_counts.is_empty() {
+                    println!("No files in the history of '{}'.", tag);
+                } else {
+                    println!("{:<5} Path", "Turns");
+                    println!("{:-<5} {:-<40}", "", "");
+                    for (path, count) in turn_counts {
+                        println!("{:<5} {}", count, path);
+                    }
+                }
+            }
+            Command::Verify { target } => {
+                let message_id = resolve_target(&conn, &target)?;
+                let metadata = inherited_context(&conn, message_id)?;
+                let files: Vec<FileMetadata> = metadata
+                    .read_write_files
+                    .into_iter()
+                    .chain(metadata.read_only_files)
+                    .collect();
+                if files.is_empty() {
+                    println!("Message {} was sent without any files.", message_id);
+                    return Ok(());
+                }
+                let (mut matching, mut differing, mut missing) = (0, 0, 0);
+                for file in &files {
+                    // Line ranges are re-read as the same lines, so moved code shows up as differing.
+                    let status = match read_context_file(&file.path) {
+                        Ok(content) if hash_content(&content) == file.hash => {
+                            matching += 1;
+                            "match"
+                        }
+                        Ok(_) => {
+                            differing += 1;
+                            "differs"
+                        }
+                        Err(_) => {
+                            missing += 1;
+                            "missing"
+                        }
+                    };
+                    println!("{:<8} {}", status, file.path);
+                }
+                println!(
+                    "{} match, {} differ, {} missing.",
+                    matching, differing, missing
+                );
+            }
+            Command::Config(ConfigSubcommand::Init { .. }) => {
+                unreachable!("config init runs before the config is loaded")
+            }
+            Command::Status => {
+                let profile = db::get_profile_by_name(&conn, "default")?;
+                println!("Profile:      {}", profile.name);
+                match &profile.active_chat_tag {
+                    Some(tag) => match db::get_message_id_by_tag(&conn, tag)? {
+                        Some(message_id) => {
+                            let leaf = if db::is_leaf(&conn, message_id)? {
+                                "leaf"
+                            } else {
+                                "not a leaf"
+                            };
+                            println!("Active chat:  {} (message {}, {})", tag, message_id, leaf);
+                            let root_id = db::get_root_id(&conn, message_id)?;
+                            if let Some(title) =
+                                db::get_conversation(&conn, root_id)?.and_then(|c| c.title)
+                            {
+                                println!("Title:        {}", title);
+                            }
+                        }
+                        None => println!("Active chat:  {} (tag not found)", tag),
+                    },
+                    None => println!("Active chat:  (none)"),
+                }
+                println!(
+                    "Project root: {}",
+                    profile.project_root.as_deref().unwrap_or("(none)")
+                );
+
+                let inherited_stage = active_inherited_context(&conn)?;
+                let prepared_stage = db::get_context_stage(&conn, "default")?;
+                let final_context_map = calculate_final_context(&inherited_stage, &prepared_stage);
+                let read_only_count = final_context_map.values().filter(|ro| **ro).count();
+                println!(
+                    "Context:      {} read-write, {} read-only file(s) for the next message",
+                    final_context_map.len() - read_only_count,
+                    read_only_count
+                );
+                println!(
+                    "Stage:        {} read-write, {} read-only, {} dropped, {} from stdin",
+                    prepared_stage.read_write_files.len(),
+                    prepared_stage.read_only_files.len(),
+                    prepared_stage.dropped_files.len(),
+                    prepared_stage.inline_files.len()
+                );
+
+                println!("Backend:      {}", config.backend);
+                println!(
+                    "Model:        {}",
+                    config.model.as_deref().unwrap_or("(backend default)")
+                );
+                println!("Database:     {}", expanded_path);
+            }
+            Command::History {
+                target,
+                tag,
+                message,
+                json,
+                compact,
+            } => {
+                let leaf_id = match (target, tag, message) {
+                    // `retort history`
+                    (None, false, false) => {
+                        let active_tag = db::get_active_chat_tag(&conn)?.ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "No active chat tag set. Use `retort profile --active-chat <tag>`."
+                            )
+                        })?;
+                        db::get_message_id_by_tag(&conn, &active_tag)?.ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Active chat tag '{}' does not point to a valid message.",
+                                active_tag
+                            )
+                        })?
+                    }
+                    // `retort history <value>` or `retort history -t <value>`
+                    (Some(value), _, false) => db::get_message_id_by_tag(&conn, &value)?
+                        .ok_or_else(|| anyhow::anyhow!("Tag '{}' not found.", value))?,
+                    // `retort history -m <value>`
+                    (Some(value), false, true) => {
                         let id = value.parse::<i64>()?;
                         if !db::message_exists(&conn, id)? {
                             anyhow::bail!("Message with ID '{}' not found.", id);
@@ -338,27 +1478,349 @@ pub async fn run() -> anyhow::Result<()> {
                     _ => anyhow::bail!("Invalid combination of arguments for history command."),
                 };
 
-                let history = db::get_conversation_history(&conn, leaf_id)?;
+                let mut history = db::get_conversation_history(&conn, leaf_id)?;
+                if compact {
+                    history.retain(|message| {
+                        !prompt::is_file_context_turn(message, &config.prompt_overrides)
+                    });
+                    for message in &mut history {
+                        message.content = prompt::collapse_code_blocks(&message.content, 10);
+                    }
+                }
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&history)?);
+                    return Ok(());
+                }
                 for (i, message) in history.iter().enumerate() {
-                    println!("[{}]", message.role);
+                    println!("{}", role_header(&message.role));
                     println!("{}", message.content);
                     if i < history.len() - 1 {
                         println!("---");
                     }
                 }
             }
+            Command::Models { backend } => {
+                let backend = backend.unwrap_or_else(|| config.backend.clone());
+                let models = llm::list_models(&config, &backend).await?;
+                if models.is_empty() {
+                    println!("No known models for backend '{}'.", backend);
+                } else {
+                    for model in models {
+                        println!("{}", model);
+                    }
+                }
+            }
+            Command::Debug(debug_command) => match debug_command {
+                DebugSubcommand::Schema => {
+                    let info = db::get_schema_info(&conn)?;
+                    println!("Database: {}", expanded_path);
+                    println!("user_version: {}", info.user_version);
+                    println!("\nSchema:");
+                    for statement in &info.statements {
+                        println!("{};", statement);
+                    }
+                    println!("\nRow counts:");
+                    for (table, count) in &info.table_row_counts {
+                        println!("  {:<20} {}", table, count);
+                    }
+                }
+            },
+            Command::Doctor { edit_format_probe } => {
+                let mut failures = 0;
+                for (name, result) in prompt::validate_templates() {
+                    match result {
+                        Ok(()) => println!("ok      prompts/{}", name),
+                        Err(e) => {
+                            failures += 1;
+                            println!("FAILED  prompts/{}\n  {}", name, e);
+                        }
+                    }
+                }
+                if failures > 0 {
+                    anyhow::bail!("{} template(s) failed to render.", failures);
+                }
+                if edit_format_probe {
+                    let format = config.edit_format;
+                    let block_names: Vec<&str> = format
+                        .kinds()
+                        .iter()
+                        .map(|kind| kind.block_name())
+                        .collect();
+                    println!("The edit format has {} blocks.", block_names.join(" and "));
+
+                    let parsed = hooks::postprocessor::PostprocessorHook::parse_response(
+                        &format.example_response(),
+                        format,
+                    )?;
+                    let parsed_kinds: Vec<_> =
+                        parsed.changes.iter().map(|change| change.kind).collect();
+                    if parsed_kinds == format.kinds() {
+                        println!("ok      the parser reads an example of each block");
+                    } else {
+                        failures += 1;
+                        println!(
+                            "FAILED  the parser read {} of {} example blocks",
+                            parsed_kinds.len(),
+                            format.kinds().len()
+                        );
+                    }
+
+                    let mut template_names: Vec<&str> =
+                        config.prompt_templates.keys().map(String::as_str).collect();
+                    template_names.sort();
+                    template_names.insert(0, "default");
+                    for name in template_names {
+                        let mut overrides = config.prompt_overrides.clone();
+                        overrides.edit_format = format;
+                        let problems = load_prompt_template(&config, name).and_then(|source| {
+                            overrides.system_prompt_template = source;
+                            prompt::check_edit_format(&overrides)
+                        });
+                        match problems {
+                            Ok(problems) if problems.is_empty() => {
+                                println!("ok      the {} prompt describes them", name)
+                            }
+                            Ok(problems) => {
+                                failures += 1;
+                                println!("FAILED  the {} prompt", name);
+                                for problem in problems {
+                                    println!("  {}", problem);
+                                }
+                            }
+                            Err(e) => {
+                                failures += 1;
+                                println!("FAILED  the {} prompt\n  {}", name, e);
+                            }
+                        }
+                    }
+                    if failures > 0 {
+                        anyhow::bail!("{} edit format check(s) failed.", failures);
+                    }
+                }
+            }
+            Command::ReplayEdits { message, force } => {
+                let assistant_message = db::get_message(&conn, message)?
+                    .ok_or_else(|| anyhow::anyhow!("Message with ID '{}' not found.", message))?;
+                if assistant_message.role != "assistant" {
+                    anyhow::bail!(
+                        "Message {} is a {} message; only assistant messages have edits to replay.",
+                        message,
+                        assistant_message.role
+                    );
+                }
+                let parsed = hooks::postprocessor::PostprocessorHook::parse_response(
+                    &assistant_message.content,
+                    config.edit_format,
+                )?;
+                if parsed.changes.is_empty() {
+                    anyhow::bail!("Message {} has no edits to replay.", message);
+                }
+
+                // Keep the read-only files of that turn protected, as they were when it was sent.
+                let read_only_files = match assistant_message.parent_id {
+                    Some(user_message_id) => load_message_metadata(&conn, user_message_id)?
+                        .map(|metadata| {
+                            let context: HashMap<String, bool> = metadata
+                                .read_write_files
+                                .into_iter()
+                                .map(|file| (file.path, false))
+                                .chain(
+                                    metadata
+                                        .read_only_files
+                                        .into_iter()
+                                        .map(|file| (file.path, true)),
+                                )
+                                .collect();
+                            protected_paths(&context)
+                        })
+                        .unwrap_or_default(),
+                    None => Vec::new(),
+                };
+                let profile = db::get_profile_by_name(&conn, "default")?;
+                let hook_context = hooks::HookContext {
+                    project_root: profile.project_root.map(PathBuf::from),
+                    read_only_files,
+                };
+                let hook_manager =
+                    edit_hook_manager(&config, false, None, None, false, force, false)?;
+                let results =
+                    hook_manager.run_post_send_hooks(&assistant_message.content, &hook_context)?;
+                println!(
+                    "Replayed {} edit(s) from message {}.",
+                    results.iter().filter(|result| result.applied).count(),
+                    message
+                );
+            }
+            Command::ExportCommit { message, output } => {
+                let assistant_message = db::get_message(&conn, message)?
+                    .ok_or_else(|| anyhow::anyhow!("Message with ID '{}' not found.", message))?;
+                if assistant_message.role != "assistant" {
+                    anyhow::bail!(
+                        "Message {} is a {} message; only assistant messages have edits to export.",
+                        message,
+                        assistant_message.role
+                    );
+                }
+                let parsed = hooks::postprocessor::PostprocessorHook::parse_response(
+                    &assistant_message.content,
+                    config.edit_format,
+                )?;
+                if parsed.changes.is_empty() {
+                    anyhow::bail!("Message {} has no edits to export.", message);
+                }
+
+                // Each file's blocks apply in order to an in-memory copy of what's on disk now.
+                // Entries are (path, original content, edited content), in the order first edited.
+                let mut files: Vec<(String, Option<String>, Option<String>)> = Vec::new();
+                let mut failures = Vec::new();
+                for change in &parsed.changes {
+                    let index = match files.iter().position(|(path, _, _)| *path == change.path) {
+                        Some(index) => index,
+                        None => {
+                            let original = if Path::new(&change.path).exists() {
+                                Some(fs::read_to_string(&change.path)?)
+                            } else {
+                                None
+                            };
+                            files.push((change.path.clone(), original.clone(), original));
+                            files.len() - 1
+                        }
+                    };
+                    match hooks::postprocessor::apply_to_file(
+                        change,
+                        files[index].2.as_deref(),
+                        config.new_file_trailing_newline,
+                    ) {
+                        Ok(new_content) => files[index].2 = Some(new_content),
+                        Err(e) => failures.push(e.to_string()),
+                    }
+                }
+                if let [failure] = failures.as_slice() {
+                    anyhow::bail!("{}", failure);
+                }
+                if !failures.is_empty() {
+                    anyhow::bail!(
+                        "{} of {} edits could not be applied:\n{}",
+                        failures.len(),
+                        parsed.changes.len(),
+                        failures.join("\n")
+                    );
+                }
+
+                let diffs: Vec<String> = files
+                    .iter()
+                    .map(|(path, original, edited)| {
+                        patch::file_diff(
+                            path,
+                            original.as_deref(),
+                            edited.as_deref().unwrap_or_default(),
+                        )
+                    })
+                    .filter(|diff| !diff.is_empty())
+                    .collect();
+                if diffs.is_empty() {
+                    anyhow::bail!("The edits from message {} don't change any files.", message);
+                }
+                // `git apply` skips anything before the first diff, so the message can lead.
+                let mut patch_text = String::new();
+                if !parsed.commit_message.is_empty() {
+                    patch_text.push_str(&parsed.commit_message);
+                    patch_text.push_str("\n\n");
+                }
+                patch_text.push_str(&diffs.concat());
+                match &output {
+                    Some(path) => {
+                        fs::write(path, &patch_text)?;
+                        println!(
+                            "Wrote a patch changing {} file(s) to {}.",
+                            diffs.len(),
+                            path.display()
+                        );
+                    }
+                    None => print!("{}", patch_text),
+                }
+            }
+            Command::Import { path } => {
+                let contents = fs::read_to_string(&path)?;
+                let dump: db::ChatDump = serde_json::from_str(&contents).map_err(|e| {
+                    anyhow::anyhow!("Failed to parse import file {}: {}", path.display(), e)
+                })?;
+                let summary = db::import_dump(&conn, &dump)?;
+                println!(
+                    "Imported {} messages and {} tags.",
+                    summary.messages, summary.tags
+                );
+            }
             Command::Send {
                 prompt,
                 parent,
+                tag,
+                parent_of,
                 chat,
+                latest,
                 new,
                 stream,
                 no_stream,
+                stop_sequences,
                 ignore_inherited_stage,
                 confirm,
                 editor,
+                task,
+                json_events,
+                context_from,
+                stdin_files,
+                diff,
+                staged,
+                estimate,
+                preview_json,
+                pager,
+                timings,
+                commit_message,
+                assistant_prefill,
+                template,
+                system_file,
+                history_after,
+                max_history_bytes,
+                require_clean_tree,
+                continue_on_edit_error,
+                force,
+                commit_type,
+                commit_scope,
+                reasoning,
+                fit_context,
+                no_readme,
+                review,
             } => {
-                let prompt = if editor {
+                let task = task.as_deref().map(load_task_file).transpose()?;
+                let config = config::Config {
+                    reasoning_effort: reasoning.or(config.reasoning_effort),
+                    model: task
+                        .as_ref()
+                        .and_then(|task| task.model.clone())
+                        .or(config.model),
+                    temperature: task
+                        .as_ref()
+                        .and_then(|task| task.temperature)
+                        .or(config.temperature),
+                    ..config
+                };
+                let response_filters = llm::compile_response_filters(&config.response_filters)?;
+                let commit_type = commit_type.map(|commit_type| match commit_scope {
+                    Some(scope) => format!("{}({})", commit_type, scope),
+                    None => commit_type,
+                });
+                let hook_manager = edit_hook_manager(
+                    &config,
+                    json_events,
+                    commit_message,
+                    commit_type,
+                    require_clean_tree,
+                    force,
+                    continue_on_edit_error,
+                )?;
+                let prompt = if let Some(task) = &task {
+                    task.prompt.clone()
+                } else if editor {
                     if let Ok(mock_content) = std::env::var("MOCK_EDITOR_CONTENT") {
                         mock_content
                     } else {
@@ -367,7 +1829,7 @@ pub async fn run() -> anyhow::Result<()> {
                 } else {
                     prompt.ok_or_else(|| {
                         anyhow::anyhow!(
-                            "Prompt must be provided either via arguments or --editor flag."
+                            "Prompt must be provided via arguments, the --editor flag or a --task file."
                         )
                     })?
                 };
@@ -380,18 +1842,66 @@ pub async fn run() -> anyhow::Result<()> {
                 let profile = db::get_profile_by_name(&conn, "default")?;
                 let project_root = profile.project_root.map(PathBuf::from);
 
+                // Both machine-readable modes keep stdout for their JSON.
+                let machine_output = json_events || preview_json;
                 let mut parent_id: Option<i64> = None;
                 let mut chat_tag_for_update: Option<String> = None;
+                // Redoing a first turn also starts a new root, but shouldn't be tagged.
+                let redoing_turn = parent_of.is_some();
 
                 if new {
-                    // --new: new root message, no tag update
+                    // --new: new root message, tagged only with --chat
+                    if let Some(tag) = &chat {
+                        if let Some(old_id) = db::get_message_id_by_tag(&conn, tag)? {
+                            if !machine_output {
+                                println!(
+                                    "Tag '{}' points to message {}; it will be moved to the new chat.",
+                                    tag, old_id
+                                );
+                            }
+                        }
+                    }
+                    chat_tag_for_update = chat;
                 } else if let Some(id) = parent {
-                    // --parent: new branch from id, no tag update
+                    // --parent: new branch from id, tagged only with --tag
+                    if let Some(tag) = &tag {
+                        if let Some(old_id) = db::get_message_id_by_tag(&conn, tag)? {
+                            if !machine_output {
+                                println!(
+                                    "Tag '{}' points to message {}; it will be moved to the new branch.",
+                                    tag, old_id
+                                );
+                            }
+                        }
+                    }
                     parent_id = Some(id);
+                    chat_tag_for_update = tag;
+                } else if let Some(tag) = parent_of {
+                    // --parent-of: new branch from the tag's previous turn, no tag update
+                    parent_id = previous_turn(&conn, &tag)?;
                 } else if let Some(tag) = chat {
                     // --chat: continue from tag, update tag
                     parent_id = db::get_message_id_by_tag(&conn, &tag)?;
                     chat_tag_for_update = Some(tag);
+                } else if latest {
+                    // --latest: continue from the newest leaf of any chat, update its tag if any
+                    let leaf = db::get_leaf_messages(&conn)?
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("There are no messages to continue from.")
+                        })?;
+                    if !machine_output {
+                        match &leaf.tag {
+                            Some(tag) => println!(
+                                "Continuing from the latest message, {} (tag '{}').",
+                                leaf.id, tag
+                            ),
+                            None => println!("Continuing from the latest message, {}.", leaf.id),
+                        }
+                    }
+                    parent_id = Some(leaf.id);
+                    chat_tag_for_update = leaf.tag;
                 } else {
                     // default: continue from active tag, or start a new chat if no active tag
                     if let Some(tag) = db::get_active_chat_tag(&conn)? {
@@ -400,32 +1910,131 @@ pub async fn run() -> anyhow::Result<()> {
                     }
                 }
 
+                // A tag left pointing mid-tree (e.g. after branching with --parent) silently forks.
+                // With --parent --tag the branch is what was asked for.
+                if let (Some(tag), Some(p_id), None) = (&chat_tag_for_update, parent_id, parent) {
+                    if !db::is_leaf(&conn, p_id)? {
+                        eprintln!(
+                            "Warning: tag '{}' points to message {}, which is not a leaf; continuing from a non-leaf creates a branch.",
+                            tag, p_id
+                        );
+                    }
+                }
+
+                // --template overrides the chat's own template for this send.
+                let chat_template = match parent_id {
+                    Some(p_id) => {
+                        let root_id = db::get_root_id(&conn, p_id)?;
+                        db::get_conversation(&conn, root_id)?.and_then(|c| c.prompt_template)
+                    }
+                    None => None,
+                };
+                let mut prompt_overrides = config.prompt_overrides.clone();
+                prompt_overrides.context_position = config.context_position;
+                prompt_overrides.edit_format = config.edit_format;
+                if let Some(name) = template.as_ref().or(chat_template.as_ref()) {
+                    prompt_overrides.system_prompt_template = load_prompt_template(&config, name)?;
+                }
+                if let Some(path) = &system_file {
+                    prompt_overrides.system_prompt =
+                        Some(fs::read_to_string(path).map_err(|e| {
+                            anyhow::anyhow!(
+                                "Failed to read --system-file {}: {}",
+                                path.display(),
+                                e
+                            )
+                        })?);
+                }
+
                 // --- Prompt Assembly ---
-                // 1. Get inherited context
-                let mut inherited_stage: MessageMetadata = Default::default();
-                if let Some(p_id) = parent_id {
-                    if !ignore_inherited_stage {
-                        // The parent_id (p_id) is the previous assistant's message.
-                        // Its parent is the user message from the same turn, which holds the context metadata.
-                        if let Some(user_message_id) = db::get_parent_id(&conn, p_id)? {
-                            if let Some(metadata_json) =
-                                db::get_message_metadata(&conn, user_message_id)?
-                            {
-                                if !metadata_json.is_empty() {
-                                    inherited_stage = serde_json::from_str(&metadata_json)?;
-                                }
-                            }
+                let (mut final_context_map, inline_files) = if let Some(source_id) = context_from {
+                    // --context-from: reuse another turn's file set, bypassing the stage.
+                    (context_from_message(&conn, source_id)?, Vec::new())
+                } else {
+                    // 1. Get inherited context
+                    let mut inherited_stage: MessageMetadata = Default::default();
+                    if let Some(p_id) = parent_id {
+                        if !ignore_inherited_stage {
+                            inherited_stage = inherited_context(&conn, p_id)?;
                         }
                     }
+
+                    // 2. Get prepared context
+                    let prepared_stage = db::get_context_stage(&conn, "default")?;
+
+                    // 3. Merge contexts.
+                    let mut final_context_map =
+                        calculate_final_context(&inherited_stage, &prepared_stage);
+                    if config.include_readme_in_context && !no_readme {
+                        add_readme(
+                            &mut final_context_map,
+                            project_root.as_deref(),
+                            &prepared_stage.dropped_files,
+                        );
+                    }
+                    (final_context_map, prepared_stage.inline_files)
+                };
+                if stdin_files {
+                    let mut input = String::new();
+                    std::io::stdin().read_to_string(&mut input)?;
+                    for path in input.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                        if !Path::new(path).is_file() {
+                            anyhow::bail!("{} from --stdin-files is not a file.", path);
+                        }
+                        final_context_map.insert(path.to_string(), false);
+                    }
+                }
+                // Like --stdin-files, a task's files are sent with this message without being staged.
+                for file in task.iter().flat_map(|task| &task.files) {
+                    let on_disk = line_range(&file.path).map_or(file.path.as_str(), |(f, _, _)| f);
+                    if !Path::new(on_disk).is_file() {
+                        anyhow::bail!("{} from the task file is not a file.", file.path);
+                    }
+                    final_context_map.insert(file.path.clone(), file.read_only);
                 }
 
-                // 2. Get prepared context
-                let prepared_stage = db::get_context_stage(&conn, "default")?;
+                // 4. Get conversation history to build prompt
+                let mut history = if let Some(p_id) = parent_id {
+                    db::get_conversation_history(&conn, p_id)?
+                } else {
+                    Vec::new()
+                };
+                if let Some(after_id) = history_after {
+                    let ancestor_ids = match parent_id {
+                        Some(p_id) => db::get_ancestor_ids(&conn, p_id)?,
+                        None => Vec::new(),
+                    };
+                    let pos = ancestor_ids
+                        .iter()
+                        .position(|id| *id == after_id)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Message {} is not in the history of this chat.",
+                                after_id
+                            )
+                        })?;
+                    history.drain(..=pos);
+                }
+                if let Some(max_bytes) = max_history_bytes.or(config.max_history_bytes) {
+                    let total: usize = history.iter().map(|m| m.content.len()).sum();
+                    let truncated = prompt::cap_history_bytes(&mut history, max_bytes);
+                    if truncated > 0 {
+                        eprintln!(
+                            "Warning: the history is {} bytes, over max_history_bytes of {}; truncated the {} largest message(s).",
+                            total, max_bytes, truncated
+                        );
+                    }
+                }
 
-                // 3. Merge contexts.
-                let final_context_map = calculate_final_context(&inherited_stage, &prepared_stage);
+                let cur_user_message = db::HistoryMessage {
+                    role: "user".to_string(),
+                    content: prompt.clone(),
+                    created_at: String::new(), // Not used for prompt building
+                };
+
+                let (cur_messages, done_messages) = (vec![cur_user_message], history);
 
-                // 4. Load file contents and prepare for prompt, and build metadata
+                // 5. Load file contents and prepare for prompt, and build metadata
                 let mut read_write_files_prompt = Vec::new();
                 let mut read_only_files_prompt = Vec::new();
                 let mut metadata = MessageMetadata::default();
@@ -433,12 +2042,29 @@ pub async fn run() -> anyhow::Result<()> {
                 let mut paths: Vec<String> = final_context_map.keys().cloned().collect();
                 paths.sort(); // Sort for consistent order in prompt
 
+                // Files can grow after staging, so the size limit is checked here rather than when staging.
+                let mut skipped_paths: HashSet<String> = HashSet::new();
                 for path in paths {
-                    let is_readonly = *final_context_map.get(&path).unwrap();
-                    let content = fs::read_to_string(&path)?;
-                    let mut hasher = Sha256::new();
-                    hasher.update(content.as_bytes());
-                    let hash = format!("{:x}", hasher.finalize());
+                    let ranged = line_range(&path).is_some();
+                    let is_readonly = *final_context_map.get(&path).unwrap() || ranged;
+                    if let Some(max_bytes) = config.max_staged_file_bytes {
+                        // A line range is measured by the lines sent, not the whole file.
+                        let size = if ranged {
+                            read_context_file(&path)?.len() as u64
+                        } else {
+                            fs::metadata(&path)?.len()
+                        };
+                        if size > max_bytes {
+                            eprintln!(
+                                "Warning: skipping {} ({} bytes exceeds max_staged_file_bytes of {}).",
+                                path, size, max_bytes
+                            );
+                            skipped_paths.insert(path);
+                            continue;
+                        }
+                    }
+                    let content = read_context_file(&path)?;
+                    let hash = hash_content(&content);
 
                     let file_metadata = FileMetadata {
                         path: path.clone(),
@@ -454,63 +2080,177 @@ pub async fn run() -> anyhow::Result<()> {
                     }
                 }
 
-                // 5. Print context view for user
-                println!("---");
-                println!("CONTEXT (for this message):");
-
-                let mut sorted_paths: Vec<String> = final_context_map.keys().cloned().collect();
-                sorted_paths.sort();
-
-                let mut final_rw: Vec<String> = Vec::new();
-                let mut final_ro: Vec<String> = Vec::new();
-
-                for path in &sorted_paths {
-                    if *final_context_map.get(path).unwrap() {
-                        final_ro.push(path.clone());
-                    } else {
-                        final_rw.push(path.clone());
+                // The same file under two path spellings (or copied verbatim) is only sent once,
+                // keeping a read-write copy if there is one so it can still be edited.
+                let mut first_with_hash: HashMap<&str, &str> = HashMap::new();
+                let mut duplicates: Vec<(String, String)> = Vec::new();
+                let files = read_write_files_prompt
+                    .iter()
+                    .zip(&metadata.read_write_files)
+                    .chain(read_only_files_prompt.iter().zip(&metadata.read_only_files));
+                for ((path, content), file_metadata) in files {
+                    if content.is_empty() {
+                        continue;
+                    }
+                    match first_with_hash.get(file_metadata.hash.as_str()) {
+                        Some(kept) => duplicates.push((path.clone(), kept.to_string())),
+                        None => {
+                            first_with_hash.insert(&file_metadata.hash, path);
+                        }
                     }
                 }
+                for (path, kept) in duplicates {
+                    eprintln!(
+                        "Warning: {} has the same content as {}; only {} is sent.",
+                        path, kept, kept
+                    );
+                    read_write_files_prompt.retain(|(p, _)| *p != path);
+                    read_only_files_prompt.retain(|(p, _)| *p != path);
+                    metadata.read_write_files.retain(|file| file.path != path);
+                    metadata.read_only_files.retain(|file| file.path != path);
+                    skipped_paths.insert(path);
+                }
 
-                if !final_rw.is_empty() {
-                    println!("  Read-Write:");
-                    for path in &final_rw {
-                        println!("    - {}", path);
-                    }
+                // Piped content and diffs go in as read-only blocks, but they aren't files, so they stay out of the metadata.
+                for inline_file in &inline_files {
+                    read_only_files_prompt
+                        .push((inline_file.name.clone(), inline_file.content.clone()));
                 }
-                if !final_ro.is_empty() {
-                    println!("  Read-Only:");
-                    for path in &final_ro {
-                        println!("    - {}", path);
+                let mut diff_label: Option<String> = None;
+                if diff {
+                    let label = if staged {
+                        "git diff --staged"
+                    } else {
+                        "git diff"
+                    };
+                    let diff_output = git_diff(project_root.as_deref(), staged)?;
+                    if diff_output.trim().is_empty() {
+                        eprintln!("Warning: `{}` is empty; no diff included.", label);
+                    } else {
+                        read_only_files_prompt
+                            .push((format!("Output of `{}`", label), diff_output));
+                        diff_label = Some(label.to_string());
                     }
                 }
-                if final_rw.is_empty() && final_ro.is_empty() {
-                    println!("  (empty)");
+
+                // --fit-context: drop files, largest first and read-only before read-write,
+                // until the prompt fits the token budget.
+                if fit_context {
+                    let budget = config.context_token_budget.ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "--fit-context needs context_token_budget set in the config."
+                        )
+                    })?;
+                    loop {
+                        let tokens = prompt_tokens(&prompt::build_prompt_messages(
+                            done_messages.clone(),
+                            cur_messages.clone(),
+                            &read_write_files_prompt,
+                            &read_only_files_prompt,
+                            &prompt_overrides,
+                        )?);
+                        if tokens <= budget {
+                            break;
+                        }
+                        let (path, content) = if let Some(index) =
+                            largest_file(&read_only_files_prompt, &final_context_map)
+                        {
+                            read_only_files_prompt.remove(index)
+                        } else if let Some(index) =
+                            largest_file(&read_write_files_prompt, &final_context_map)
+                        {
+                            read_write_files_prompt.remove(index)
+                        } else {
+                            eprintln!(
+                                "Warning: the prompt is still about {} tokens, over context_token_budget of {}, with no files left to drop.",
+                                tokens, budget
+                            );
+                            break;
+                        };
+                        eprintln!(
+                            "Dropped {} (about {} tokens) to fit context_token_budget of {}.",
+                            path,
+                            llm::estimate_tokens(&content),
+                            budget
+                        );
+                        metadata.read_write_files.retain(|file| file.path != path);
+                        metadata.read_only_files.retain(|file| file.path != path);
+                        skipped_paths.insert(path);
+                    }
                 }
-                println!("---");
 
-                let metadata_json = serde_json::to_string(&metadata)?;
+                // 6. Print context view for user
+                let mut sorted_paths: Vec<String> = final_context_map
+                    .keys()
+                    .filter(|path| !skipped_paths.contains(*path))
+                    .cloned()
+                    .collect();
+                sorted_paths.sort();
 
-                // 6. Get conversation history to build prompt
-                let history = if let Some(p_id) = parent_id {
-                    db::get_conversation_history(&conn, p_id)?
-                } else {
-                    Vec::new()
-                };
+                if json_events {
+                    let files = sorted_paths
+                        .iter()
+                        .map(|path| events::ContextFile {
+                            path: path.clone(),
+                            read_only: *final_context_map.get(path).unwrap(),
+                        })
+                        .collect();
+                    events::emit(&Event::Context { files })?;
+                } else if !preview_json {
+                    println!("---");
+                    println!("{}", "CONTEXT (for this message):".bold());
 
-                let cur_user_message = db::HistoryMessage {
-                    role: "user".to_string(),
-                    content: prompt.clone(),
-                    created_at: String::new(), // Not used for prompt building
-                };
+                    let mut final_rw: Vec<String> = Vec::new();
+                    let mut final_ro: Vec<String> = Vec::new();
 
-                let (cur_messages, done_messages) = (vec![cur_user_message], history);
+                    for path in &sorted_paths {
+                        if *final_context_map.get(path).unwrap() {
+                            final_ro.push(path.clone());
+                        } else {
+                            final_rw.push(path.clone());
+                        }
+                    }
+
+                    if !final_rw.is_empty() {
+                        println!("  Read-Write:");
+                        for path in &final_rw {
+                            println!("    - {}", path.dimmed());
+                        }
+                    }
+                    if !final_ro.is_empty() {
+                        println!("  Read-Only:");
+                        for path in &final_ro {
+                            println!("    - {}", path.dimmed());
+                        }
+                    }
+                    if !inline_files.is_empty() {
+                        println!("  Stdin (read-only):");
+                        for inline_file in &inline_files {
+                            println!("    - {}", inline_file.name.dimmed());
+                        }
+                    }
+                    if let Some(label) = &diff_label {
+                        println!("  Diff:");
+                        println!("    - {}", label.dimmed());
+                    }
+                    if final_rw.is_empty()
+                        && final_ro.is_empty()
+                        && inline_files.is_empty()
+                        && diff_label.is_none()
+                    {
+                        println!("  (empty)");
+                    }
+                    println!("---");
+                }
+
+                let metadata_json = serde_json::to_string(&metadata)?;
 
                 let mut llm_messages_for_prompt = prompt::build_prompt_messages(
                     done_messages,
                     cur_messages,
                     &read_write_files_prompt,
                     &read_only_files_prompt,
+                    &prompt_overrides,
                 )?;
 
                 let system_prompt = if !llm_messages_for_prompt.is_empty()
@@ -521,22 +2261,106 @@ pub async fn run() -> anyhow::Result<()> {
                     None
                 };
 
+                let assistant_prefill = match assistant_prefill {
+                    Some(_) if !llm::supports_prefill(&config.backend) => {
+                        eprintln!(
+                            "Warning: backend '{}' doesn't support --assistant-prefill; sending without it.",
+                            config.backend
+                        );
+                        None
+                    }
+                    prefill => prefill,
+                };
+                if let Some(prefill) = &assistant_prefill {
+                    llm_messages_for_prompt.push(prompt::Message {
+                        role: "assistant".to_string(),
+                        content: prefill.clone(),
+                    });
+                }
+
+                if estimate {
+                    let texts: Vec<&str> = system_prompt
+                        .iter()
+                        .map(String::as_str)
+                        .chain(llm_messages_for_prompt.iter().map(|m| m.content.as_str()))
+                        .collect();
+                    let characters: usize = texts.iter().map(|text| text.chars().count()).sum();
+                    let tokens: usize = texts.iter().map(|text| llm::estimate_tokens(text)).sum();
+                    println!(
+                        "Estimated prompt size: {} tokens ({} characters).",
+                        tokens, characters
+                    );
+                    match config.input_cost_per_million_tokens {
+                        Some(rate) => println!(
+                            "Estimated input cost: ${:.4} (at ${} per million tokens).",
+                            tokens as f64 * rate / 1_000_000.0,
+                            rate
+                        ),
+                        None => println!(
+                            "Set input_cost_per_million_tokens in the config to estimate cost."
+                        ),
+                    }
+                    println!("Nothing was sent.");
+                    return Ok(());
+                }
+
+                if preview_json {
+                    let files: Vec<events::ContextFile> = sorted_paths
+                        .iter()
+                        .map(|path| events::ContextFile {
+                            path: path.clone(),
+                            read_only: *final_context_map.get(path).unwrap(),
+                        })
+                        .collect();
+                    let preview = serde_json::json!({
+                        "system": system_prompt,
+                        "messages": llm_messages_for_prompt,
+                        "files": files,
+                    });
+                    println!("{}", serde_json::to_string(&preview)?);
+                    return Ok(());
+                }
+
+                // A guardrail against sending a stale, sprawling stage by accident. --confirm
+                // already asks, so it only applies without it.
+                let mut context_files: Vec<&(String, String)> = read_write_files_prompt
+                    .iter()
+                    .chain(&read_only_files_prompt)
+                    .collect();
+                let reasons = large_context_reasons(&context_files, &config);
+                if !reasons.is_empty() && !confirm {
+                    let summary = format!("The context is large: {}.", reasons.join("; "));
+                    if json_events && !assume_yes {
+                        anyhow::bail!("{} Pass --yes to send it anyway.", summary);
+                    }
+                    if !json_events {
+                        println!("{}", summary);
+                        context_files.sort_by_key(|(_, content)| std::cmp::Reverse(content.len()));
+                        for (path, content) in &context_files {
+                            println!("  {:>10}  {}", content.len(), path);
+                        }
+                        if !prompt_yes_no("Send it anyway?", false, assume_yes)? {
+                            println!("Aborted.");
+                            return Ok(());
+                        }
+                    }
+                }
+
                 if confirm {
                     println!("--- PROMPT PREVIEW ---");
                     if let Some(system) = &system_prompt {
-                        println!("[system]\n{}", system);
+                        println!("{}\n{}", role_header("system"), system);
                         println!("---");
                     }
                     for msg in &llm_messages_for_prompt {
-                        println!("[{}]\n{}", msg.role, msg.content);
+                        println!("{}\n{}", role_header(&msg.role), msg.content);
                         println!("---");
                     }
-                    print!("Send Message? [Y/n] ");
+                    // The whole preview is out before the question, and nothing streams until
+                    // it's answered. Declining returns before the message is stored or the
+                    // stage is cleared, so the same send can be run again.
                     stdout().flush()?;
-                    let mut input = String::new();
-                    std::io::stdin().read_line(&mut input)?;
-                    let response = input.trim().to_lowercase();
-                    if response != "y" && !response.is_empty() {
+                    if !prompt_yes_no("Send Message?", config.confirm_default, assume_yes)? {
                         println!("Aborted.");
                         return Ok(());
                     }
@@ -545,7 +2369,15 @@ pub async fn run() -> anyhow::Result<()> {
                 // Add user message with metadata
                 let user_message_id =
                     db::add_message(&conn, parent_id, "user", &prompt, Some(&metadata_json))?;
-                println!("Added user message with ID: {}", user_message_id);
+                if !json_events {
+                    println!("Added user message with ID: {}", user_message_id);
+                }
+                // A new chat keeps the template it was started with.
+                if parent_id.is_none() {
+                    if let Some(name) = template.as_deref().filter(|name| *name != "default") {
+                        db::set_conversation_prompt_template(&conn, user_message_id, Some(name))?;
+                    }
+                }
 
                 // Convert to LLM ChatMessage format
                 let llm_messages: Vec<ChatMessage> = llm_messages_for_prompt
@@ -562,53 +2394,229 @@ pub async fn run() -> anyhow::Result<()> {
                     .collect();
 
                 // Get LLM response
+                // Auto mode streams for interactive use and buffers when piped, keeping script output atomic.
                 let use_stream = if stream {
                     true
                 } else if no_stream {
                     false
                 } else {
-                    config.stream.unwrap_or(false)
+                    config
+                        .stream
+                        .unwrap_or_else(|| std::io::stdout().is_terminal())
+                };
+
+                let stop_sequences = if stop_sequences.is_empty() {
+                    config.stop_sequences.clone()
+                } else {
+                    stop_sequences
                 };
 
+                let prompt_token_estimate =
+                    system_prompt.as_deref().map_or(0, llm::estimate_tokens)
+                        + prompt_tokens(&llm_messages_for_prompt);
+
+                // The model only returns the continuation, so the prefill is the start of the response.
+                let prefill = assistant_prefill.unwrap_or_default();
+                let prefill_len = prefill.len();
+                let started = std::time::Instant::now();
                 let assistant_response = if use_stream {
-                    let mut stream = llm::get_response_stream(&llm_messages, system_prompt).await?;
-                    let mut full_response = String::new();
+                    let mut stream =
+                        llm::get_response_stream(&config, &llm_messages, system_prompt).await?;
+                    if !prefill.is_empty() {
+                        if json_events {
+                            events::emit(&Event::Chunk {
+                                text: prefill.clone(),
+                            })?;
+                        } else if !pager {
+                            print!("{}", prefill);
+                            stdout().flush()?;
+                        }
+                    }
+                    let mut backup = config
+                        .stream_backup
+                        .then(|| StreamBackup::new(user_message_id));
+                    let mut full_response = prefill;
                     while let Some(result) = stream.next().await {
-                        let text_chunk = result?;
+                        let mut text_chunk = match result {
+                            Ok(text_chunk) => text_chunk,
+                            Err(e) if full_response.len() > prefill_len => {
+                                if !json_events && !pager {
+                                    println!();
+                                }
+                                let saved = save_partial_response(
+                                    &conn,
+                                    user_message_id,
+                                    &full_response,
+                                    e,
+                                );
+                                if saved.is_err() {
+                                    // The backup is then the only copy of what arrived.
+                                    if let Some(backup) = backup.take() {
+                                        backup.keep();
+                                    }
+                                }
+                                return Err(saved?);
+                            }
+                            Err(e) => {
+                                // Nothing but the prefill, which the user wrote, has arrived, so
+                                // there is no partial response worth saving.
+                                if prefill_len > 0 && !json_events && !pager {
+                                    println!();
+                                }
+                                return Err(e);
+                            }
+                        };
+                        if let Some(backup) = &mut backup {
+                            backup.write(&text_chunk)?;
+                        }
+                        let chunk_start = full_response.len();
                         full_response.push_str(&text_chunk);
-                        print!("{}", text_chunk);
-                        stdout().flush()?;
+                        let stop_at = llm::find_stop_sequence(&full_response, &stop_sequences);
+                        if let Some(pos) = stop_at {
+                            // Only show the part of this chunk before the stop sequence.
+                            text_chunk = full_response[chunk_start.min(pos)..pos].to_string();
+                            full_response.truncate(pos);
+                        }
+                        if json_events {
+                            events::emit(&Event::Chunk { text: text_chunk })?;
+                        } else if !pager {
+                            print!("{}", text_chunk);
+                            stdout().flush()?;
+                        }
+                        if stop_at.is_some() {
+                            // Dropping the stream stops the rest of the generation.
+                            break;
+                        }
+                    }
+                    if !json_events && !pager {
+                        println!(); // For a newline after the streaming is done
+                    }
+                    // The whole response arrived, so keep the backup until it is stored.
+                    if let Some(backup) = backup {
+                        backup.keep();
                     }
-                    println!(); // For a newline after the streaming is done
                     full_response
                 } else {
-                    llm::get_response(&llm_messages, system_prompt).await?
+                    let mut response =
+                        prefill + &llm::get_response(&config, &llm_messages, system_prompt).await?;
+                    if let Some(pos) = llm::find_stop_sequence(&response, &stop_sequences) {
+                        response.truncate(pos);
+                    }
+                    if json_events {
+                        events::emit(&Event::Chunk {
+                            text: response.clone(),
+                        })?;
+                    } else if !pager {
+                        println!("{}", response);
+                    }
+                    response
+                };
+
+                let latency = started.elapsed();
+                let generated_tokens =
+                    llm::estimate_tokens(assistant_response.get(prefill_len..).unwrap_or_default());
+
+                if pager {
+                    page(&assistant_response)?;
+                }
+
+                // Filtered before --review, so the reviewer sees what would be applied.
+                let assistant_response =
+                    llm::apply_response_filters(&assistant_response, &response_filters);
+
+                // --review: a human checkpoint between generation and touching any files.
+                // Whatever the editor leaves is what gets applied and stored.
+                let assistant_response = if review {
+                    let reviewed = if let Ok(mock_content) = std::env::var("MOCK_REVIEW_CONTENT") {
+                        mock_content
+                    } else {
+                        edit::edit(&assistant_response)?
+                    };
+                    if reviewed.trim().is_empty() {
+                        anyhow::bail!("The reviewed response is empty; no edits were applied.");
+                    }
+                    if reviewed != assistant_response {
+                        println!("Using the reviewed response.");
+                    }
+                    reviewed
+                } else {
+                    assistant_response
                 };
 
-                hook_manager.run_post_send_hooks(&assistant_response, &project_root)?;
+                let hook_context = hooks::HookContext {
+                    project_root,
+                    read_only_files: protected_paths(&final_context_map),
+                };
+                hook_manager.run_post_send_hooks(&assistant_response, &hook_context)?;
 
-                db::clear_context_stage(&conn, "default")?;
+                // The stage wasn't used with --context-from, so keep it for the next message.
+                if context_from.is_none() {
+                    db::clear_context_stage(&conn, "default")?;
+                }
 
+                let assistant_metadata_json = serde_json::to_string(&AssistantMetadata {
+                    streamed: use_stream,
+                    partial: false,
+                    latency_ms: Some(latency.as_millis() as u64),
+                })?;
                 let assistant_message_id = db::add_message(
                     &conn,
                     Some(user_message_id),
                     "assistant",
                     &assistant_response,
-                    None, // Assistant messages don't need metadata
+                    Some(&assistant_metadata_json),
                 )?;
-                println!("Added assistant message with ID: {}", assistant_message_id);
+                if use_stream && config.stream_backup {
+                    let _ = fs::remove_file(stream_backup_path(user_message_id));
+                }
+                if !json_events {
+                    println!("Added assistant message with ID: {}", assistant_message_id);
+                }
+                if timings {
+                    // Token counts are estimates; the backends' usage reports aren't read.
+                    let seconds = latency.as_secs_f64();
+                    println!(
+                        "Took {:.2}s for about {} tokens ({:.1} tokens/s).",
+                        seconds,
+                        generated_tokens,
+                        generated_tokens as f64 / seconds.max(0.001)
+                    );
+                    if let Some(rate) = config.input_cost_per_million_tokens {
+                        println!(
+                            "Estimated input cost: ${:.4} for about {} prompt tokens.",
+                            prompt_token_estimate as f64 * rate / 1_000_000.0,
+                            prompt_token_estimate
+                        );
+                    }
+                }
 
                 // If a chat tag was in play for this operation, update it.
-                // This happens for --chat or the active profile tag, but not for --parent or --new.
+                // This happens for --chat (with or without --new), --parent with --tag, or the
+                // active profile tag, but not for a bare --parent or --new.
                 if let Some(tag) = chat_tag_for_update {
-                    if parent_id.is_none() {
+                    if parent_id.is_none() && !json_events {
                         println!("Creating new chat with tag '{}'", &tag);
                     }
                     db::set_chat_tag(&conn, &tag, assistant_message_id)?;
-                    println!(
-                        "Updated tag '{}' to point to message ID {}",
-                        tag, assistant_message_id
-                    );
+                    if !json_events {
+                        println!(
+                            "Updated tag '{}' to point to message ID {}",
+                            tag, assistant_message_id
+                        );
+                    }
+                } else if parent_id.is_none() && !redoing_turn && config.auto_tag_new_chats {
+                    let tag = new_chat_tag(&conn, &prompt)?;
+                    db::set_chat_tag(&conn, &tag, assistant_message_id)?;
+                    db::set_active_chat_tag(&conn, &tag)?;
+                    if !json_events {
+                        println!("Tagged new chat as '{}' and made it the active chat.", tag);
+                    }
+                }
+
+                if json_events {
+                    events::emit(&Event::Done {
+                        assistant_message_id,
+                    })?;
                 }
             }
         }
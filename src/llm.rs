@@ -1,90 +1,622 @@
+use crate::config::{Config, ResponseFilter};
 use ::llm::{
     builder::{LLMBackend, LLMBuilder},
-    chat::ChatMessage,
+    chat::{ChatMessage, ChatRole, ReasoningEffort},
+    error::LLMError,
+    LLMProvider,
 };
 use anyhow::Result;
 use futures::stream::{Stream, StreamExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
 
-pub async fn get_response_stream(
-    messages: &[ChatMessage],
-    system_prompt: Option<String>,
-) -> Result<std::pin::Pin<Box<dyn Stream<Item = Result<String>> + Send>>> {
-    if let Ok(mock_content) = std::env::var("MOCK_LLM_CONTENT") {
-        return Ok(Box::pin(futures::stream::once(async { Ok(mock_content) })));
+/// Returns the byte offset of the earliest stop sequence in `text`, if any.
+///
+/// The `llm` crate doesn't expose server-side stop sequences, so callers use this
+/// to truncate responses (and stop reading streams) on the client.
+pub fn find_stop_sequence(text: &str, stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text.find(stop.as_str()))
+        .min()
+}
+
+/// Compiles the configured `response_filters`, so a bad pattern is reported before sending.
+pub fn compile_response_filters(filters: &[ResponseFilter]) -> Result<Vec<(Regex, String)>> {
+    filters
+        .iter()
+        .map(|filter| {
+            let pattern = Regex::new(&filter.pattern).map_err(|e| {
+                anyhow::anyhow!(
+                    "Invalid response_filters pattern '{}': {}",
+                    filter.pattern,
+                    e
+                )
+            })?;
+            Ok((pattern, filter.replacement.clone()))
+        })
+        .collect()
+}
+
+/// Applies each filter in turn, replacing every match.
+pub fn apply_response_filters(response: &str, filters: &[(Regex, String)]) -> String {
+    filters
+        .iter()
+        .fold(response.to_string(), |text, (pattern, replacement)| {
+            pattern
+                .replace_all(&text, replacement.as_str())
+                .into_owned()
+        })
+}
+
+/// Roughly estimates the number of tokens in `text`.
+///
+/// Uses the common ~4 characters per token rule of thumb, which is close enough
+/// for sizing prompts without shipping a tokenizer for every backend.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Whether a failed request is worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Dropped connections, timeouts, rate limits and server errors, which may pass on their own.
+    Transient,
+    /// Bad credentials, invalid requests and the like, which fail the same way every time.
+    Terminal,
+}
+
+/// The HTTP status code mentioned in an error message, if any. The `llm` crate
+/// only reports statuses as text, e.g. "API returned error status: 400 Bad Request".
+fn http_status(message: &str) -> Option<u16> {
+    let status_re = regex::Regex::new(r"(?i)status\D{0,20}(\d{3})").ok()?;
+    status_re
+        .captures(message)
+        .and_then(|captures| captures[1].parse().ok())
+}
+
+/// Classifies an error from the `llm` crate as transient or terminal.
+pub fn classify_error(err: &LLMError) -> ErrorClass {
+    let message = match err {
+        LLMError::HttpError(message) | LLMError::ProviderError(message) => message,
+        // The raw response is the provider's error body, so it's not searched for a status.
+        LLMError::ResponseFormatError { message, .. } => message,
+        _ => return ErrorClass::Terminal,
+    };
+    match http_status(message) {
+        Some(408 | 429) | Some(500..=599) => ErrorClass::Transient,
+        Some(_) => ErrorClass::Terminal,
+        // Without a status, an HTTP error never got a response: connection resets, timeouts, DNS.
+        None if matches!(err, LLMError::HttpError(_)) => ErrorClass::Transient,
+        None => ErrorClass::Terminal,
     }
-    if std::env::var("MOCK_LLM").is_ok() {
-        let response_string = "This is a mocked response.".to_string();
-        return Ok(Box::pin(futures::stream::once(async {
-            Ok(response_string)
-        })));
+}
+
+/// Wraps an `llm` error with a hint about whether trying again could help. The
+/// original error stays available through `downcast_ref`.
+fn chat_error(err: LLMError) -> anyhow::Error {
+    let hint = match classify_error(&err) {
+        ErrorClass::Transient => "this looks like a transient failure, so trying again may work",
+        ErrorClass::Terminal => {
+            "this looks like a config or request problem, not a transient failure"
+        }
+    };
+    anyhow::Error::new(err).context(format!("Chat error: {}.", hint))
+}
+
+/// Whether a backend continues a trailing assistant message instead of starting
+/// a new reply, which `--assistant-prefill` relies on.
+pub fn supports_prefill(backend_name: &str) -> bool {
+    matches!(
+        LLMBackend::from_str(backend_name),
+        Ok(LLMBackend::Anthropic)
+    )
+}
+
+/// The API key env var for a backend when `api_key_env` isn't configured.
+fn default_api_key_env(backend: &LLMBackend, backend_name: &str) -> String {
+    match backend {
+        LLMBackend::Google => "GOOGLE_API_KEY".to_string(),
+        _ => format!("{}_API_KEY", backend_name.to_uppercase()),
     }
+}
 
-    // Get Google API key from environment variable.
-    let api_key =
-        std::env::var("GEMINI_API_KEY").map_err(|_| anyhow::anyhow!("GEMINI_API_KEY not set."))?;
+/// Reads the API key for a backend from `api_key_env`, or from its default variable.
+fn resolve_api_key(
+    api_key_env: Option<&str>,
+    backend: &LLMBackend,
+    backend_name: &str,
+) -> Result<String> {
+    let key_var = api_key_env
+        .map(str::to_string)
+        .unwrap_or_else(|| default_api_key_env(backend, backend_name));
+    if let Ok(key) = std::env::var(&key_var) {
+        return Ok(key);
+    }
+    // The streaming path used to read GEMINI_API_KEY, so keep honoring it.
+    if api_key_env.is_none() && matches!(backend, LLMBackend::Google) {
+        if let Ok(key) = std::env::var("GEMINI_API_KEY") {
+            return Ok(key);
+        }
+    }
+    anyhow::bail!("{} not set.", key_var)
+}
+
+/// Parses a `reasoning_effort` setting.
+fn parse_reasoning_effort(effort: &str) -> Result<ReasoningEffort> {
+    match effort {
+        "low" => Ok(ReasoningEffort::Low),
+        "medium" => Ok(ReasoningEffort::Medium),
+        "high" => Ok(ReasoningEffort::High),
+        _ => anyhow::bail!(
+            "Unknown reasoning_effort '{}'; use low, medium or high.",
+            effort
+        ),
+    }
+}
+
+/// Forwards the reasoning effort to backends that take one. Anthropic takes a
+/// thinking budget instead, which has to stay under `max_tokens`.
+fn apply_reasoning_effort(
+    builder: LLMBuilder,
+    backend: &LLMBackend,
+    backend_name: &str,
+    effort: ReasoningEffort,
+) -> LLMBuilder {
+    match backend {
+        LLMBackend::OpenAI | LLMBackend::AzureOpenAI | LLMBackend::Cohere | LLMBackend::Mistral => {
+            builder.reasoning_effort(effort)
+        }
+        LLMBackend::Anthropic => {
+            let budget = match effort {
+                ReasoningEffort::Low => 1024,
+                ReasoningEffort::Medium => 4096,
+                ReasoningEffort::High => 8000,
+            };
+            // Anthropic rejects any other temperature while extended thinking is on.
+            builder
+                .reasoning(true)
+                .reasoning_budget_tokens(budget)
+                .temperature(1.0)
+        }
+        _ => {
+            eprintln!(
+                "Warning: backend '{}' doesn't support reasoning_effort; ignoring it.",
+                backend_name
+            );
+            builder
+        }
+    }
+}
+
+/// Builds the chat provider described by the config, shared by the streaming and
+/// non-streaming paths.
+fn build_llm(config: &Config, system_prompt: Option<String>) -> Result<Box<dyn LLMProvider>> {
+    let backend = LLMBackend::from_str(&config.backend).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let reasoning_effort = config
+        .reasoning_effort
+        .as_deref()
+        .map(parse_reasoning_effort)
+        .transpose()?;
+    let model = match &config.model {
+        Some(model) => model.clone(),
+        None => known_models(&backend)
+            .first()
+            .map(|model| model.to_string())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No default model for backend '{}'; set `model` in the config.",
+                    config.backend
+                )
+            })?,
+    };
+    let api_key = resolve_api_key(config.api_key_env.as_deref(), &backend, &config.backend)?;
 
     let mut builder = LLMBuilder::new()
-        .backend(LLMBackend::Google)
+        .backend(backend.clone())
         .api_key(api_key)
-        .model("gemini-2.5-flash");
+        .model(model)
+        .max_tokens(8512)
+        .temperature(config.temperature.unwrap_or(0.7));
 
+    if let Some(base_url) = &config.base_url {
+        builder = builder.base_url(base_url);
+    }
+    if let Some(effort) = reasoning_effort {
+        builder = apply_reasoning_effort(builder, &backend, &config.backend, effort);
+    }
     if let Some(system) = system_prompt {
         builder = builder.system(system);
     }
 
-    let llm = builder
+    builder
         .build()
-        .map_err(|e| anyhow::anyhow!("Failed to build LLM (Google): {}", e))?;
+        .map_err(|e| anyhow::anyhow!("Failed to build LLM ({}): {}", config.backend, e))
+}
 
-    let stream = llm.chat_stream(messages).await?;
+pub async fn get_response_stream(
+    config: &Config,
+    messages: &[ChatMessage],
+    system_prompt: Option<String>,
+) -> Result<std::pin::Pin<Box<dyn Stream<Item = Result<String>> + Send>>> {
+    if let Some(response_string) = mock_response(messages, system_prompt.as_deref())? {
+        // MOCK_LLM_STREAM_ERROR drops the mocked stream after its first chunk.
+        if let Ok(error) = std::env::var("MOCK_LLM_STREAM_ERROR") {
+            return Ok(Box::pin(futures::stream::iter([
+                Ok(response_string),
+                Err(anyhow::anyhow!(error)),
+            ])));
+        }
+        return Ok(Box::pin(futures::stream::once(async {
+            Ok(response_string)
+        })));
+    }
+
+    let entry = recording_entry(messages, system_prompt.as_deref());
+    let llm = build_llm(config, system_prompt)?;
+
+    let stream = llm.chat_stream(messages).await.map_err(chat_error)?;
 
-    Ok(Box::pin(
-        stream.map(|item| item.map_err(anyhow::Error::from)),
-    ))
+    let stream = Box::pin(stream.map(|item| item.map_err(chat_error)));
+    Ok(match entry {
+        Some((path, entry)) => Box::pin(RecordingStream {
+            inner: stream,
+            path,
+            entry,
+            failed: false,
+        }),
+        None => stream,
+    })
 }
 
 pub async fn get_response(
+    config: &Config,
     messages: &[ChatMessage],
     system_prompt: Option<String>,
 ) -> Result<String> {
-    // In a test environment, if MOCK_LLM is set, we return a mock response
-    // without making a network call.
-    if let Ok(mock_content) = std::env::var("MOCK_LLM_CONTENT") {
-        println!("{}", mock_content);
-        return Ok(mock_content);
-    }
-    if std::env::var("MOCK_LLM").is_ok() {
-        let response_string = "This is a mocked response.".to_string();
-        // The real function prints the response, so we do too for consistency.
-        println!("{}", response_string);
+    if let Some(response_string) = mock_response(messages, system_prompt.as_deref())? {
         return Ok(response_string);
     }
 
-    // Get Google API key from environment variable.
-    let api_key =
-        std::env::var("GOOGLE_API_KEY").map_err(|_| anyhow::anyhow!("GOOGLE_API_KEY not set."))?;
+    let entry = recording_entry(messages, system_prompt.as_deref());
+    let llm = build_llm(config, system_prompt)?;
 
-    let mut builder = LLMBuilder::new()
-        .backend(LLMBackend::Google)
-        .api_key(api_key)
-        .model("gemini-2.5-flash")
-        .max_tokens(8512)
-        .temperature(0.7);
+    let text = match llm.chat(messages).await {
+        Ok(text) => text.to_string(),
+        Err(e) => return Err(chat_error(e)),
+    };
+    if let Some((path, mut entry)) = entry {
+        entry.response = text.clone();
+        record("RETORT_RECORD", &path, &entry);
+    }
+    Ok(text)
+}
 
-    if let Some(system) = system_prompt {
-        builder = builder.system(system);
+/// One request and the model's answer, as written to a `RETORT_RECORD` cassette.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct CassetteEntry {
+    system: Option<String>,
+    messages: Vec<CassetteMessage>,
+    response: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct CassetteMessage {
+    role: String,
+    content: String,
+}
+
+/// The cassette to record this request to, and the entry to fill in with the response,
+/// if `$RETORT_RECORD` is set.
+fn recording_entry(
+    messages: &[ChatMessage],
+    system_prompt: Option<&str>,
+) -> Option<(PathBuf, CassetteEntry)> {
+    let path = std::env::var_os("RETORT_RECORD")?;
+    Some((PathBuf::from(path), cassette_entry(messages, system_prompt)))
+}
+
+/// A cassette entry for this request, with an empty response.
+fn cassette_entry(messages: &[ChatMessage], system_prompt: Option<&str>) -> CassetteEntry {
+    let messages = messages
+        .iter()
+        .map(|message| CassetteMessage {
+            role: match message.role {
+                ChatRole::User => "user",
+                ChatRole::Assistant => "assistant",
+            }
+            .to_string(),
+            content: message.content.clone(),
+        })
+        .collect();
+    CassetteEntry {
+        system: system_prompt.map(str::to_string),
+        messages,
+        response: String::new(),
+    }
+}
+
+/// Appends `entry` to the cassette at `path`, named by `env_var` in warnings. Recording
+/// is a side channel, so a failure is only a warning and never fails the send.
+fn record(env_var: &str, path: &Path, entry: &CassetteEntry) {
+    if let Err(e) = append_to_cassette(path, entry) {
+        eprintln!(
+            "Warning: could not record to {} file {}: {}",
+            env_var,
+            path.display(),
+            e
+        );
     }
+}
+
+fn append_to_cassette(path: &Path, entry: &CassetteEntry) -> Result<()> {
+    let mut entries: Vec<serde_json::Value> = match std::fs::read_to_string(path) {
+        Ok(existing) => serde_json::from_str(&existing)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    entries.push(serde_json::to_value(entry)?);
+    std::fs::write(path, serde_json::to_string_pretty(&entries)?)?;
+    Ok(())
+}
+
+/// Passes a response stream through, recording the text read from it once it's dropped,
+/// so a stream cut short at a stop sequence is still recorded.
+struct RecordingStream {
+    inner: Pin<Box<dyn Stream<Item = Result<String>> + Send>>,
+    path: PathBuf,
+    entry: CassetteEntry,
+    failed: bool,
+}
+
+impl Stream for RecordingStream {
+    type Item = Result<String>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let poll = this.inner.as_mut().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => this.entry.response.push_str(chunk),
+            Poll::Ready(Some(Err(_))) => this.failed = true,
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl Drop for RecordingStream {
+    fn drop(&mut self) {
+        if !self.failed {
+            record("RETORT_RECORD", &self.path, &self.entry);
+        }
+    }
+}
+
+/// In a test environment, returns the response to use instead of making a network call:
+/// the next one recorded in `$RETORT_REPLAY`, `$MOCK_LLM_CONTENT`, or a fixed string if
+/// `MOCK_LLM` is set. If `$MOCK_LLM_LOG` is set, the request that reached the mock and its
+/// response are appended to that file in the `RETORT_RECORD` format, for tests to inspect.
+fn mock_response(messages: &[ChatMessage], system_prompt: Option<&str>) -> Result<Option<String>> {
+    let response = if let Ok(path) = std::env::var("RETORT_REPLAY") {
+        next_replayed_response(Path::new(&path))?
+    } else if let Ok(mock_content) = std::env::var("MOCK_LLM_CONTENT") {
+        mock_content
+    } else if std::env::var("MOCK_LLM").is_ok() {
+        "This is a mocked response.".to_string()
+    } else {
+        return Ok(None);
+    };
+    if let Some(path) = std::env::var_os("MOCK_LLM_LOG") {
+        let entry = CassetteEntry {
+            response: response.clone(),
+            ..cassette_entry(messages, system_prompt)
+        };
+        record("MOCK_LLM_LOG", Path::new(&path), &entry);
+    }
+    Ok(Some(response))
+}
 
+/// Where the number of responses already replayed from `path` is kept between runs.
+fn replay_cursor_path(path: &Path) -> PathBuf {
+    let mut cursor = path.as_os_str().to_owned();
+    cursor.push(".cursor");
+    PathBuf::from(cursor)
+}
+
+/// A response in a `RETORT_REPLAY` file: a plain string, or an entry recorded by `RETORT_RECORD`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReplayEntry {
+    Response(String),
+    Recorded { response: String },
+}
+
+/// Returns the next response from a JSON array of strings or recorded entries, one per call,
+/// in order. Progress is kept in `<path>.cursor`; delete it to replay from the start.
+fn next_replayed_response(path: &Path) -> Result<String> {
+    let recorded = std::fs::read_to_string(path).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to read RETORT_REPLAY file {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let responses: Vec<ReplayEntry> = serde_json::from_str(&recorded).map_err(|e| {
+        anyhow::anyhow!(
+            "RETORT_REPLAY file {} must be a JSON array of response strings or recorded entries: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let cursor_path = replay_cursor_path(path);
+    let next: usize = match std::fs::read_to_string(&cursor_path) {
+        Ok(cursor) => cursor.trim().parse()?,
+        Err(_) => 0,
+    };
+    let response = match responses.get(next) {
+        Some(ReplayEntry::Response(response) | ReplayEntry::Recorded { response }) => {
+            response.clone()
+        }
+        None => anyhow::bail!(
+            "All {} responses in RETORT_REPLAY file {} have been replayed; delete {} to start over.",
+            responses.len(),
+            path.display(),
+            cursor_path.display()
+        ),
+    };
+    std::fs::write(&cursor_path, (next + 1).to_string())?;
+    Ok(response)
+}
+
+/// Models known to work with backends whose model-list API isn't supported by the `llm` crate.
+fn known_models(backend: &LLMBackend) -> &'static [&'static str] {
+    match backend {
+        LLMBackend::Google => &[
+            "gemini-2.5-flash",
+            "gemini-2.5-pro",
+            "gemini-2.5-flash-lite",
+        ],
+        LLMBackend::DeepSeek => &["deepseek-chat", "deepseek-reasoner"],
+        LLMBackend::OpenAI => &["gpt-4o", "gpt-4o-mini"],
+        LLMBackend::Anthropic => &["claude-sonnet-4-5", "claude-opus-4-1", "claude-haiku-4-5"],
+        _ => &[],
+    }
+}
+
+/// Lists the model IDs available for a backend.
+///
+/// Backends with a model-list API are queried like `build_llm` does: for the configured
+/// backend, at its `base_url` with the key from `api_key_env`, and otherwise at the
+/// default endpoint with `<BACKEND>_API_KEY`. Other backends return a known default set,
+/// which may be empty.
+pub async fn list_models(config: &Config, backend_name: &str) -> Result<Vec<String>> {
+    let backend = LLMBackend::from_str(backend_name).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let has_list_api = matches!(
+        backend,
+        LLMBackend::OpenAI | LLMBackend::Anthropic | LLMBackend::Cohere | LLMBackend::Mistral
+    );
+    if !has_list_api {
+        return Ok(known_models(&backend)
+            .iter()
+            .map(|model| model.to_string())
+            .collect());
+    }
+
+    // The endpoint settings belong to the configured backend, not one picked with --backend.
+    let (api_key_env, base_url) = if backend_name == config.backend {
+        (config.api_key_env.as_deref(), config.base_url.as_deref())
+    } else {
+        (None, None)
+    };
+    let api_key = resolve_api_key(api_key_env, &backend, backend_name)?;
+
+    let mut builder = LLMBuilder::new().backend(backend.clone()).api_key(api_key);
+    if let Some(base_url) = base_url {
+        builder = builder.base_url(base_url);
+    }
     let llm = builder
         .build()
-        .map_err(|e| anyhow::anyhow!("Failed to build LLM (Google): {}", e))?;
+        .map_err(|e| anyhow::anyhow!("Failed to build LLM ({}): {}", backend_name, e))?;
 
-    match llm.chat(messages).await {
-        Ok(text) => {
-            let response_string = text.to_string();
-            println!("{}", response_string);
-            Ok(response_string)
+    let response = llm
+        .list_models(None)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to list models for {}: {}", backend_name, e))?;
+    let mut models = response.get_models();
+    models.sort();
+    Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anthropic_defaults() {
+        let backend = LLMBackend::from_str("anthropic").unwrap();
+        assert_eq!(known_models(&backend).first(), Some(&"claude-sonnet-4-5"));
+        assert_eq!(
+            default_api_key_env(&backend, "anthropic"),
+            "ANTHROPIC_API_KEY"
+        );
+    }
+
+    #[test]
+    fn test_next_replayed_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.json");
+        std::fs::write(&path, r#"["first", "second"]"#).unwrap();
+
+        assert_eq!(next_replayed_response(&path).unwrap(), "first");
+        assert_eq!(next_replayed_response(&path).unwrap(), "second");
+        let err = next_replayed_response(&path).unwrap_err().to_string();
+        assert!(err.contains("All 2 responses"), "{}", err);
+
+        std::fs::remove_file(replay_cursor_path(&path)).unwrap();
+        assert_eq!(next_replayed_response(&path).unwrap(), "first");
+
+        // A cassette written by RETORT_RECORD replays its responses.
+        let cassette = dir.path().join("cassette.json");
+        let entry = CassetteEntry {
+            system: Some("Be brief.".to_string()),
+            messages: vec![CassetteMessage {
+                role: "user".to_string(),
+                content: "hi".to_string(),
+            }],
+            response: "hello".to_string(),
+        };
+        append_to_cassette(&cassette, &entry).unwrap();
+        append_to_cassette(&cassette, &entry).unwrap();
+        let recorded: Vec<CassetteEntry> =
+            serde_json::from_str(&std::fs::read_to_string(&cassette).unwrap()).unwrap();
+        assert_eq!(recorded, vec![entry.clone(), entry]);
+        assert_eq!(next_replayed_response(&cassette).unwrap(), "hello");
+
+        std::fs::write(&path, r#"{"not": "a list"}"#).unwrap();
+        let err = next_replayed_response(&path).unwrap_err().to_string();
+        assert!(err.contains("must be a JSON array"), "{}", err);
+    }
+
+    #[test]
+    fn test_classify_error() {
+        let transient = [
+            LLMError::HttpError("error sending request: connection reset by peer".to_string()),
+            LLMError::HttpError(
+                "HTTP status server error (503 Service Unavailable) for url (https://x)"
+                    .to_string(),
+            ),
+            LLMError::ResponseFormatError {
+                message: "OpenAI API returned error status: 429 Too Many Requests".to_string(),
+                raw_response: String::new(),
+            },
+        ];
+        for err in &transient {
+            assert_eq!(classify_error(err), ErrorClass::Transient, "{}", err);
         }
-        Err(e) => anyhow::bail!("Chat error: {e}"),
+
+        let terminal = [
+            LLMError::AuthError("Missing Anthropic API key".to_string()),
+            LLMError::HttpError(
+                "HTTP status client error (400 Bad Request) for url (https://x)".to_string(),
+            ),
+            LLMError::ResponseFormatError {
+                message: "Anthropic API returned error status: 401 Unauthorized".to_string(),
+                raw_response: "status 500".to_string(),
+            },
+            LLMError::ProviderError("No text in response".to_string()),
+        ];
+        for err in &terminal {
+            assert_eq!(classify_error(err), ErrorClass::Terminal, "{}", err);
+        }
+
+        let err = chat_error(LLMError::HttpError("operation timed out".to_string()));
+        assert!(err.to_string().contains("trying again may work"));
+        assert!(err.downcast_ref::<LLMError>().is_some());
     }
 }
@@ -0,0 +1,193 @@
+//! Unified diffs in the format `git apply` reads, for exporting edits without writing them.
+
+use std::fmt::Write;
+
+/// Lines of unchanged context around each hunk, as `git diff` uses by default.
+const CONTEXT_LINES: usize = 3;
+
+/// Past this many line pairs, the changed middle of a file is diffed as one block
+/// instead of being aligned line by line.
+const MAX_ALIGNED_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// A git-style diff from `old` to `new` for the file at `path`, or `None` for a file that
+/// doesn't exist yet. Empty if the content is unchanged.
+pub fn file_diff(path: &str, old: Option<&str>, new: &str) -> String {
+    if old == Some(new) {
+        return String::new();
+    }
+    let mut out = format!("diff --git a/{0} b/{0}\n", path);
+    match old {
+        Some(_) => {
+            let _ = write!(out, "--- a/{}\n+++ b/{}\n", path, path);
+        }
+        None if new.is_empty() => {
+            out.push_str("new file mode 100644\n");
+            return out;
+        }
+        None => {
+            let _ = write!(out, "new file mode 100644\n--- /dev/null\n+++ b/{}\n", path);
+        }
+    }
+
+    let old_lines: Vec<&str> = old.unwrap_or_default().split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = diff_lines(&old_lines, &new_lines);
+    for hunk in hunks(&ops) {
+        write_hunk(&mut out, &ops, hunk, &old_lines, &new_lines);
+    }
+    out
+}
+
+/// The edit script turning `old` into `new`: the common prefix and suffix are kept, and
+/// what's between them is aligned on its longest common subsequence.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut ops = vec![Op::Equal; prefix];
+    if old_mid.len().saturating_mul(new_mid.len()) > MAX_ALIGNED_CELLS {
+        ops.extend(std::iter::repeat_n(Op::Delete, old_mid.len()));
+        ops.extend(std::iter::repeat_n(Op::Insert, new_mid.len()));
+    } else {
+        // lcs[i][j] is the length of the longest common subsequence of old_mid[i..] and new_mid[j..].
+        let (n, m) = (old_mid.len(), new_mid.len());
+        let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i][j] = if old_mid[i] == new_mid[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_mid[i] == new_mid[j] {
+                ops.push(Op::Equal);
+                i += 1;
+                j += 1;
+            } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                ops.push(Op::Delete);
+                i += 1;
+            } else {
+                ops.push(Op::Insert);
+                j += 1;
+            }
+        }
+    }
+    ops.extend(std::iter::repeat_n(Op::Equal, suffix));
+    ops
+}
+
+/// The ranges of `ops` that make up each hunk: every change with up to
+/// `CONTEXT_LINES` of context on each side, merging hunks whose context overlaps.
+fn hunks(ops: &[Op]) -> Vec<std::ops::Range<usize>> {
+    let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+    for (index, op) in ops.iter().enumerate() {
+        if *op == Op::Equal {
+            continue;
+        }
+        let start = index.saturating_sub(CONTEXT_LINES);
+        let end = (index + 1 + CONTEXT_LINES).min(ops.len());
+        match ranges.last_mut() {
+            Some(last) if start <= last.end => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+    ranges
+}
+
+fn write_hunk(
+    out: &mut String,
+    ops: &[Op],
+    range: std::ops::Range<usize>,
+    old: &[&str],
+    new: &[&str],
+) {
+    // Where the hunk starts in each file: the lines the ops before it consumed.
+    let (before, hunk) = (&ops[..range.start], &ops[range]);
+    let mut old_index = before.iter().filter(|op| **op != Op::Insert).count();
+    let mut new_index = before.iter().filter(|op| **op != Op::Delete).count();
+    let old_count = hunk.iter().filter(|op| **op != Op::Insert).count();
+    let new_count = hunk.iter().filter(|op| **op != Op::Delete).count();
+    // An empty range is numbered by the line before it, as in `diff -u`.
+    let start = |index: usize, count: usize| if count == 0 { index } else { index + 1 };
+    let _ = writeln!(
+        out,
+        "@@ -{},{} +{},{} @@",
+        start(old_index, old_count),
+        old_count,
+        start(new_index, new_count),
+        new_count
+    );
+
+    for op in hunk {
+        let (marker, line) = match op {
+            Op::Equal => {
+                old_index += 1;
+                new_index += 1;
+                (' ', new[new_index - 1])
+            }
+            Op::Delete => {
+                old_index += 1;
+                ('-', old[old_index - 1])
+            }
+            Op::Insert => {
+                new_index += 1;
+                ('+', new[new_index - 1])
+            }
+        };
+        out.push(marker);
+        out.push_str(line);
+        if !line.ends_with('\n') {
+            out.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+        assert_eq!(
+            file_diff("src/x.txt", Some(old), new),
+            "diff --git a/src/x.txt b/src/x.txt\n--- a/src/x.txt\n+++ b/src/x.txt\n\
+             @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+             @@ -8,3 +8,4 @@\n h\n i\n j\n+k\n"
+        );
+        assert_eq!(file_diff("x", Some(old), old), "");
+    }
+
+    #[test]
+    fn test_file_diff_new_file_and_missing_newline() {
+        assert_eq!(
+            file_diff("new.txt", None, "one\ntwo"),
+            "diff --git a/new.txt b/new.txt\nnew file mode 100644\n--- /dev/null\n+++ b/new.txt\n\
+             @@ -0,0 +1,2 @@\n+one\n+two\n\\ No newline at end of file\n"
+        );
+        assert_eq!(
+            file_diff("x", Some("one"), "one\n"),
+            "diff --git a/x b/x\n--- a/x\n+++ b/x\n\
+             @@ -1,1 +1,1 @@\n-one\n\\ No newline at end of file\n+one\n"
+        );
+    }
+}
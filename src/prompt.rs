@@ -1,12 +1,16 @@
 use crate::db::HistoryMessage;
+use crate::fence;
+use crate::hooks::postprocessor::{ChangeKind, EditFormat, EditMarkers};
 use anyhow::Result;
 use minijinja::Environment;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 // Stubbed data from Python _build_diff_fenced_context
 const READ_ONLY_FILES_PREFIX: &str = "The user has provided the following read-only files:";
 const CHAT_FILES_PREFIX: &str =
     "The user has added these files to the chat. You may propose edits to them.";
+const READ_ONLY_FILES_REPLY: &str = "Ok, I will use these files as references.";
+const CHAT_FILES_REPLY: &str = "Ok, any changes I propose will be to those files.";
 const RENAME_WITH_SHELL: &str =
     "To rename files which have been added to the chat, use shell commands at the end of your response.";
 const GO_AHEAD_TIP: &str = "If the user just says something like \"ok\" or \"go ahead\" or \"do that\" they probably want you to make SEARCH/REPLACE blocks for the code changes you just proposed.\nThe user will say when they've applied your edits. If they haven't explicitly confirmed the edits have been applied, they probably want proper SEARCH/REPLACE blocks.";
@@ -14,65 +18,318 @@ const LAZY_PROMPT: &str = "";
 const OVEREAGER_PROMPT: &str = "Pay careful attention to the scope of the user's request.\nDo what they ask, but no more.\nDo not improve, comment, fix or modify unrelated parts of the code in any way!";
 const SYSTEM_REMINDER: Option<&str> = None;
 
+/// Templates used to build the system prompt, by file name under `prompts/`.
+const SYSTEM_PROMPT_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "_diff_fenced_system_prompt.j2",
+        include_str!("../prompts/_diff_fenced_system_prompt.j2"),
+    ),
+    (
+        "_shell_cmd_prompt.j2",
+        include_str!("../prompts/_shell_cmd_prompt.j2"),
+    ),
+    (
+        "_shell_cmd_reminder.j2",
+        include_str!("../prompts/_shell_cmd_reminder.j2"),
+    ),
+];
+
+/// The name a `prompt_templates` template is loaded under.
+const CUSTOM_SYSTEM_PROMPT: &str = "custom_system_prompt.j2";
+
+/// Shipped templates that aren't rendered yet, but are still checked by `retort doctor`.
+const OTHER_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "_final_reminders.j2",
+        include_str!("../prompts/_final_reminders.j2"),
+    ),
+    ("diff_fenced.j2", include_str!("../prompts/diff_fenced.j2")),
+];
+
+/// Where the file blocks go in the prompt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextPosition {
+    /// Right after the system prompt, ahead of the whole conversation.
+    #[default]
+    BeforeHistory,
+    /// After the conversation history, just before the latest user message.
+    BeforeLastUser,
+}
+
+/// Config overrides for the prompt scaffolding. Unset fields use the built-in text.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PromptOverrides {
+    #[serde(default)]
+    pub read_only_files_prefix: Option<String>,
+    #[serde(default)]
+    pub chat_files_prefix: Option<String>,
+    #[serde(default)]
+    pub go_ahead_tip: Option<String>,
+    #[serde(default)]
+    pub overeager_prompt: Option<String>,
+    /// The source of a template to render instead of the built-in system prompt. It's
+    /// chosen per send from `prompt_templates`, so it can't be set here in the config.
+    #[serde(skip)]
+    pub system_prompt_template: Option<String>,
+    /// A system prompt used as-is, without rendering any template. Set per send by `--system-file`.
+    #[serde(skip)]
+    pub system_prompt: Option<String>,
+    /// Set from the top-level `context_position` config.
+    #[serde(skip)]
+    pub context_position: ContextPosition,
+    /// Set from the top-level `edit_format` config.
+    #[serde(skip)]
+    pub edit_format: EditFormat,
+}
+
 #[derive(Serialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
 }
 
-pub fn build_prompt_messages(
-    done_messages: Vec<HistoryMessage>,
-    cur_messages: Vec<HistoryMessage>,
-    read_write_files: &[(String, String)],
-    read_only_files: &[(String, String)],
-) -> Result<Vec<Message>> {
-    #[derive(Serialize)]
-    struct SystemPromptContext {
-        fence: &'static str,
-        platform: String,
-        lazy_prompt: &'static str,
-        overeager_prompt: &'static str,
-        rename_with_shell: &'static str,
-        go_ahead_tip: &'static str,
+#[derive(Serialize)]
+struct SystemPromptContext<'a> {
+    fence: &'a str,
+    edit_format: EditMarkers,
+    platform: String,
+    lazy_prompt: &'static str,
+    overeager_prompt: &'a str,
+    rename_with_shell: &'static str,
+    go_ahead_tip: &'a str,
+}
+
+impl<'a> SystemPromptContext<'a> {
+    fn new(overrides: &'a PromptOverrides, fence: &'a str) -> Self {
+        let platform_info = format!(
+            "- Platform: {}-{}\n- Shell: {}",
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            std::env::var("SHELL").unwrap_or_else(|_| "unknown".to_string())
+        );
+        Self {
+            fence,
+            edit_format: overrides.edit_format.markers(),
+            platform: platform_info,
+            lazy_prompt: LAZY_PROMPT,
+            overeager_prompt: overrides
+                .overeager_prompt
+                .as_deref()
+                .unwrap_or(OVEREAGER_PROMPT),
+            rename_with_shell: RENAME_WITH_SHELL,
+            go_ahead_tip: overrides.go_ahead_tip.as_deref().unwrap_or(GO_AHEAD_TIP),
+        }
+    }
+}
+
+/// Wraps a minijinja error with the template it came from and a hint at the usual fix.
+fn template_error(action: &str, name: &str, err: minijinja::Error) -> anyhow::Error {
+    let file = if name == CUSTOM_SYSTEM_PROMPT {
+        "the chat's prompt_templates file".to_string()
+    } else {
+        format!("prompts/{}", name)
+    };
+    anyhow::anyhow!(
+        "Failed to {} template {}: {}\nCheck {} for broken Jinja syntax, such as an unclosed `{{% if %}}` block or a typo in a `{{{{ }}}}` expression.",
+        action,
+        file,
+        err,
+        file
+    )
+}
+
+fn load_templates(templates: &[(&'static str, &'static str)]) -> Result<Environment<'static>> {
+    let mut env = Environment::new();
+    for (name, source) in templates {
+        env.add_template(name, source)
+            .map_err(|e| template_error("load", name, e))?;
     }
+    Ok(env)
+}
+
+fn render_template<S: Serialize>(env: &Environment, name: &str, context: S) -> Result<String> {
+    env.get_template(name)
+        .and_then(|tmpl| tmpl.render(context))
+        .map_err(|e| template_error("render", name, e))
+}
 
+/// Renders every shipped template with a sample context, returning each template's
+/// name and whether it loaded and rendered.
+pub fn validate_templates() -> Vec<(&'static str, Result<()>)> {
+    let all_templates: Vec<(&'static str, &'static str)> = SYSTEM_PROMPT_TEMPLATES
+        .iter()
+        .chain(OTHER_TEMPLATES)
+        .copied()
+        .collect();
+
+    // Load each template on its own first, so one broken template doesn't hide the rest.
     let mut env = Environment::new();
-    env.add_template(
-        "_diff_fenced_system_prompt.j2",
-        include_str!("../prompts/_diff_fenced_system_prompt.j2"),
-    )?;
-    env.add_template(
-        "_shell_cmd_prompt.j2",
-        include_str!("../prompts/_shell_cmd_prompt.j2"),
-    )?;
-    env.add_template(
-        "_shell_cmd_reminder.j2",
-        include_str!("../prompts/_shell_cmd_reminder.j2"),
-    )?;
-    let tmpl = env.get_template("_diff_fenced_system_prompt.j2")?;
-
-    let fence = "```";
-    let platform_info = format!(
-        "- Platform: {}-{}\n- Shell: {}",
-        std::env::consts::OS,
-        std::env::consts::ARCH,
-        std::env::var("SHELL").unwrap_or_else(|_| "unknown".to_string())
-    );
+    let mut load_errors = std::collections::HashMap::new();
+    for (name, source) in &all_templates {
+        if let Err(e) = env.add_template(name, source) {
+            load_errors.insert(*name, template_error("load", name, e));
+        }
+    }
 
-    let context = SystemPromptContext {
-        fence,
-        platform: platform_info,
-        lazy_prompt: LAZY_PROMPT,
-        overeager_prompt: OVEREAGER_PROMPT,
-        rename_with_shell: RENAME_WITH_SHELL,
-        go_ahead_tip: GO_AHEAD_TIP,
-    };
+    all_templates
+        .iter()
+        .map(|(name, _)| {
+            let result = match load_errors.remove(name) {
+                Some(err) => Err(err),
+                None => render_template(
+                    &env,
+                    name,
+                    SystemPromptContext::new(&PromptOverrides::default(), fence::DEFAULT_FENCE),
+                )
+                .map(|_| ()),
+            };
+            (*name, result)
+        })
+        .collect()
+}
+
+/// Whether a message is one of the turns `build_prompt_messages` wraps file blocks in,
+/// rather than part of the conversation, e.g. in an imported transcript.
+pub fn is_file_context_turn(message: &HistoryMessage, overrides: &PromptOverrides) -> bool {
+    match message.role.as_str() {
+        "assistant" => {
+            message.content == READ_ONLY_FILES_REPLY || message.content == CHAT_FILES_REPLY
+        }
+        "user" => [
+            overrides
+                .read_only_files_prefix
+                .as_deref()
+                .unwrap_or(READ_ONLY_FILES_PREFIX),
+            overrides
+                .chat_files_prefix
+                .as_deref()
+                .unwrap_or(CHAT_FILES_PREFIX),
+        ]
+        .iter()
+        .any(|prefix| message.content.starts_with(&format!("{}\n", prefix))),
+        _ => false,
+    }
+}
+
+/// Replaces the body of each fenced code block longer than `max_lines` with a note
+/// saying how many lines were hidden. Unclosed fences are left as they are.
+pub fn collapse_code_blocks(content: &str, max_lines: usize) -> String {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        match fence::closing_fence(&lines, i) {
+            Some(close) => {
+                let hidden = close - i - 1;
+                result.push(line.to_string());
+                if hidden > max_lines {
+                    result.push(format!("[{} lines hidden]", hidden));
+                } else {
+                    result.extend(lines[i + 1..close].iter().map(|l| l.to_string()));
+                }
+                result.push(lines[close].to_string());
+                i = close + 1;
+            }
+            None => {
+                result.push(line.to_string());
+                i += 1;
+            }
+        }
+    }
+    result.join("\n")
+}
+
+/// Splits a `path:start-end` context entry into the file and its 1-based, inclusive line range.
+pub fn split_line_range(path: &str) -> Option<(&str, usize, usize)> {
+    let (file, range) = path.rsplit_once(':')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+    if file.is_empty() || start == 0 || end < start {
+        return None;
+    }
+    Some((file, start, end))
+}
+
+/// How a context file is labelled in the prompt, so the model knows a line range is a fragment.
+fn file_label(path: &str) -> String {
+    match split_line_range(path) {
+        Some((file, start, end)) => format!("{} (lines {}-{} only)", file, start, end),
+        None => path.to_string(),
+    }
+}
 
-    let mut system_prompt_content = tmpl.render(context)?;
+/// Checks that the system prompt `overrides` select describes the edit blocks of
+/// `overrides.edit_format` and no others: every marker line of its blocks appears in the
+/// prompt, and none of another kind's. Returns one problem per marker that is wrong.
+pub fn check_edit_format(overrides: &PromptOverrides) -> Result<Vec<String>> {
+    let system_prompt = render_system_prompt(overrides, fence::DEFAULT_FENCE)?;
+    let kinds = overrides.edit_format.kinds();
+    let mut problems = Vec::new();
+    for kind in [ChangeKind::Replace, ChangeKind::Append] {
+        for marker in kind.markers() {
+            let described = system_prompt.contains(marker);
+            if kinds.contains(&kind) && !described {
+                problems.push(format!(
+                    "doesn't show the `{}` line of {} blocks",
+                    marker,
+                    kind.block_name()
+                ));
+            } else if !kinds.contains(&kind) && described {
+                problems.push(format!(
+                    "shows the `{}` line of {} blocks, which the edit_format doesn't parse",
+                    marker,
+                    kind.block_name()
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+fn render_system_prompt(overrides: &PromptOverrides, fence: &str) -> Result<String> {
+    let mut env = load_templates(SYSTEM_PROMPT_TEMPLATES)?;
+    // A custom template can still `{% include %}` the shipped partials.
+    let system_template = match &overrides.system_prompt_template {
+        Some(source) => {
+            env.add_template_owned(CUSTOM_SYSTEM_PROMPT, source.clone())
+                .map_err(|e| template_error("load", CUSTOM_SYSTEM_PROMPT, e))?;
+            CUSTOM_SYSTEM_PROMPT
+        }
+        None => "_diff_fenced_system_prompt.j2",
+    };
+    let mut system_prompt_content = render_template(
+        &env,
+        system_template,
+        SystemPromptContext::new(overrides, fence),
+    )?;
     if let Some(reminder) = SYSTEM_REMINDER {
         system_prompt_content.push('\n');
         system_prompt_content.push_str(reminder);
     }
+    Ok(system_prompt_content)
+}
+
+pub fn build_prompt_messages(
+    done_messages: Vec<HistoryMessage>,
+    cur_messages: Vec<HistoryMessage>,
+    read_write_files: &[(String, String)],
+    read_only_files: &[(String, String)],
+    overrides: &PromptOverrides,
+) -> Result<Vec<Message>> {
+    // One fence for every file, longer than any backtick run in them, so a file with code
+    // blocks of its own can't end its block early. The model is told to fence edits with it too.
+    let fence = fence::fence_for(
+        read_write_files
+            .iter()
+            .chain(read_only_files)
+            .map(|(_, content)| content.as_str()),
+    );
+    let system_prompt_content = match &overrides.system_prompt {
+        Some(system_prompt) => system_prompt.clone(),
+        None => render_system_prompt(overrides, &fence)?,
+    };
 
     let mut result_messages = Vec::new();
 
@@ -81,10 +338,31 @@ pub fn build_prompt_messages(
         content: system_prompt_content,
     });
 
+    let mut history: Vec<Message> = done_messages
+        .into_iter()
+        .map(|m| Message {
+            role: m.role,
+            content: m.content,
+        })
+        .collect();
+    if overrides.context_position == ContextPosition::BeforeLastUser {
+        result_messages.append(&mut history);
+    }
+
     if !read_only_files.is_empty() {
-        let mut content = format!("{}\n", READ_ONLY_FILES_PREFIX);
+        let prefix = overrides
+            .read_only_files_prefix
+            .as_deref()
+            .unwrap_or(READ_ONLY_FILES_PREFIX);
+        let mut content = format!("{}\n", prefix);
         for (path, file_content) in read_only_files {
-            content.push_str(&format!("{}\n```\n{}\n```\n", path, file_content));
+            content.push_str(&format!(
+                "{}\n{}\n{}\n{}\n",
+                file_label(path),
+                fence,
+                file_content,
+                fence
+            ));
         }
         result_messages.push(Message {
             role: "user".to_string(),
@@ -92,14 +370,21 @@ pub fn build_prompt_messages(
         });
         result_messages.push(Message {
             role: "assistant".to_string(),
-            content: "Ok, I will use these files as references.".to_string(),
+            content: READ_ONLY_FILES_REPLY.to_string(),
         });
     }
 
     if !read_write_files.is_empty() {
-        let mut content = format!("{}\n", CHAT_FILES_PREFIX);
+        let prefix = overrides
+            .chat_files_prefix
+            .as_deref()
+            .unwrap_or(CHAT_FILES_PREFIX);
+        let mut content = format!("{}\n", prefix);
         for (path, file_content) in read_write_files {
-            content.push_str(&format!("{}\n```\n{}\n```\n", path, file_content));
+            content.push_str(&format!(
+                "{}\n{}\n{}\n{}\n",
+                path, fence, file_content, fence
+            ));
         }
         result_messages.push(Message {
             role: "user".to_string(),
@@ -107,14 +392,12 @@ pub fn build_prompt_messages(
         });
         result_messages.push(Message {
             role: "assistant".to_string(),
-            content: "Ok, any changes I propose will be to those files.".to_string(),
+            content: CHAT_FILES_REPLY.to_string(),
         });
     }
 
-    result_messages.extend(done_messages.into_iter().map(|m| Message {
-        role: m.role,
-        content: m.content,
-    }));
+    // Empty if the history already went in ahead of the files.
+    result_messages.append(&mut history);
     result_messages.extend(cur_messages.into_iter().map(|m| Message {
         role: m.role,
         content: m.content,
@@ -123,11 +406,113 @@ pub fn build_prompt_messages(
     Ok(result_messages)
 }
 
+/// Truncates the largest history messages until their contents total at most `max_bytes`,
+/// cutting each one down to the same size so no single message dominates. Truncated
+/// messages end with a short note saying how much was cut, which counts toward the limit;
+/// when even the notes wouldn't fit, messages are cut without them. Returns how many were
+/// truncated.
+pub fn cap_history_bytes(history: &mut [HistoryMessage], max_bytes: usize) -> usize {
+    let total: usize = history.iter().map(|m| m.content.len()).sum();
+    if total <= max_bytes {
+        return 0;
+    }
+    let note = |cut: usize| format!("\n[... {} bytes truncated by max_history_bytes]", cut);
+    let cut_at = |content: &str, cap: usize| {
+        let mut end = cap.min(content.len());
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        end
+    };
+    // A message is only cut when that makes it smaller, note included. Reserving the longest
+    // note it could get keeps the size monotonic in `cap` for the search below.
+    let keeps_whole = |content: &str, cap: usize, with_note: bool| {
+        let reserve = if with_note {
+            note(content.len()).len()
+        } else {
+            0
+        };
+        content.len() <= cap + reserve
+    };
+    // The size of a message cut down to `cap`, with its note if there is one.
+    let capped_len = |content: &str, cap: usize, with_note: bool| {
+        if keeps_whole(content, cap, with_note) {
+            return content.len();
+        }
+        let end = cut_at(content, cap);
+        end + if with_note {
+            note(content.len() - end).len()
+        } else {
+            0
+        }
+    };
+    let fits = |cap: usize, with_note: bool| {
+        history
+            .iter()
+            .map(|m| capped_len(&m.content, cap, with_note))
+            .sum::<usize>()
+            <= max_bytes
+    };
+    let with_note = fits(0, true);
+
+    // The largest per-message size that keeps the total within the limit.
+    let (mut low, mut high) = (
+        0,
+        history.iter().map(|m| m.content.len()).max().unwrap_or(0),
+    );
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if fits(mid, with_note) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    let mut truncated = 0;
+    for message in history
+        .iter_mut()
+        .filter(|m| !keeps_whole(&m.content, low, with_note))
+    {
+        let end = cut_at(&message.content, low);
+        let cut = message.content.len() - end;
+        message.content.truncate(end);
+        if with_note {
+            message.content.push_str(&note(cut));
+        }
+        truncated += 1;
+    }
+    truncated
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::db::HistoryMessage;
 
+    #[test]
+    fn test_system_prompt_matches_edit_format() {
+        for edit_format in [EditFormat::SearchReplaceAppend, EditFormat::SearchReplace] {
+            let overrides = PromptOverrides {
+                edit_format,
+                ..Default::default()
+            };
+            assert_eq!(check_edit_format(&overrides).unwrap(), Vec::<String>::new());
+        }
+
+        let overrides = PromptOverrides {
+            edit_format: EditFormat::SearchReplace,
+            system_prompt_template: Some("Use <<<<<<< APPEND blocks.".to_string()),
+            ..Default::default()
+        };
+        let problems = check_edit_format(&overrides).unwrap();
+        assert_eq!(problems.len(), 4);
+        assert_eq!(
+            problems[3],
+            "shows the `<<<<<<< APPEND` line of APPEND blocks, which the edit_format doesn't parse"
+        );
+    }
+
     #[test]
     fn test_build_prompt_messages() {
         let done_messages = vec![
@@ -148,7 +533,14 @@ mod tests {
             created_at: "".to_string(),
         }];
 
-        let messages = build_prompt_messages(done_messages, cur_messages, &[], &[]).unwrap();
+        let messages = build_prompt_messages(
+            done_messages,
+            cur_messages,
+            &[],
+            &[],
+            &PromptOverrides::default(),
+        )
+        .unwrap();
 
         assert!(!messages.is_empty());
 
@@ -169,6 +561,209 @@ mod tests {
         assert_eq!(messages[3].content, "current user message");
     }
 
+    #[test]
+    fn test_build_prompt_messages_with_overrides() {
+        let overrides = PromptOverrides {
+            read_only_files_prefix: Some("Reference material:".to_string()),
+            chat_files_prefix: None,
+            go_ahead_tip: Some("CUSTOM GO AHEAD TIP".to_string()),
+            overeager_prompt: Some("CUSTOM SCOPE RULE".to_string()),
+            system_prompt_template: None,
+            system_prompt: None,
+            context_position: ContextPosition::BeforeHistory,
+            edit_format: EditFormat::default(),
+        };
+        let read_write_files = vec![("rw.txt".to_string(), "rw content".to_string())];
+        let read_only_files = vec![("ro.txt".to_string(), "ro content".to_string())];
+
+        let messages = build_prompt_messages(
+            Vec::new(),
+            Vec::new(),
+            &read_write_files,
+            &read_only_files,
+            &overrides,
+        )
+        .unwrap();
+
+        assert!(messages[0].content.contains("CUSTOM GO AHEAD TIP"));
+        assert!(messages[0].content.contains("CUSTOM SCOPE RULE"));
+        assert!(!messages[0].content.contains(OVEREAGER_PROMPT));
+        assert!(messages[1].content.starts_with("Reference material:\n"));
+        // Unset overrides keep the default text.
+        assert!(messages[3].content.starts_with(CHAT_FILES_PREFIX));
+    }
+
+    #[test]
+    fn test_cap_history_bytes() {
+        let message = |content: &str| HistoryMessage {
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: "".to_string(),
+        };
+        let mut history = vec![
+            message("short"),
+            message(&"x".repeat(100)),
+            message(&"y".repeat(100)),
+        ];
+
+        assert_eq!(cap_history_bytes(&mut history, 1000), 0);
+        assert_eq!(history[1].content.len(), 100);
+
+        // Both long messages are cut to the same size, and their notes count toward the limit:
+        // 5 + (20 + 46) + (20 + 46) = 137.
+        let total =
+            |history: &[HistoryMessage]| -> usize { history.iter().map(|m| m.content.len()).sum() };
+        assert_eq!(cap_history_bytes(&mut history, 137), 2);
+        assert_eq!(history[0].content, "short");
+        assert_eq!(
+            history[1].content,
+            format!(
+                "{}\n[... 80 bytes truncated by max_history_bytes]",
+                "x".repeat(20)
+            )
+        );
+        assert_eq!(
+            history[2].content,
+            format!(
+                "{}\n[... 80 bytes truncated by max_history_bytes]",
+                "y".repeat(20)
+            )
+        );
+        assert!(total(&history) <= 137);
+
+        // Without room for the notes, the messages are cut without them.
+        let mut history = vec![message(&"x".repeat(100)), message(&"y".repeat(40))];
+        assert_eq!(cap_history_bytes(&mut history, 45), 2);
+        assert_eq!(history[0].content, "x".repeat(22));
+        assert_eq!(history[1].content, "y".repeat(22));
+        assert!(total(&history) <= 45);
+    }
+
+    #[test]
+    fn test_split_line_range() {
+        assert_eq!(
+            split_line_range("src/big.rs:120-180"),
+            Some(("src/big.rs", 120, 180))
+        );
+        assert_eq!(split_line_range("C:/big.rs:5-5"), Some(("C:/big.rs", 5, 5)));
+        assert_eq!(split_line_range("src/big.rs"), None);
+        assert_eq!(split_line_range("src/big.rs:0-10"), None);
+        assert_eq!(split_line_range("src/big.rs:20-10"), None);
+        assert_eq!(split_line_range(":1-2"), None);
+
+        let read_only_files = vec![("big.rs:2-3".to_string(), "two\nthree".to_string())];
+        let messages = build_prompt_messages(
+            Vec::new(),
+            Vec::new(),
+            &[],
+            &read_only_files,
+            &PromptOverrides::default(),
+        )
+        .unwrap();
+        assert!(messages[1]
+            .content
+            .contains("big.rs (lines 2-3 only)\n```\ntwo\nthree\n```"));
+    }
+
+    #[test]
+    fn test_compact_history_helpers() {
+        let message = |role: &str, content: &str| HistoryMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            created_at: "".to_string(),
+        };
+        let overrides = PromptOverrides::default();
+        let messages = build_prompt_messages(
+            Vec::new(),
+            Vec::new(),
+            &[("rw.txt".to_string(), "rw".to_string())],
+            &[("ro.txt".to_string(), "ro".to_string())],
+            &overrides,
+        )
+        .unwrap();
+        for scaffolding in &messages[1..] {
+            assert!(is_file_context_turn(
+                &message(&scaffolding.role, &scaffolding.content),
+                &overrides
+            ));
+        }
+        assert!(!is_file_context_turn(
+            &message("user", "fix the bug"),
+            &overrides
+        ));
+        assert!(!is_file_context_turn(
+            &message("assistant", "Ok"),
+            &overrides
+        ));
+
+        let long = format!("Here:\n```rust\n{}```\nDone.", "line\n".repeat(12));
+        assert_eq!(
+            collapse_code_blocks(&long, 10),
+            "Here:\n```rust\n[12 lines hidden]\n```\nDone."
+        );
+        let short = "```\na\nb\n```";
+        assert_eq!(collapse_code_blocks(short, 10), short);
+        let unclosed = "```\na\nb";
+        assert_eq!(collapse_code_blocks(unclosed, 1), unclosed);
+    }
+
+    #[test]
+    fn test_shipped_templates_render() {
+        for (name, result) in validate_templates() {
+            assert!(result.is_ok(), "{} failed: {:?}", name, result);
+        }
+    }
+
+    #[test]
+    fn test_malformed_template_error_names_the_file() {
+        let err = load_templates(&[("broken.j2", "{% if fence %}unclosed")])
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("Failed to load template prompts/broken.j2"));
+        assert!(err.contains("Check prompts/broken.j2 for broken Jinja syntax"));
+    }
+
+    #[test]
+    fn test_custom_system_prompt_template() {
+        let overrides = PromptOverrides {
+            system_prompt_template: Some(
+                "Plan only, do not edit.\n{% include '_shell_cmd_reminder.j2' %}".to_string(),
+            ),
+            ..Default::default()
+        };
+        let messages = build_prompt_messages(Vec::new(), Vec::new(), &[], &[], &overrides).unwrap();
+        assert!(messages[0].content.starts_with("Plan only, do not edit.\n"));
+        assert!(!messages[0]
+            .content
+            .contains("Act as an expert software developer."));
+
+        let broken = PromptOverrides {
+            system_prompt_template: Some("{% if fence %}unclosed".to_string()),
+            ..Default::default()
+        };
+        let err = build_prompt_messages(Vec::new(), Vec::new(), &[], &[], &broken)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("Check the chat's prompt_templates file for broken Jinja syntax"));
+    }
+
+    #[test]
+    fn test_system_prompt_skips_templates() {
+        let overrides = PromptOverrides {
+            system_prompt: Some("You are a {{ not a template }}.".to_string()),
+            system_prompt_template: Some("ignored".to_string()),
+            ..Default::default()
+        };
+        let read_only_files = vec![("ro.txt".to_string(), "ro content".to_string())];
+        let messages =
+            build_prompt_messages(Vec::new(), Vec::new(), &[], &read_only_files, &overrides)
+                .unwrap();
+        assert_eq!(messages[0].content, "You are a {{ not a template }}.");
+        assert!(messages[1].content.contains("ro content"));
+    }
+
     #[test]
     fn test_build_prompt_messages_with_files() {
         let done_messages = vec![HistoryMessage {
@@ -189,6 +784,7 @@ mod tests {
             cur_messages,
             &read_write_files,
             &read_only_files,
+            &PromptOverrides::default(),
         )
         .unwrap();
 
@@ -227,4 +823,68 @@ mod tests {
         assert_eq!(messages[6].role, "user");
         assert_eq!(messages[6].content, "current user message");
     }
+
+    #[test]
+    fn test_files_with_fences_get_a_longer_fence() {
+        let read_write_files = vec![(
+            "README.md".to_string(),
+            "## Usage\n```bash\nretort send\n```".to_string(),
+        )];
+        let read_only_files = vec![("notes.txt".to_string(), "plain".to_string())];
+
+        let messages = build_prompt_messages(
+            Vec::new(),
+            Vec::new(),
+            &read_write_files,
+            &read_only_files,
+            &PromptOverrides::default(),
+        )
+        .unwrap();
+
+        assert!(messages[0].content.contains("The closing fence: ````\n"));
+        assert!(messages[1]
+            .content
+            .contains("notes.txt\n````\nplain\n````\n"));
+        assert!(messages[3]
+            .content
+            .contains("README.md\n````\n## Usage\n```bash\nretort send\n```\n````\n"));
+    }
+
+    #[test]
+    fn test_context_before_last_user() {
+        let done_messages = vec![HistoryMessage {
+            role: "user".to_string(),
+            content: "previous user message".to_string(),
+            created_at: "".to_string(),
+        }];
+        let cur_messages = vec![HistoryMessage {
+            role: "user".to_string(),
+            content: "current user message".to_string(),
+            created_at: "".to_string(),
+        }];
+        let read_write_files = vec![("rw.txt".to_string(), "rw content".to_string())];
+        let overrides = PromptOverrides {
+            context_position: ContextPosition::BeforeLastUser,
+            ..Default::default()
+        };
+
+        let messages = build_prompt_messages(
+            done_messages,
+            cur_messages,
+            &read_write_files,
+            &[],
+            &overrides,
+        )
+        .unwrap();
+
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents.len(), 5);
+        assert_eq!(contents[1], "previous user message");
+        assert!(contents[2].starts_with(CHAT_FILES_PREFIX));
+        assert_eq!(
+            contents[3],
+            "Ok, any changes I propose will be to those files."
+        );
+        assert_eq!(contents[4], "current user message");
+    }
 }
@@ -2,10 +2,35 @@ use anyhow::Result;
 use assert_cmd::Command;
 use predicates::prelude::*;
 use std::fs;
-use tempfile::tempdir;
+use tempfile::{tempdir, TempDir};
 
 // CLI tests with fences are in cli_fence.rs because they break ai pair programming more often.
 
+/// Creates a HOME whose config points at `test.db` inside it, followed by `extra_config`.
+/// Returns the directory, which must be kept alive, and a function building a `retort`
+/// command with the given args that runs there.
+fn retort_env(extra_config: &str) -> (TempDir, impl Fn(&[&str]) -> Command) {
+    let temp_dir = tempdir().unwrap();
+    let home_dir = temp_dir.path().to_path_buf();
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir).unwrap();
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!(
+            "database_path: {}\n{}",
+            home_dir.join("test.db").to_str().unwrap(),
+            extra_config
+        ),
+    )
+    .unwrap();
+    let command = move |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("retort").unwrap();
+        cmd.args(args).current_dir(&home_dir).env("HOME", &home_dir);
+        cmd
+    };
+    (temp_dir, command)
+}
+
 #[test]
 fn test_list_chats_format_and_logic() -> Result<()> {
     let temp_dir = tempdir()?;
@@ -202,13 +227,17 @@ fn test_send_command() -> Result<()> {
         .assert()
         .success();
 
+    // The tag no longer points at a leaf after the branch, so this warns about forking.
     Command::cargo_bin("retort")?
         .arg("send")
         .arg("continue prompt")
         .env("HOME", home_dir)
         .env("MOCK_LLM", "1")
         .assert()
-        .success();
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: tag 'my-chat' points to message 2, which is not a leaf",
+        ));
 
     // Verify tag points to new message (id 6, since we added 2 in branch test, 2 here)
     {
@@ -217,6 +246,18 @@ fn test_send_command() -> Result<()> {
         assert_eq!(tagged_id, 6);
     }
 
+    // Continuing from the now-leaf tag does not warn.
+    Command::cargo_bin("retort")?
+        .arg("send")
+        .arg("--chat")
+        .arg("my-chat")
+        .arg("leaf prompt")
+        .env("HOME", home_dir)
+        .env("MOCK_LLM", "1")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Warning").not());
+
     // Test 3: retort send --new "..."
     Command::cargo_bin("retort")?
         .arg("send")
@@ -421,14 +462,14 @@ fn test_context_inheritance() -> Result<()> {
     Command::cargo_bin("retort")?
         .current_dir(temp_dir.path())
         .args(["stage", "file1.txt"])
-        .env("HOME", &home_dir)
+        .env("HOME", home_dir)
         .assert()
         .success();
 
     Command::cargo_bin("retort")?
         .current_dir(temp_dir.path())
         .args(["send", "--chat", "inherit-test", "msg1"])
-        .env("HOME", &home_dir)
+        .env("HOME", home_dir)
         .env("MOCK_LLM", "1")
         .assert()
         .success()
@@ -439,7 +480,7 @@ fn test_context_inheritance() -> Result<()> {
     // Set the active chat so `retort stage` can find the inherited context
     Command::cargo_bin("retort")?
         .args(["profile", "--active-chat", "inherit-test"])
-        .env("HOME", &home_dir)
+        .env("HOME", home_dir)
         .assert()
         .success();
 
@@ -447,7 +488,7 @@ fn test_context_inheritance() -> Result<()> {
     let expected_stage1 = "Final Context (for next message):\n  Read-Write:\n    - file1.txt\n\nInherited Context (from active chat):\n  Read-Write:\n    - file1.txt\n\nPrepared Context (delta for next message):\n  (empty)\n";
     Command::cargo_bin("retort")?
         .arg("stage")
-        .env("HOME", &home_dir)
+        .env("HOME", home_dir)
         .assert()
         .success()
         .stdout(predicate::str::diff(expected_stage1));
@@ -456,14 +497,14 @@ fn test_context_inheritance() -> Result<()> {
     Command::cargo_bin("retort")?
         .current_dir(temp_dir.path())
         .args(["stage", "file2.txt"])
-        .env("HOME", &home_dir)
+        .env("HOME", home_dir)
         .assert()
         .success();
 
     Command::cargo_bin("retort")?
         .current_dir(temp_dir.path())
         .args(["send", "--chat", "inherit-test", "msg2"])
-        .env("HOME", &home_dir)
+        .env("HOME", home_dir)
         .env("MOCK_LLM", "1")
         .assert()
         .success()
@@ -475,7 +516,7 @@ fn test_context_inheritance() -> Result<()> {
     Command::cargo_bin("retort")?
         .current_dir(temp_dir.path())
         .args(["stage", "file3.txt"])
-        .env("HOME", &home_dir)
+        .env("HOME", home_dir)
         .assert()
         .success();
 
@@ -488,7 +529,7 @@ fn test_context_inheritance() -> Result<()> {
             "--ignore-inherited-stage",
             "msg3",
         ])
-        .env("HOME", &home_dir)
+        .env("HOME", home_dir)
         .env("MOCK_LLM", "1")
         .assert()
         .success()
@@ -570,6 +611,14 @@ fn test_stage_command() -> Result<()> {
         .success()
         .stdout(predicate::str::diff(expected_final));
 
+    // 7. Read-only and drop can't be combined.
+    Command::cargo_bin("retort")?
+        .args(["stage", "file2.txt", "-r", "-d"])
+        .env("HOME", home_dir)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+
     Ok(())
 }
 
@@ -645,6 +694,129 @@ fn test_send_confirm_flow() -> Result<()> {
     let leaves = retort::db::get_leaf_messages(&conn)?;
     assert_eq!(leaves.len(), 2);
 
+    // Test 4: 'yes' is accepted, after re-asking on an unrecognized answer
+    let mut cmd = Command::cargo_bin("retort")?;
+    cmd.args(["send", "--new", "--confirm", "third prompt"])
+        .env("HOME", home_dir)
+        .env("MOCK_LLM", "1");
+    cmd.write_stdin("maybe\nyes\n");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Please answer 'y' or 'n'."))
+        .stdout(predicate::str::contains("Added user message with ID: 5"));
+
+    // Test 5: 'no' aborts
+    let mut cmd = Command::cargo_bin("retort")?;
+    cmd.args(["send", "--new", "--confirm", "fourth prompt"])
+        .env("HOME", home_dir)
+        .env("MOCK_LLM", "1");
+    cmd.write_stdin("no\n");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Aborted."));
+
+    let leaves = retort::db::get_leaf_messages(&conn)?;
+    assert_eq!(leaves.len(), 3);
+
+    // Test 6: with confirm_default false, Enter aborts and the prompt says so
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!(
+            "database_path: {}\nconfirm_default: false",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+    let mut cmd = Command::cargo_bin("retort")?;
+    cmd.args(["send", "--new", "--confirm", "fifth prompt"])
+        .env("HOME", home_dir)
+        .env("MOCK_LLM", "1");
+    cmd.write_stdin("\n");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Send Message? [y/N]"))
+        .stdout(predicate::str::contains("Aborted."));
+
+    let leaves = retort::db::get_leaf_messages(&conn)?;
+    assert_eq!(leaves.len(), 3);
+
+    // Test 7: the global --yes answers for you, even against the default, without reading stdin
+    let mut cmd = Command::cargo_bin("retort")?;
+    cmd.args(["--yes", "send", "--new", "--confirm", "sixth prompt"])
+        .env("HOME", home_dir)
+        .env("MOCK_LLM", "1");
+    cmd.write_stdin("n\n");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Send Message? [y/N] y (--yes)"))
+        .stdout(predicate::str::contains("Added user message with ID: 7"));
+
+    let leaves = retort::db::get_leaf_messages(&conn)?;
+    assert_eq!(leaves.len(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_send_confirm_abort_keeps_stage_and_tag() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    fs::write(home_dir.join("notes.txt"), "notes")?;
+
+    let retort = |args: &[&str], stdin: &str| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM_CONTENT", "STREAMED REPLY")
+            .write_stdin(stdin)
+            .assert())
+    };
+
+    retort(&["send", "--chat", "work", "first"], "")?.success();
+    retort(&["stage", "notes.txt"], "")?.success();
+
+    // Declining a streamed send shows the preview, then the question, and nothing after.
+    let output = retort(
+        &["send", "--chat", "work", "--stream", "--confirm", "second"],
+        "n\n",
+    )?
+    .success()
+    .get_output()
+    .stdout
+    .clone();
+    let stdout = String::from_utf8(output)?;
+    let preview = stdout.find("--- PROMPT PREVIEW ---").unwrap();
+    let question = stdout.find("Send Message?").unwrap();
+    let aborted = stdout.find("Aborted.").unwrap();
+    assert!(preview < question && question < aborted, "{}", stdout);
+    assert_eq!(stdout.matches("STREAMED REPLY").count(), 1, "{}", stdout);
+    assert!(!stdout.contains("Added user message"), "{}", stdout);
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert!(retort::db::get_message(&conn, 3)?.is_none());
+    assert_eq!(retort::db::get_message_id_by_tag(&conn, "work")?, Some(2));
+    let stage = retort::db::get_context_stage(&conn, "default")?;
+    assert_eq!(stage.read_write_files, vec!["notes.txt".to_string()]);
+
+    // Accepting streams the reply only after the question.
+    let output = retort(
+        &["send", "--chat", "work", "--stream", "--confirm", "second"],
+        "y\n",
+    )?
+    .success()
+    .get_output()
+    .stdout
+    .clone();
+    let stdout = String::from_utf8(output)?;
+    let question = stdout.find("Send Message?").unwrap();
+    let reply = stdout.rfind("STREAMED REPLY").unwrap();
+    assert!(question < reply, "{}", stdout);
+    assert!(retort::db::get_context_stage(&conn, "default")?
+        .read_write_files
+        .is_empty());
+
     Ok(())
 }
 
@@ -703,3 +875,2330 @@ fn test_send_editor_flow() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_import_command() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    // Pre-existing messages occupy IDs 1 and 2, so imported IDs must be remapped.
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let u1 = retort::db::add_message(&conn, None, "user", "existing", None)?;
+        retort::db::add_message(&conn, Some(u1), "assistant", "existing reply", None)?;
+    }
+
+    // Children listed before their parents must still import correctly.
+    let dump_path = home_dir.join("dump.json");
+    fs::write(
+        &dump_path,
+        r#"{
+            "messages": [
+                {"id": 2, "parent_id": 1, "role": "assistant", "content": "imported reply"},
+                {"id": 1, "parent_id": null, "role": "user", "content": "imported question"}
+            ],
+            "tags": [{"tag": "imported", "message_id": 2}]
+        }"#,
+    )?;
+
+    command(&["import", dump_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Imported 2 messages and 1 tags."));
+
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let tagged_id = retort::db::get_message_id_by_tag(&conn, "imported")?.unwrap();
+        assert_eq!(tagged_id, 4);
+        let history = retort::db::get_conversation_history(&conn, tagged_id)?;
+        let contents: Vec<&str> = history.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["imported question", "imported reply"]);
+    }
+
+    // Invalid roles are rejected and nothing is imported.
+    fs::write(
+        &dump_path,
+        r#"{"messages": [{"id": 1, "role": "robot", "content": "beep"}]}"#,
+    )?;
+    command(&["import", dump_path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Invalid role 'robot' for message 1 in import.",
+        ));
+
+    // Unknown parents are rejected.
+    fs::write(
+        &dump_path,
+        r#"{"messages": [{"id": 1, "parent_id": 7, "role": "user", "content": "orphan"}]}"#,
+    )?;
+    command(&["import", dump_path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Message 1 references unknown parent 7 in import.",
+        ));
+
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        assert!(!retort::db::message_exists(&conn, 5)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_send_context_from() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    let _conn = retort::db::setup(db_path.to_str().unwrap())?;
+
+    fs::write(home_dir.join("file1.txt"), "content1")?;
+    fs::write(home_dir.join("file2.txt"), "content2")?;
+    fs::write(home_dir.join("file3.txt"), "content3")?;
+
+    // 1. Send a message with file1 (read-write) and file2 (read-only).
+    for args in [vec!["stage", "file1.txt"], vec!["stage", "-r", "file2.txt"]] {
+        command(&args).assert().success();
+    }
+    command(&["send", "--new", "first question"])
+        .env("MOCK_LLM", "1")
+        .assert()
+        .success();
+
+    // 2. Stage file3, then reuse message 2's context. The stage should be bypassed.
+    command(&["stage", "file3.txt"]).assert().success();
+    command(&["send", "--new", "--context-from", "2", "same question"])
+        .env("MOCK_LLM", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "CONTEXT (for this message):\n  Read-Write:\n    - file1.txt\n  Read-Only:\n    - file2.txt\n---",
+        ));
+
+    // The unused stage is kept for the next message.
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let stage = retort::db::get_context_stage(&conn, "default")?;
+        assert_eq!(stage.read_write_files, vec!["file3.txt"]);
+    }
+
+    // 3. A nonexistent message is an error.
+    command(&["send", "--new", "--context-from", "99", "oops"])
+        .env("MOCK_LLM", "1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Message with ID '99' not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_tag_show_command() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let u1 = retort::db::add_message(&conn, None, "user", "first line\nsecond line", None)?;
+        retort::db::add_message(&conn, Some(u1), "assistant", "reply", None)?;
+        retort::db::set_chat_tag(&conn, "mid-tree", u1)?;
+    }
+
+    command(&["tag", "show", "mid-tree"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Message ID: 1\n"))
+        .stdout(predicate::str::contains("Role:       user\n"))
+        .stdout(predicate::str::contains("Leaf:       no\n"))
+        .stdout(predicate::str::contains(
+            "Preview:    first line second line\n",
+        ));
+
+    command(&["tag", "show", "nonexistent"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Tag 'nonexistent' not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_tag_set_descendant_only() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    // 1 -> 2 -> 3, with 4 branching off 2.
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let u1 = retort::db::add_message(&conn, None, "user", "one", None)?;
+        let a1 = retort::db::add_message(&conn, Some(u1), "assistant", "1", None)?;
+        retort::db::add_message(&conn, Some(a1), "user", "two", None)?;
+        retort::db::add_message(&conn, Some(a1), "user", "other two", None)?;
+        retort::db::set_chat_tag(&conn, "head", a1)?;
+    }
+
+    command(&["tag", "set", "head", "-m", "3", "--descendant-only"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Moved tag 'head' from message 2 to 3.",
+        ));
+    command(&["tag", "set", "head", "-m", "1", "--descendant-only"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Message 1 is an ancestor of message 3, which tag 'head' points to; pass --force to move the tag anyway.",
+        ));
+    command(&["tag", "set", "head", "-m", "4", "--move-head"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Message 4 is not a descendant of message 3",
+        ));
+    command(&["tag", "set", "head", "-m", "1", "--force"])
+        .assert()
+        .failure();
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        assert_eq!(retort::db::get_message_id_by_tag(&conn, "head")?, Some(3));
+    }
+
+    command(&[
+        "tag",
+        "set",
+        "head",
+        "-m",
+        "1",
+        "--descendant-only",
+        "--force",
+    ])
+    .assert()
+    .success()
+    .stdout(predicate::str::contains(
+        "Moved tag 'head' from message 3 to 1.",
+    ));
+    // A new tag has no head to move forward from.
+    command(&["tag", "set", "fresh", "-m", "4", "--descendant-only"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Tagged message 4 with 'fresh'"));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_stop_sequences() -> Result<()> {
+    let (temp_dir, command) = retort_env("stop_sequences:\n  - \"<END>\"\n");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    // 1. Non-streamed, using the configured stop sequence.
+    command(&["send", "--new", "first"])
+        .env("MOCK_LLM_CONTENT", "the answer<END>rambling on")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("rambling").not());
+
+    // 2. Streamed, with --stop overriding the config.
+    command(&["send", "--new", "--stream", "--stop", "STOP", "second"])
+        .env("MOCK_LLM_CONTENT", "another answer<END>STOP more rambling")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("rambling").not());
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let first = retort::db::get_message(&conn, 2)?.unwrap();
+    assert_eq!(first.content, "the answer");
+    let second = retort::db::get_message(&conn, 4)?.unwrap();
+    assert_eq!(second.content, "another answer<END>");
+
+    Ok(())
+}
+
+#[test]
+fn test_send_response_filters() -> Result<()> {
+    let (temp_dir, command) = retort_env("response_filters:\n  - pattern: '^Preamble\\n'\n  - pattern: '<code lang=\"(\\w+)\">'\n    replacement: '```$1'\n");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    let config_path = home_dir.join(".retort").join("config.yaml");
+
+    command(&["send", "--new", "hi"])
+        .env(
+            "MOCK_LLM_CONTENT",
+            "Preamble\n<code lang=\"rust\">\nfn main() {}",
+        )
+        .assert()
+        .success();
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let response = retort::db::get_message(&conn, 2)?.unwrap();
+    assert_eq!(response.content, "```rust\nfn main() {}");
+
+    // A bad pattern is reported before anything is sent.
+    fs::write(
+        &config_path,
+        format!(
+            "database_path: {}\nresponse_filters:\n  - pattern: '(unclosed'\n",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+    command(&["send", "--new", "again"])
+        .env("MOCK_LLM_CONTENT", "unused")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Invalid response_filters pattern '(unclosed'",
+        ));
+    assert!(retort::db::get_message(&conn, 3)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_models_command() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    // Google has no model-list API in the llm crate, so the known set is printed.
+    command(&["models"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("gemini-2.5-flash\n"));
+
+    // Backends with a list API need their key.
+    command(&["models", "--backend", "openai"])
+        .env_remove("OPENAI_API_KEY")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("OPENAI_API_KEY not set."));
+
+    command(&["models", "--backend", "nope"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Unknown LLM backend: nope"));
+
+    Ok(())
+}
+
+#[test]
+fn test_debug_schema_command() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        retort::db::add_message(&conn, None, "user", "hello", None)?;
+    }
+
+    command(&["debug", "schema"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("user_version: 0"))
+        .stdout(predicate::str::contains("CREATE TABLE messages"))
+        .stdout(predicate::str::contains(
+            "CREATE INDEX idx_messages_parent_id",
+        ))
+        .stdout(predicate::str::is_match(r"\n  messages +1\n")?)
+        .stdout(predicate::str::is_match(r"\n  profiles +1\n")?);
+
+    // The debug command is hidden from the help output.
+    command(&["--help"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("debug").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_send_estimate() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    let config_path = home_dir.join(".retort").join("config.yaml");
+
+    // Without a rate, only the size is reported. No LLM mock is set, so this would fail if it sent.
+    command(&["send", "--new", "--estimate", "how big is this?"])
+        .env_remove("GOOGLE_API_KEY")
+        .env_remove("GEMINI_API_KEY")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Estimated prompt size:"))
+        .stdout(predicate::str::contains(
+            "Set input_cost_per_million_tokens in the config to estimate cost.",
+        ))
+        .stdout(predicate::str::contains("Nothing was sent."));
+
+    fs::write(
+        &config_path,
+        format!(
+            "database_path: {}\ninput_cost_per_million_tokens: 2.5\n",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+    command(&["send", "--new", "--estimate", "how big is this?"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Estimated input cost: $"))
+        .stdout(predicate::str::contains("(at $2.5 per million tokens)"));
+
+    // Nothing was written to the database.
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert!(retort::db::get_leaf_messages(&conn)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_send_custom_endpoint_config() -> Result<()> {
+    let (temp_dir, command) = retort_env("backend: openai\nmodel: local-model\nbase_url: http://127.0.0.1:9/v1/\napi_key_env: RETORT_TEST_MISSING_KEY\n");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    // The configured key variable is used instead of OPENAI_API_KEY.
+    command(&["send", "--new", "--no-stream", "hello"])
+        .env("OPENAI_API_KEY", "unused")
+        .env_remove("RETORT_TEST_MISSING_KEY")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("RETORT_TEST_MISSING_KEY not set."));
+
+    // An unknown backend is reported clearly.
+    fs::write(
+        home_dir.join(".retort").join("config.yaml"),
+        format!(
+            "database_path: {}\nbackend: nope\n",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+    command(&["send", "--new", "--no-stream", "hello"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Unknown LLM backend: nope"));
+
+    Ok(())
+}
+
+#[test]
+fn test_models_custom_endpoint_config() -> Result<()> {
+    let (_temp_dir, command) = retort_env(
+        "backend: openai\nbase_url: http://127.0.0.1:9/v1/\napi_key_env: RETORT_TEST_MODELS_KEY\n",
+    );
+
+    // The configured key variable is used instead of OPENAI_API_KEY.
+    command(&["models"])
+        .env("OPENAI_API_KEY", "unused")
+        .env_remove("RETORT_TEST_MODELS_KEY")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("RETORT_TEST_MODELS_KEY not set."));
+
+    // The model list is requested from the configured server, not api.openai.com.
+    command(&["models", "--backend", "openai"])
+        .env("RETORT_TEST_MODELS_KEY", "test-key")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("http://127.0.0.1:9/v1/models"));
+
+    Ok(())
+}
+
+#[test]
+fn test_doctor_command() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    command(&["doctor"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "ok      prompts/_diff_fenced_system_prompt.j2",
+        ))
+        .stdout(predicate::str::contains("ok      prompts/diff_fenced.j2"))
+        .stdout(predicate::str::contains("FAILED").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_doctor_edit_format_probe() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    let template_path = home_dir.join("stale.j2");
+    fs::write(
+        &template_path,
+        "Edit with {{ edit_format.search }}, {{ edit_format.divider }} and {{ edit_format.replace }}.\nAppend with <<<<<<< APPEND.",
+    )?;
+
+    let config_dir = home_dir.join(".retort");
+    let write_config = |edit_format: &str| {
+        fs::write(
+            config_dir.join("config.yaml"),
+            format!(
+                "database_path: {}\nedit_format: {}\nprompt_templates:\n  stale: {}\n",
+                db_path.to_str().unwrap(),
+                edit_format,
+                template_path.to_str().unwrap()
+            ),
+        )
+    };
+    let doctor = || -> Result<assert_cmd::assert::Assert> {
+        Ok(command(&["doctor", "--edit-format-probe"]).assert())
+    };
+
+    // The template hardcodes an APPEND marker the format doesn't parse.
+    write_config("search_replace")?;
+    doctor()?
+        .failure()
+        .stdout(predicate::str::contains(
+            "The edit format has SEARCH/REPLACE blocks.",
+        ))
+        .stdout(predicate::str::contains(
+            "ok      the parser reads an example of each block",
+        ))
+        .stdout(predicate::str::contains("ok      the default prompt describes them"))
+        .stdout(predicate::str::contains(
+            "FAILED  the stale prompt\n  shows the `<<<<<<< APPEND` line of APPEND blocks, which the edit_format doesn't parse",
+        ))
+        .stderr(predicate::str::contains("1 edit format check(s) failed."));
+
+    // With APPEND blocks in the format, the same template is missing their end marker.
+    write_config("search_replace_append")?;
+    doctor()?
+        .failure()
+        .stdout(predicate::str::contains(
+            "The edit format has SEARCH/REPLACE and APPEND blocks.",
+        ))
+        .stdout(predicate::str::contains(
+            "  doesn't show the `>>>>>>> APPEND` line of APPEND blocks",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_pager() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let paged_path = home_dir.join("paged.txt");
+
+    for stream_flag in ["--stream", "--no-stream"] {
+        // The response goes to the pager, while the banner and IDs stay on stdout.
+        command(&["send", "--new", "--pager", stream_flag, "explain"])
+            .env("PAGER", format!("cat > {}", paged_path.display()))
+            .env("MOCK_LLM_CONTENT", "a very long explanation")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("CONTEXT (for this message):"))
+            .stdout(predicate::str::contains("Added assistant message with ID"))
+            .stdout(predicate::str::contains("a very long explanation").not());
+
+        assert_eq!(fs::read_to_string(&paged_path)?, "a very long explanation");
+    }
+
+    // A pager that can't be run still leaves the response on screen.
+    command(&["send", "--new", "--pager", "explain"])
+        .env("PAGER", "retort-test-missing-pager")
+        .env("MOCK_LLM_CONTENT", "a very long explanation")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("a very long explanation"))
+        .stderr(predicate::str::contains(
+            "Warning: pager 'retort-test-missing-pager' failed",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_context_preset_commands() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    let retort =
+        |args: &[&str]| -> Result<assert_cmd::assert::Assert> { Ok(command(args).assert()) };
+
+    retort(&["context", "save", "empty"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "The stage is empty; nothing to save.",
+        ));
+
+    retort(&["stage", "src/lib.rs"])?.success();
+    retort(&["stage", "-r", "README.md"])?.success();
+    retort(&["context", "save", "backend-work"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Saved preset 'backend-work' with 2 file(s).",
+        ));
+    retort(&["context", "list"])?
+        .success()
+        .stdout(predicate::str::contains("backend-work"))
+        .stdout(predicate::str::contains("src/lib.rs, README.md (ro)"));
+
+    // Send a message so the stage is cleared, then load the preset back.
+    command(&["send", "--new", "hi"])
+        .env("MOCK_LLM", "1")
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .assert()
+        .success();
+    retort(&["context", "load", "backend-work"])?
+        .success()
+        .stdout(predicate::str::contains("Staged src/lib.rs as read-write."))
+        .stdout(predicate::str::contains("Staged README.md as read-only."));
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let stage = retort::db::get_context_stage(&conn, "default")?;
+    assert_eq!(stage.read_write_files, vec!["src/lib.rs"]);
+    assert_eq!(stage.read_only_files, vec!["README.md"]);
+
+    retort(&["context", "load", "missing"])?
+        .failure()
+        .stderr(predicate::str::contains("Preset 'missing' not found."));
+    retort(&["context", "delete", "backend-work"])?
+        .success()
+        .stdout(predicate::str::contains("Deleted preset 'backend-work'."));
+    retort(&["context", "list"])?
+        .success()
+        .stdout(predicate::str::contains("No presets found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_context_diff() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    for name in ["a.txt", "b.txt", "shared.txt", "same.txt"] {
+        fs::write(home_dir.join(name), name)?;
+    }
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    for args in [
+        vec!["stage", "a.txt"],
+        vec!["stage", "shared.txt"],
+        vec!["stage", "-r", "same.txt"],
+        vec!["send", "--chat", "left", "one"],
+        vec!["stage", "b.txt"],
+        vec!["stage", "-r", "shared.txt"],
+        vec!["stage", "-r", "same.txt"],
+        vec!["send", "--chat", "right", "two"],
+    ] {
+        retort(&args)?.success();
+    }
+
+    retort(&["context", "diff", "left", "right"])?
+        .success()
+        .stdout(
+            "- RW a.txt\n+ RW b.txt\n~ shared.txt RW -> RO\n1 added, 1 removed, 1 changed mode.\n",
+        );
+    // Message 2 is the head of 'left'.
+    retort(&["context", "diff", "2", "left"])?
+        .success()
+        .stdout("'2' and 'left' have the same context.\n");
+    retort(&["context", "diff", "left", "nope"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "'nope' is neither a tag nor a message ID.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_history_colors() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let u1 = retort::db::add_message(&conn, None, "user", "hi", None)?;
+        retort::db::set_chat_tag(&conn, "chat1", u1)?;
+    }
+
+    // Piped output is plain unless color is forced.
+    command(&["history", "chat1"])
+        .env("CLICOLOR_FORCE", "1")
+        .env_remove("NO_COLOR")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\x1b["))
+        .stdout(predicate::str::contains("[user]"));
+
+    command(&["history", "chat1"])
+        .env_remove("CLICOLOR_FORCE")
+        .assert()
+        .success()
+        .stdout(predicate::str::diff("[user]\nhi\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_parent_of() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let u1 = retort::db::add_message(&conn, None, "user", "first", None)?;
+    let a1 = retort::db::add_message(&conn, Some(u1), "assistant", "first reply", None)?;
+    let u2 = retort::db::add_message(&conn, Some(a1), "user", "second", None)?;
+    let a2 = retort::db::add_message(&conn, Some(u2), "assistant", "second reply", None)?;
+    retort::db::set_chat_tag(&conn, "chat1", a2)?;
+
+    // Redoing the latest turn branches from the previous assistant reply.
+    command(&["send", "--parent-of", "chat1", "second, reworded"])
+        .env("MOCK_LLM", "1")
+        .assert()
+        .success();
+
+    let new_user = retort::db::get_message(&conn, a2 + 1)?.unwrap();
+    assert_eq!(new_user.content, "second, reworded");
+    assert_eq!(new_user.parent_id, Some(a1));
+    // The tag is left alone.
+    assert_eq!(retort::db::get_message_id_by_tag(&conn, "chat1")?, Some(a2));
+
+    // When the tag is on the first turn, the redo starts a new root.
+    retort::db::set_chat_tag(&conn, "chat2", a1)?;
+    command(&["send", "--parent-of", "chat2", "first, reworded"])
+        .env("MOCK_LLM", "1")
+        .assert()
+        .success();
+    let new_root = retort::db::get_message(&conn, a2 + 3)?.unwrap();
+    assert_eq!(new_root.content, "first, reworded");
+    assert_eq!(new_root.parent_id, None);
+
+    command(&["send", "--parent-of", "missing", "hi"])
+        .env("MOCK_LLM", "1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Tag 'missing' not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_skips_files_over_size_limit() -> Result<()> {
+    let (temp_dir, command) = retort_env("max_staged_file_bytes: 10\n");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    fs::write(home_dir.join("small.txt"), "tiny")?;
+    fs::write(home_dir.join("huge.log"), "x".repeat(100))?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["stage", "small.txt"])?.success();
+    // Staging a large file warns, since it may still shrink before sending.
+    retort(&["stage", "huge.log"])?
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: huge.log is 100 bytes, over max_staged_file_bytes of 10",
+        ));
+
+    retort(&["send", "--new", "look"])?
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: skipping huge.log (100 bytes exceeds max_staged_file_bytes of 10).",
+        ))
+        .stdout(predicate::str::contains("- small.txt"))
+        .stdout(predicate::str::contains("huge.log").not());
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let metadata = retort::db::get_message_metadata(&conn, 1)?.unwrap();
+    assert!(metadata.contains("small.txt"));
+    assert!(!metadata.contains("huge.log"));
+
+    Ok(())
+}
+
+#[test]
+fn test_stage_mv_command() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    // 1. A prepared file keeps its mode and position.
+    retort(&["stage", "a.txt"])?.success();
+    retort(&["stage", "-r", "b.txt"])?.success();
+    retort(&["stage", "c.txt"])?.success();
+    retort(&["stage", "mv", "a.txt", "renamed.txt"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Renamed a.txt to renamed.txt (read-write).",
+        ));
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let stage = retort::db::get_context_stage(&conn, "default")?;
+    assert_eq!(stage.read_write_files, vec!["renamed.txt", "c.txt"]);
+    assert_eq!(stage.read_only_files, vec!["b.txt"]);
+
+    // 2. An inherited file is dropped and the new path staged with the same mode.
+    retort(&["stage", "-d", "renamed.txt"])?.success();
+    retort(&["stage", "-d", "c.txt"])?.success();
+    fs::write(home_dir.join("b.txt"), "b")?;
+    retort(&["send", "--chat", "mv-chat", "hi"])?.success();
+    retort(&["profile", "--active-chat", "mv-chat"])?.success();
+    retort(&["stage", "mv", "b.txt", "docs/b.txt"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Renamed b.txt to docs/b.txt (read-only).",
+        ));
+    let stage = retort::db::get_context_stage(&conn, "default")?;
+    assert_eq!(stage.read_only_files, vec!["docs/b.txt"]);
+    assert_eq!(stage.dropped_files, vec!["b.txt"]);
+
+    // 3. Unknown paths are an error.
+    retort(&["stage", "mv", "nope.txt", "other.txt"])?
+        .failure()
+        .stderr(predicate::str::contains("nope.txt is not in the context."));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_inherits_context_from_user_head() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    fs::write(home_dir.join("notes.txt"), "notes")?;
+
+    // A chat whose head is a user root holding its own context, e.g. from an import.
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let metadata = r#"{"read_write_files":[],"read_only_files":[{"path":"notes.txt","hash":"x"}]}"#;
+    let root = retort::db::add_message(&conn, None, "user", "read this", Some(metadata))?;
+    retort::db::set_chat_tag(&conn, "user-head", root)?;
+
+    command(&["profile", "--active-chat", "user-head"])
+        .assert()
+        .success();
+    command(&["stage"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Inherited Context (from active chat):\n  Read-Only:\n    - notes.txt\n",
+        ));
+
+    command(&["send", "--chat", "user-head", "and now?"])
+        .env("MOCK_LLM", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("  Read-Only:\n    - notes.txt\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_default_stage_read_only() -> Result<()> {
+    let (temp_dir, command) = retort_env("default_stage_read_only: true");
+    let home_dir = temp_dir.path();
+    fs::write(home_dir.join("ref.txt"), "ref")?;
+    fs::write(home_dir.join("target.txt"), "target")?;
+
+    let retort =
+        |args: &[&str]| -> Result<assert_cmd::assert::Assert> { Ok(command(args).assert()) };
+
+    retort(&["stage", "ref.txt"])?
+        .success()
+        .stdout(predicate::str::contains("Staged ref.txt as read-only."));
+    retort(&["stage", "--rw", "target.txt"])?
+        .success()
+        .stdout(predicate::str::contains("Staged target.txt as read-write."));
+    retort(&["stage", "-r", "--rw", "target.txt"])?.failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_force_read_only_patterns() -> Result<()> {
+    let (temp_dir, command) =
+        retort_env("force_read_only_patterns:\n  - '*.lock'\n  - 'vendor/**'\n");
+    let home_dir = temp_dir.path();
+    fs::create_dir_all(home_dir.join("sub"))?;
+    fs::create_dir_all(home_dir.join("vendor/dep/src"))?;
+    fs::write(home_dir.join("Cargo.lock"), "lock")?;
+    fs::write(home_dir.join("sub/yarn.lock"), "lock")?;
+    fs::write(home_dir.join("vendor/dep/src/lib.rs"), "dep")?;
+    fs::write(home_dir.join("lock.rs"), "code")?;
+
+    let retort =
+        |args: &[&str]| -> Result<assert_cmd::assert::Assert> { Ok(command(args).assert()) };
+
+    retort(&["stage", "Cargo.lock"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Cargo.lock matches force_read_only_patterns entry '*.lock'; pass --rw to stage it read-write.",
+        ))
+        .stdout(predicate::str::contains("Staged Cargo.lock as read-only."));
+    retort(&["stage", "sub/yarn.lock"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Staged sub/yarn.lock as read-only.",
+        ));
+    retort(&["stage", "./vendor/dep/src/lib.rs"])?
+        .success()
+        .stdout(predicate::str::contains("entry 'vendor/**'; pass --rw"))
+        .stdout(predicate::str::contains("as read-only."));
+    retort(&["stage", "lock.rs"])?
+        .success()
+        .stdout(predicate::str::contains("force_read_only_patterns").not())
+        .stdout(predicate::str::contains("Staged lock.rs as read-write."));
+
+    // --rw is an explicit choice, so it wins.
+    retort(&["stage", "--rw", "Cargo.lock"])?
+        .success()
+        .stdout(predicate::str::contains("force_read_only_patterns").not())
+        .stdout(predicate::str::contains("Staged Cargo.lock as read-write."));
+
+    Ok(())
+}
+
+#[test]
+fn test_stage_status_porcelain() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    fs::write(home_dir.join("inherited.txt"), "hello")?;
+    fs::write(home_dir.join("old.txt"), "old")?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["stage", "inherited.txt"])?.success();
+    retort(&["stage", "-r", "old.txt"])?.success();
+    retort(&["send", "--chat", "status-chat", "hi"])?.success();
+    retort(&["profile", "--active-chat", "status-chat"])?.success();
+    retort(&["stage", "-r", "missing.txt"])?.success();
+    retort(&["stage", "-d", "old.txt"])?.success();
+
+    // sha256("hello") and sha256("old")
+    let hello_hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    let old_hash = "cba06b5736faf67e54b07b561eae94395e774c517a7d910a54369e1263ccfbd4";
+    let expected = format!(
+        "RW\tinherited\texists\t{}\tinherited.txt\nRO\tprepared\tmissing\t-\tmissing.txt\n-\tdropped\texists\t{}\told.txt\n",
+        hello_hash, old_hash
+    );
+    retort(&["stage", "status", "--porcelain"])?
+        .success()
+        .stdout(predicate::str::diff(expected));
+
+    // Without --porcelain, status is the usual human-readable view.
+    retort(&["stage", "status"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Final Context (for next message):",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_stage_from_stdin() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort = |args: &[&str], stdin: &str| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM", "1")
+            .write_stdin(stdin)
+            .assert())
+    };
+
+    // 1. Stdin needs a name.
+    retort(&["stage", "-"], "error: boom\n")?
+        .failure()
+        .stderr(predicate::str::contains("needs --name"));
+
+    // 2. Piped content is staged as a read-only entry.
+    retort(&["stage", "-", "--name", "build.log"], "error: boom\n")?
+        .success()
+        .stdout(predicate::str::contains(
+            "Staged 12 bytes from stdin as build.log (read-only).",
+        ));
+    retort(&["stage"], "")?
+        .success()
+        .stdout(predicate::str::contains(
+            "Stdin (read-only):\n    - build.log",
+        ));
+    // sha256("error: boom\n")
+    let hash = "7905ee81163085709d602eadd2030ba81186edc1dbcc785aad5c436df32f2a43";
+    retort(&["stage", "status", "--porcelain"], "")?
+        .success()
+        .stdout(predicate::str::diff(format!(
+            "RO\tstdin\tinline\t{}\tbuild.log\n",
+            hash
+        )));
+
+    // 3. It is sent as a read-only block, then cleared with the rest of the stage.
+    retort(&["send", "--new", "--confirm", "why?"], "y\n")?
+        .success()
+        .stdout(predicate::str::contains("Stdin (read-only):"))
+        .stdout(predicate::str::contains("build.log\n```\nerror: boom\n"));
+    retort(&["stage", "status", "--porcelain"], "")?
+        .success()
+        .stdout(predicate::str::is_empty());
+
+    // 4. Dropping the name removes the entry.
+    retort(&["stage", "-", "--name", "build.log"], "again\n")?.success();
+    retort(&["stage", "-d", "build.log"], "")?.success();
+    retort(&["stage"], "")?
+        .success()
+        .stdout(predicate::str::contains("Stdin (read-only):").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_send_assistant_prefill() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    let config_dir = home_dir.join(".retort");
+    let write_config = |backend: &str| {
+        fs::write(
+            config_dir.join("config.yaml"),
+            format!(
+                "database_path: {}\nbackend: {}\n",
+                db_path.to_str().unwrap(),
+                backend
+            ),
+        )
+    };
+
+    // 1. A backend with prefill support starts the response with the prefill.
+    write_config("anthropic")?;
+    for stream_flag in ["--stream", "--no-stream"] {
+        command(&[
+            "send",
+            "--new",
+            stream_flag,
+            "--assistant-prefill",
+            "src/main.rs\n",
+            "edit it",
+        ])
+        .env("MOCK_LLM_CONTENT", "<<<<<<< SEARCH")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("src/main.rs\n<<<<<<< SEARCH"));
+    }
+    command(&["history", "-m", "2"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("src/main.rs\n<<<<<<< SEARCH"));
+
+    // 2. Other backends warn and send without it.
+    write_config("google")?;
+    command(&["send", "--new", "--assistant-prefill", "prefix ", "hello"])
+        .env("MOCK_LLM", "1")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: backend 'google' doesn't support --assistant-prefill",
+        ))
+        .stdout(predicate::str::contains("prefix This").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_title_command() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--chat", "refactor", "first question"])?.success();
+    retort(&["send", "--chat", "refactor", "second question"])?.success();
+
+    retort(&["title", "refactor"])?
+        .success()
+        .stdout(predicate::str::diff("Title: (none)\nNotes: (none)\n"));
+
+    retort(&["title", "refactor", "My refactor", "--notes", "Split db.rs"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Set title of the chat tagged 'refactor' to 'My refactor'.",
+        ));
+    retort(&["title", "refactor"])?
+        .success()
+        .stdout(predicate::str::diff(
+            "Title: My refactor\nNotes: Split db.rs\n",
+        ));
+
+    // The title belongs to the whole conversation, shown alongside the preview.
+    retort(&["list"])?
+        .success()
+        .stdout(predicate::str::contains("[My refactor] second question"));
+
+    retort(&["title", "missing"])?
+        .failure()
+        .stderr(predicate::str::contains("Tag 'missing' not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_history_after() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort = |args: &[&str], stdin: &str| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM", "1")
+            .write_stdin(stdin)
+            .assert())
+    };
+
+    // Messages 1-4: two turns.
+    retort(&["send", "--chat", "long", "first question"], "")?.success();
+    retort(&["send", "--chat", "long", "second question"], "")?.success();
+
+    // Only the history after message 2 (the first reply) is sent.
+    retort(
+        &[
+            "send",
+            "--chat",
+            "long",
+            "--history-after",
+            "2",
+            "--confirm",
+            "third",
+        ],
+        "n\n",
+    )?
+    .success()
+    .stdout(predicate::str::contains("second question"))
+    .stdout(predicate::str::contains("first question").not());
+
+    // The ID must be in the chain being continued.
+    retort(&["send", "--new", "unrelated"], "")?.success();
+    retort(
+        &["send", "--chat", "long", "--history-after", "6", "more"],
+        "",
+    )?
+    .failure()
+    .stderr(predicate::str::contains(
+        "Message 6 is not in the history of this chat.",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_max_history_bytes() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort = |args: &[&str], stdin: &str| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM", "1")
+            .write_stdin(stdin)
+            .assert())
+    };
+
+    let pasted_log = format!("here is the log: {}", "log line\n".repeat(1000));
+    retort(&["send", "--chat", "logs", &pasted_log], "")?.success();
+
+    // The pasted log is cut down, but the current prompt is sent whole.
+    let long_prompt = "why? ".repeat(100);
+    retort(
+        &[
+            "send",
+            "--chat",
+            "logs",
+            "--max-history-bytes",
+            "200",
+            "--confirm",
+            &long_prompt,
+        ],
+        "n\n",
+    )?
+    .success()
+    .stderr(predicate::str::contains(
+        "over max_history_bytes of 200; truncated the 1 largest message(s).",
+    ))
+    .stdout(predicate::str::contains(
+        "bytes truncated by max_history_bytes]",
+    ))
+    .stdout(predicate::str::contains(long_prompt.trim_end()));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_stdin_files() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    fs::write(home_dir.join("a.txt"), "a")?;
+    fs::write(home_dir.join("b.txt"), "b")?;
+
+    let retort = |args: &[&str], stdin: &str| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM", "1")
+            .write_stdin(stdin)
+            .assert())
+    };
+
+    retort(
+        &["send", "--new", "--stdin-files", "fix these"],
+        "a.txt\n\n  b.txt\n",
+    )?
+    .success();
+    retort(&["show", "1", "--metadata"], "")?
+        .success()
+        .stdout(predicate::str::contains("\"path\": \"a.txt\""))
+        .stdout(predicate::str::contains("\"path\": \"b.txt\""));
+
+    // The paths are only used for that message; nothing is staged.
+    retort(&["stage", "status", "--porcelain"], "")?
+        .success()
+        .stdout(predicate::str::contains("prepared").not());
+
+    retort(&["send", "--new", "--stdin-files", "fix"], "missing.txt\n")?
+        .failure()
+        .stderr(predicate::str::contains(
+            "missing.txt from --stdin-files is not a file.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_replays_recorded_responses() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let replay_path = home_dir.join("replay.json");
+    fs::write(
+        &replay_path,
+        r#"["Which file is it in?", "Fixed it in main.rs."]"#,
+    )?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("RETORT_REPLAY", &replay_path).assert())
+    };
+
+    retort(&["send", "--chat", "bug", "fix the bug"])?
+        .success()
+        .stdout(predicate::str::contains("Which file is it in?"));
+    retort(&["send", "--chat", "bug", "main.rs"])?
+        .success()
+        .stdout(predicate::str::contains("Fixed it in main.rs."));
+    retort(&["history", "bug", "--json"])?
+        .success()
+        .stdout(predicate::str::contains("Which file is it in?"))
+        .stdout(predicate::str::contains("Fixed it in main.rs."));
+
+    retort(&["send", "--chat", "bug", "thanks"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "All 2 responses in RETORT_REPLAY file",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_command() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    fs::write(home_dir.join("same.txt"), "same")?;
+    fs::write(home_dir.join("changed.txt"), "before")?;
+    fs::write(home_dir.join("gone.txt"), "gone")?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["stage", "same.txt"])?.success();
+    retort(&["stage", "-r", "changed.txt"])?.success();
+    retort(&["stage", "gone.txt"])?.success();
+    retort(&["send", "--chat", "stale", "look"])?.success();
+
+    fs::write(home_dir.join("changed.txt"), "after")?;
+    fs::remove_file(home_dir.join("gone.txt"))?;
+
+    // The tag points at the answer, which is checked against the files its question was sent with.
+    retort(&["verify", "stale"])?
+        .success()
+        .stdout(predicate::str::contains("match    same.txt"))
+        .stdout(predicate::str::contains("differs  changed.txt"))
+        .stdout(predicate::str::contains("missing  gone.txt"))
+        .stdout(predicate::str::contains("1 match, 1 differ, 1 missing."));
+    retort(&["verify", "1"])?
+        .success()
+        .stdout(predicate::str::contains("1 match, 1 differ, 1 missing."));
+
+    retort(&["verify", "nope"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "'nope' is neither a tag nor a message ID.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_saves_partial_stream() -> Result<()> {
+    let (temp_dir, command) = retort_env("stream_backup: true");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    let tmp_dir = home_dir.join("tmp");
+    fs::create_dir_all(&tmp_dir)?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("TMPDIR", &tmp_dir)
+            .env("MOCK_LLM_CONTENT", "The first half of")
+            .env("MOCK_LLM_STREAM_ERROR", "connection reset by peer")
+            .assert())
+    };
+
+    retort(&["send", "--new", "--stream", "explain"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "The response stream failed after 17 bytes. They were saved as message 2, and no edits were applied.",
+        ))
+        .stderr(predicate::str::contains("connection reset by peer"));
+    retort(&["show", "2", "--metadata"])?
+        .success()
+        .stdout(predicate::str::contains("The first half of"))
+        .stdout(predicate::str::contains("\"partial\": true"));
+    // The response is in the database, so the backup is gone.
+    assert!(!tmp_dir.join("retort-response-1.txt").exists());
+
+    // A response that fails to apply isn't stored, and its backup is kept.
+    let bad_edit = "missing.txt\n<<<<<<< SEARCH\nnot there\n=======\nx\n>>>>>>> REPLACE\n";
+    command(&["send", "--new", "--stream", "edit"])
+        .env("TMPDIR", &tmp_dir)
+        .env("MOCK_LLM_CONTENT", bad_edit)
+        .assert()
+        .failure();
+    assert_eq!(
+        fs::read_to_string(tmp_dir.join("retort-response-3.txt"))?,
+        bad_edit
+    );
+
+    // A stream that fails before the model adds anything to the prefill saves nothing,
+    // and leaves no backup behind.
+    fs::write(
+        home_dir.join(".retort").join("config.yaml"),
+        format!(
+            "database_path: {}\nstream_backup: true\nbackend: anthropic",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+    command(&[
+        "send",
+        "--new",
+        "--stream",
+        "--assistant-prefill",
+        "Sure,",
+        "go",
+    ])
+    .env("TMPDIR", &tmp_dir)
+    .env("MOCK_LLM_CONTENT", "")
+    .env("MOCK_LLM_STREAM_ERROR", "connection refused")
+    .assert()
+    .failure()
+    .stdout(predicate::str::contains("Sure,"))
+    .stderr(predicate::str::contains("connection refused"))
+    .stderr(predicate::str::contains("saved as message").not());
+    assert!(!tmp_dir.join("retort-response-4.txt").exists());
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert!(retort::db::is_leaf(&conn, 4)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_command() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--chat", "original", "first question"])?.success();
+    retort(&["send", "--chat", "original", "second question"])?.success();
+    retort(&["title", "original", "Pristine"])?.success();
+
+    retort(&["copy", "original", "experiment"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Copied 4 messages from 'original' to 'experiment', with its head at message 8.",
+        ));
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert_eq!(retort::db::get_ancestor_ids(&conn, 8)?, vec![5, 6, 7, 8]);
+    assert_eq!(
+        retort::db::get_message(&conn, 7)?.unwrap().content,
+        "second question"
+    );
+    assert_eq!(
+        retort::db::get_message_metadata(&conn, 7)?,
+        retort::db::get_message_metadata(&conn, 3)?
+    );
+    retort(&["title", "experiment"])?
+        .success()
+        .stdout(predicate::str::contains("Pristine"));
+
+    // Continuing the copy leaves the original where it was.
+    retort(&["send", "--chat", "experiment", "a different turn"])?.success();
+    assert_eq!(
+        retort::db::get_message_id_by_tag(&conn, "original")?,
+        Some(4)
+    );
+    assert_eq!(
+        retort::db::get_message_id_by_tag(&conn, "experiment")?,
+        Some(10)
+    );
+
+    retort(&["copy", "original", "experiment"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "Tag 'experiment' already exists, pointing to message 10.",
+        ));
+    retort(&["copy", "nope", "other"])?
+        .failure()
+        .stderr(predicate::str::contains("Tag 'nope' not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_llm_log_records_assembled_prompt() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let log_path = home_dir.join("requests.json");
+    fs::write(home_dir.join("notes.txt"), "the staged file body")?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM", "1")
+            .env("MOCK_LLM_LOG", &log_path)
+            .assert())
+    };
+
+    retort(&["stage", "-r", "notes.txt"])?.success();
+    retort(&["send", "--chat", "logged", "first question"])?.success();
+    retort(&["send", "--chat", "logged", "second question"])?.success();
+
+    let log: Vec<serde_json::Value> = serde_json::from_str(&fs::read_to_string(&log_path)?)?;
+    assert_eq!(log.len(), 2);
+    assert!(log[0]["system"].as_str().is_some_and(|s| !s.is_empty()));
+
+    let first = log[0]["messages"].as_array().unwrap();
+    assert!(first.iter().any(|message| message["content"]
+        .as_str()
+        .unwrap()
+        .contains("the staged file body")));
+    let last = first.last().unwrap();
+    assert_eq!(last["role"], "user");
+    assert_eq!(last["content"], "first question");
+    assert_eq!(log[0]["response"], "This is a mocked response.");
+
+    // The second request carries the first turn as history, and the inherited file.
+    let second = log[1]["messages"].as_array().unwrap();
+    let contents: Vec<&str> = second
+        .iter()
+        .map(|message| message["content"].as_str().unwrap())
+        .collect();
+    assert!(contents.contains(&"first question"));
+    assert!(contents.contains(&"This is a mocked response."));
+    assert!(contents.iter().any(|c| c.contains("the staged file body")));
+    assert_eq!(contents.last(), Some(&"second question"));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_confirms_large_context() -> Result<()> {
+    let (temp_dir, command) = retort_env("confirm_context_files: 1\nconfirm_context_bytes: 10\n");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    fs::write(home_dir.join("big.txt"), "a".repeat(20))?;
+    fs::write(home_dir.join("small.txt"), "b")?;
+
+    let retort = |args: &[&str], stdin: &str| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM", "1")
+            .write_stdin(stdin)
+            .assert())
+    };
+
+    retort(&["stage", "small.txt"], "")?.success();
+    retort(&["stage", "-r", "big.txt"], "")?.success();
+
+    // An empty answer doesn't send.
+    retort(&["send", "--new", "hi"], "\n")?
+        .success()
+        .stdout(predicate::str::contains(
+            "The context is large: 2 files, over confirm_context_files of 1; 21 bytes, over confirm_context_bytes of 10.",
+        ))
+        .stdout(predicate::str::is_match(r"20  big.txt\n\s+1  small.txt\n")?)
+        .stdout(predicate::str::contains("Aborted."));
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert!(retort::db::get_message(&conn, 1)?.is_none());
+
+    retort(&["send", "--new", "--json-events", "hi"], "")?
+        .failure()
+        .stderr(predicate::str::contains("Pass --yes to send it anyway."));
+
+    retort(&["send", "--new", "hi"], "y\n")?
+        .success()
+        .stdout(predicate::str::contains("Added assistant message"));
+    retort(&["stage", "small.txt"], "")?.success();
+    retort(&["stage", "-r", "big.txt"], "")?.success();
+    retort(&["--yes", "send", "--new", "hi"], "")?
+        .success()
+        .stdout(predicate::str::contains("Send it anyway? [y/N] y (--yes)"));
+
+    // Under both limits, nothing is asked.
+    retort(&["stage", "small.txt"], "")?.success();
+    retort(&["send", "--new", "hi"], "")?
+        .success()
+        .stdout(predicate::str::contains("The context is large").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_send_timings() -> Result<()> {
+    let (_temp_dir, command) = retort_env("input_cost_per_million_tokens: 3.0\n");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--new", "--timings", "hi"])?
+        .success()
+        .stdout(predicate::str::is_match(
+            r"Took \d+\.\d\ds for about 7 tokens \(\d+\.\d tokens/s\)\.",
+        )?)
+        .stdout(predicate::str::is_match(
+            r"Estimated input cost: \$\d\.\d{4} for about \d+ prompt tokens\.",
+        )?);
+    retort(&["send", "--new", "hi"])?
+        .success()
+        .stdout(predicate::str::contains("Took").not());
+
+    // Every response records its latency, with or without --timings.
+    for id in [2, 4] {
+        retort(&["show", &id.to_string(), "--metadata"])?
+            .success()
+            .stdout(predicate::str::is_match(r#""latency_ms": \d+"#)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_send_latest() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--latest", "hi"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "There are no messages to continue from.",
+        ));
+
+    retort(&["send", "--chat", "first", "one"])?.success();
+    retort(&["send", "--chat", "second", "two"])?.success();
+
+    // The newest leaf is the second chat's, so its tag moves along.
+    retort(&["send", "--latest", "three"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Continuing from the latest message, 4 (tag 'second').",
+        ))
+        .stdout(predicate::str::contains(
+            "Updated tag 'second' to point to message ID 6",
+        ));
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert_eq!(retort::db::get_parent_id(&conn, 5)?, Some(4));
+    assert_eq!(retort::db::get_message_id_by_tag(&conn, "first")?, Some(2));
+
+    // An untagged leaf is continued without tagging anything.
+    retort(&["add", "--parent", "2", "--role", "user", "aside"])?.success();
+    retort(&["send", "--latest", "four"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Continuing from the latest message, 7.",
+        ))
+        .stdout(predicate::str::contains("Updated tag").not());
+    assert_eq!(retort::db::get_parent_id(&conn, 8)?, Some(7));
+    assert_eq!(retort::db::get_message_id_by_tag(&conn, "first")?, Some(2));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_task_file() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    let log_path = home_dir.join("requests.json");
+    fs::write(home_dir.join("main.rs"), "fn main() {}")?;
+    fs::write(home_dir.join("spec.md"), "the spec")?;
+    fs::write(home_dir.join("staged.txt"), "staged")?;
+    fs::write(
+        home_dir.join("task.yaml"),
+        "prompt: Implement the spec\nfiles:\n  - path: main.rs\n  - path: spec.md\n    read_only: true\nmodel: gemini-2.5-pro\ntemperature: 0.2\n",
+    )?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM", "1")
+            .env("MOCK_LLM_LOG", &log_path)
+            .assert())
+    };
+
+    retort(&["stage", "staged.txt"])?.success();
+    retort(&["send", "--new", "--task", "task.yaml"])?
+        .success()
+        .stdout(predicate::str::contains("Read-Write:\n    - main.rs"))
+        .stdout(predicate::str::contains("Read-Only:\n    - spec.md"));
+
+    let log: Vec<serde_json::Value> = serde_json::from_str(&fs::read_to_string(&log_path)?)?;
+    let messages = log[0]["messages"].as_array().unwrap();
+    assert_eq!(messages.last().unwrap()["content"], "Implement the spec");
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let metadata = retort::db::get_message_metadata(&conn, 1)?.unwrap();
+    assert!(metadata.contains("main.rs") && metadata.contains("spec.md"));
+    // The stage still goes with the message, and the task's files aren't left staged.
+    assert!(metadata.contains("staged.txt"));
+    retort(&["stage"])?
+        .success()
+        .stdout(predicate::str::contains("main.rs").not());
+
+    fs::write(
+        home_dir.join("typo.yaml"),
+        "prompt: hi\nfile:\n  - path: main.rs\n",
+    )?;
+    retort(&["send", "--new", "--task", "typo.yaml"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "Failed to parse task file typo.yaml",
+        ))
+        .stderr(predicate::str::contains("unknown field `file`"));
+    fs::write(
+        home_dir.join("missing.yaml"),
+        "prompt: hi\nfiles:\n  - path: gone.rs\n",
+    )?;
+    retort(&["send", "--new", "--task", "missing.yaml"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "gone.rs from the task file is not a file.",
+        ));
+    retort(&["send", "--task", "task.yaml", "another prompt"])?.failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_send_new_with_chat_tag() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--chat", "old", "one"])?.success();
+    retort(&["send", "--new", "--chat", "fresh", "two"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Creating new chat with tag 'fresh'",
+        ));
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert_eq!(retort::db::get_parent_id(&conn, 3)?, None);
+    assert_eq!(retort::db::get_message_id_by_tag(&conn, "fresh")?, Some(4));
+
+    // An existing tag is moved to the new root's chat, even though it had a chat to continue.
+    retort(&["send", "--new", "--chat", "old", "three"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Tag 'old' points to message 2; it will be moved to the new chat.",
+        ));
+    assert_eq!(retort::db::get_parent_id(&conn, 5)?, None);
+    assert_eq!(retort::db::get_message_id_by_tag(&conn, "old")?, Some(6));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_parent_with_tag() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--chat", "main", "one"])?.success();
+    retort(&["send", "--chat", "main", "two"])?.success();
+
+    // Branching from an untagged, non-leaf message tags the branch without a warning.
+    retort(&["send", "--parent", "2", "--tag", "experiment", "other"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Updated tag 'experiment' to point to message ID 6",
+        ))
+        .stderr(predicate::str::contains("not a leaf").not());
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert_eq!(retort::db::get_parent_id(&conn, 5)?, Some(2));
+    assert_eq!(retort::db::get_message_id_by_tag(&conn, "main")?, Some(4));
+
+    retort(&["send", "--chat", "experiment", "more"])?.success();
+    assert_eq!(retort::db::get_parent_id(&conn, 7)?, Some(6));
+
+    // An existing tag is moved to the new branch.
+    retort(&["send", "--parent", "2", "--tag", "main", "again"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Tag 'main' points to message 4; it will be moved to the new branch.",
+        ));
+    assert_eq!(retort::db::get_message_id_by_tag(&conn, "main")?, Some(10));
+
+    retort(&["send", "--tag", "loose", "no parent"])?
+        .failure()
+        .stderr(predicate::str::contains("--parent"));
+
+    Ok(())
+}
+
+#[test]
+fn test_status_command() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    fs::write(home_dir.join("a.txt"), "a")?;
+    fs::write(home_dir.join("b.txt"), "b")?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["status"])?
+        .success()
+        .stdout(predicate::str::contains("Active chat:  (none)"))
+        .stdout(predicate::str::contains("Backend:      google"))
+        .stdout(predicate::str::contains(format!(
+            "Database:     {}",
+            db_path.to_str().unwrap()
+        )));
+
+    retort(&["stage", "a.txt"])?.success();
+    retort(&["send", "--chat", "work", "hi"])?.success();
+    retort(&["profile", "--active-chat", "work"])?.success();
+    retort(&["stage", "-r", "b.txt"])?.success();
+
+    retort(&["whoami"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Active chat:  work (message 2, leaf)",
+        ))
+        .stdout(predicate::str::contains(
+            "Context:      1 read-write, 1 read-only file(s) for the next message",
+        ))
+        .stdout(predicate::str::contains(
+            "Stage:        0 read-write, 1 read-only, 0 dropped, 0 from stdin",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_reasoning_effort() -> Result<()> {
+    let (temp_dir, command) =
+        retort_env("backend: ollama\nmodel: local\nbase_url: http://127.0.0.1:9/\n");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    // An unsupported backend warns and sends anyway (the request then fails to connect).
+    command(&[
+        "send",
+        "--new",
+        "--no-stream",
+        "--reasoning",
+        "high",
+        "hello",
+    ])
+    .env("OLLAMA_API_KEY", "unused")
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains(
+        "Warning: backend 'ollama' doesn't support reasoning_effort; ignoring it.",
+    ));
+
+    // The flag only takes known levels.
+    command(&["send", "--new", "--reasoning", "extreme", "hello"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid value 'extreme'"));
+
+    // A bad config value is reported before anything is sent.
+    fs::write(
+        home_dir.join(".retort").join("config.yaml"),
+        format!(
+            "database_path: {}\nreasoning_effort: lots\n",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+    command(&["send", "--new", "--no-stream", "hello"])
+        .env("GOOGLE_API_KEY", "unused")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Unknown reasoning_effort 'lots'; use low, medium or high.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_siblings_command() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    // Messages 1-4 on 'main', then an alternative second turn (5, 6) from message 2.
+    retort(&["send", "--chat", "main", "first"])?.success();
+    retort(&["send", "--chat", "main", "second"])?.success();
+    retort(&["send", "--parent", "2", "second, reworded"])?.success();
+    retort(&["tag", "set", "alt", "-m", "6"])?.success();
+
+    let expected = format!(
+        "  {:<5} {:<20} Preview\n  {:-<5} {:-<20} {:-<60}\n* 3     main                 second\n  5     alt                  second, reworded\n",
+        "ID", "Tags", "", "", ""
+    );
+    retort(&["siblings", "3"])?
+        .success()
+        .stdout(predicate::str::diff(expected));
+
+    retort(&["siblings", "99"])?
+        .failure()
+        .stderr(predicate::str::contains("Message with ID '99' not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_show_metadata_records_streaming() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--new", "--no-stream", "buffered"])?.success();
+    retort(&["send", "--new", "--stream", "streamed"])?.success();
+
+    retort(&["show", "2", "--metadata"])?
+        .success()
+        .stdout(predicate::str::contains("This is a mocked response."))
+        .stdout(predicate::str::contains("\"streamed\": false"));
+    retort(&["show", "4", "--metadata"])?
+        .success()
+        .stdout(predicate::str::contains("\"streamed\": true"));
+
+    // User messages carry their file context; without --metadata only the message is shown.
+    retort(&["show", "1", "--metadata"])?
+        .success()
+        .stdout(predicate::str::contains("\"read_write_files\": []"));
+    retort(&["show", "1"])?
+        .success()
+        .stdout(predicate::str::contains("buffered"))
+        .stdout(predicate::str::contains("read_write_files").not());
+
+    // Reusing an answer's context still resolves to the user message it answered.
+    retort(&["send", "--new", "--context-from", "2", "again"])?.success();
+
+    Ok(())
+}
+
+#[test]
+fn test_send_fit_context() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    let config_dir = home_dir.join(".retort");
+    let write_config = |budget: Option<usize>| {
+        let mut config = format!("database_path: {}\n", db_path.to_str().unwrap());
+        if let Some(budget) = budget {
+            config.push_str(&format!("context_token_budget: {}\n", budget));
+        }
+        fs::write(config_dir.join("config.yaml"), config)
+    };
+    fs::write(home_dir.join("big.txt"), "b".repeat(4000))?;
+    fs::write(home_dir.join("small.txt"), "s".repeat(400))?;
+    fs::write(home_dir.join("edit.txt"), "e".repeat(2000))?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    // --fit-context needs a budget.
+    write_config(None)?;
+    retort(&["stage", "-r", "big.txt"])?.success();
+    retort(&["stage", "-r", "small.txt"])?.success();
+    retort(&["stage", "edit.txt"])?.success();
+    retort(&["send", "--new", "--fit-context", "hi"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "--fit-context needs context_token_budget set in the config.",
+        ));
+
+    let output = retort(&["send", "--new", "--estimate", "hi"])?.success();
+    let stdout = String::from_utf8(output.get_output().stdout.clone())?;
+    let full_tokens: usize = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Estimated prompt size: "))
+        .and_then(|rest| rest.split(' ').next())
+        .unwrap()
+        .parse()?;
+
+    // 1. Only the largest read-only file has to go.
+    write_config(Some(full_tokens - 900))?;
+    retort(&["send", "--new", "--fit-context", "hi"])?
+        .success()
+        .stderr(predicate::str::contains(
+            "Dropped big.txt (about 1000 tokens)",
+        ))
+        .stderr(predicate::str::contains("small.txt").not())
+        .stdout(predicate::str::contains("    - small.txt"))
+        .stdout(predicate::str::contains("    - big.txt").not());
+
+    // 2. Read-only files go before the read-write file, and the budget may still not be met.
+    retort(&["stage", "-r", "big.txt"])?.success();
+    retort(&["stage", "-r", "small.txt"])?.success();
+    retort(&["stage", "edit.txt"])?.success();
+    write_config(Some(10))?;
+    retort(&["send", "--new", "--fit-context", "hi"])?
+        .success()
+        .stderr(predicate::str::is_match(
+            "(?s)Dropped big.txt.*Dropped small.txt.*Dropped edit.txt.*no files left to drop",
+        )?);
+
+    Ok(())
+}
+
+#[test]
+fn test_add_command() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort =
+        |args: &[&str]| -> Result<assert_cmd::assert::Assert> { Ok(command(args).assert()) };
+
+    // Build a few-shot history by hand.
+    retort(&["add", "--role", "system", "Answer in one word."])?
+        .success()
+        .stdout(predicate::str::diff("Added system message with ID: 1\n"));
+    retort(&["add", "--parent", "1", "Capital of France?"])?
+        .success()
+        .stdout(predicate::str::diff("Added user message with ID: 2\n"));
+    retort(&["add", "--role", "assistant", "--parent", "2", "Paris"])?.success();
+
+    retort(&["history", "-m", "3"])?
+        .success()
+        .stdout(predicate::str::is_match(
+            "(?s)\\[system\\].*Answer in one word\\..*\\[user\\].*Capital of France\\?.*\\[assistant\\].*Paris",
+        )?);
+
+    retort(&["add", "--role", "robot", "beep"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "Invalid role 'robot'; use one of system, user, assistant.",
+        ));
+    retort(&["add", "--parent", "42", "orphan"])?
+        .failure()
+        .stderr(predicate::str::contains("Message with ID '42' not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_auto_tags_new_chats() -> Result<()> {
+    let (_temp_dir, command) = retort_env("auto_tag_new_chats: true\n");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "Fix the login bug, please!"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Tagged new chat as 'fix-the-login-bug' and made it the active chat.",
+        ));
+    // Follow-ups continue the tagged chat.
+    retort(&["send", "and add a test"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Updated tag 'fix-the-login-bug' to point to message ID 4",
+        ));
+
+    // Taken tags get a suffix, and prompts without words fall back to 'chat'.
+    retort(&["send", "--new", "fix the login bug"])?
+        .success()
+        .stdout(predicate::str::contains("'fix-the-login-bug-2'"));
+    retort(&["send", "--new", "???"])?
+        .success()
+        .stdout(predicate::str::contains("'chat'"));
+
+    // Branches aren't auto-tagged.
+    retort(&["send", "--parent", "2", "branch"])?
+        .success()
+        .stdout(predicate::str::contains("Tagged new chat").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_history_json() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--chat", "json-chat", "multi\nline \"prompt\""])?.success();
+
+    let output = retort(&["history", "json-chat", "--json"])?.success();
+    let history: serde_json::Value = serde_json::from_slice(&output.get_output().stdout)?;
+    let messages = history.as_array().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["role"], "user");
+    assert_eq!(messages[0]["content"], "multi\nline \"prompt\"");
+    assert_eq!(messages[1]["role"], "assistant");
+    assert_eq!(messages[1]["content"], "This is a mocked response.");
+    assert!(messages[1]["created_at"].as_str().unwrap().len() >= 19);
+
+    Ok(())
+}
+
+#[test]
+fn test_history_compact() -> Result<()> {
+    let (_temp_dir, command) = retort_env("");
+
+    let retort =
+        |args: &[&str]| -> Result<assert_cmd::assert::Assert> { Ok(command(args).assert()) };
+
+    // A transcript with the file-context turns stored in it, e.g. from an import.
+    let answer = format!("Like this:\n```rust\n{}```", "let x = 1;\n".repeat(20));
+    retort(&[
+        "add",
+        "The user has provided the following read-only files:\nlib.rs\n```\nfn main() {}\n```",
+    ])?
+    .success();
+    retort(&[
+        "add",
+        "--role",
+        "assistant",
+        "--parent",
+        "1",
+        "Ok, I will use these files as references.",
+    ])?
+    .success();
+    retort(&["add", "--parent", "2", "how do I bind x?"])?.success();
+    retort(&["add", "--role", "assistant", "--parent", "3", &answer])?.success();
+
+    let output = retort(&["history", "-m", "4", "--compact", "--json"])?.success();
+    let history: serde_json::Value = serde_json::from_slice(&output.get_output().stdout)?;
+    let messages = history.as_array().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["content"], "how do I bind x?");
+    assert_eq!(
+        messages[1]["content"],
+        "Like this:\n```rust\n[20 lines hidden]\n```"
+    );
+
+    // Without --compact, everything is shown.
+    retort(&["history", "-m", "4"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Ok, I will use these files as references.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_include_readme_in_context() -> Result<()> {
+    let (temp_dir, command) = retort_env("include_readme_in_context: true\n");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    fs::write(home_dir.join("README.md"), "# Project\n")?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["send", "--new", "look"])?.success();
+    retort(&["send", "--new", "--no-readme", "skip it"])?.success();
+    // Staged read-write under another spelling, it isn't added a second time.
+    retort(&["stage", "./README.md"])?.success();
+    retort(&["send", "--new", "edit it"])?.success();
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let metadata: serde_json::Value =
+        serde_json::from_str(&retort::db::get_message_metadata(&conn, 1)?.unwrap())?;
+    assert_eq!(metadata["read_only_files"][0]["path"], "README.md");
+    let metadata = retort::db::get_message_metadata(&conn, 3)?.unwrap();
+    assert!(!metadata.contains("README.md"));
+    let metadata: serde_json::Value =
+        serde_json::from_str(&retort::db::get_message_metadata(&conn, 5)?.unwrap())?;
+    assert_eq!(metadata["read_only_files"], serde_json::json!([]));
+    assert_eq!(metadata["read_write_files"][0]["path"], "./README.md");
+
+    Ok(())
+}
+
+#[test]
+fn test_send_collapses_identical_files() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    fs::write(home_dir.join("notes.txt"), "same\n")?;
+    fs::write(home_dir.join("empty1.txt"), "")?;
+    fs::write(home_dir.join("empty2.txt"), "")?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["stage", "-r", "./notes.txt"])?.success();
+    retort(&["stage", "notes.txt"])?.success();
+    retort(&["stage", "empty1.txt"])?.success();
+    retort(&["stage", "empty2.txt"])?.success();
+    // The read-write copy is kept, and empty files are never treated as duplicates.
+    retort(&["send", "--new", "look"])?
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: ./notes.txt has the same content as notes.txt; only notes.txt is sent.",
+        ))
+        .stderr(predicate::str::contains("empty").not())
+        .stdout(predicate::str::contains("./notes.txt").not());
+
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    let metadata: serde_json::Value =
+        serde_json::from_str(&retort::db::get_message_metadata(&conn, 1)?.unwrap())?;
+    let paths: Vec<&str> = metadata["read_write_files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(paths, ["empty1.txt", "empty2.txt", "notes.txt"]);
+    assert_eq!(metadata["read_only_files"], serde_json::json!([]));
+
+    Ok(())
+}
+
+#[test]
+fn test_ls_files() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let first = r#"{"read_write_files":[{"path":"src/lib.rs","hash":"a"}],"read_only_files":[{"path":"README.md","hash":"b"}]}"#;
+        let second =
+            r#"{"read_write_files":[{"path":"src/lib.rs","hash":"c"}],"read_only_files":[]}"#;
+        let user1 = retort::db::add_message(&conn, None, "user", "one", Some(first))?;
+        let reply1 = retort::db::add_message(
+            &conn,
+            Some(user1),
+            "assistant",
+            "ok",
+            Some(r#"{"streamed":false}"#),
+        )?;
+        let user2 = retort::db::add_message(&conn, Some(reply1), "user", "two", Some(second))?;
+        let reply2 = retort::db::add_message(&conn, Some(user2), "assistant", "ok", None)?;
+        retort::db::set_chat_tag(&conn, "work", reply2)?;
+        let lone = retort::db::add_message(&conn, None, "user", "no files", None)?;
+        retort::db::set_chat_tag(&conn, "empty", lone)?;
+    }
+
+    let retort =
+        |args: &[&str]| -> Result<assert_cmd::assert::Assert> { Ok(command(args).assert()) };
+
+    retort(&["ls-files", "work"])?
+        .success()
+        .stdout(predicate::str::diff(format!(
+            "Turns Path\n----- {}\n1     README.md\n2     src/lib.rs\n",
+            "-".repeat(40)
+        )));
+    retort(&["ls-files", "empty"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "No files in the history of 'empty'.",
+        ));
+    retort(&["ls-files", "missing"])?
+        .failure()
+        .stderr(predicate::str::contains("Tag 'missing' not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_prompt_template_per_chat() -> Result<()> {
+    let (temp_dir, command) = retort_env("prompt_templates:\n  planning: ~/planning.j2\n");
+    let home_dir = temp_dir.path();
+    fs::write(
+        home_dir.join("planning.j2"),
+        "You are planning, not editing.\n",
+    )?;
+
+    let retort = |args: &[&str], stdin: &str| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args)
+            .env("MOCK_LLM", "1")
+            .write_stdin(stdin)
+            .assert())
+    };
+    let custom = "You are planning, not editing.";
+    let built_in = "Act as an expert software developer.";
+
+    // A chat started with --template keeps it.
+    retort(&["send", "--new", "--template", "planning", "plan it"], "")?.success();
+    retort(&["tag", "set", "plan", "-m", "2"], "")?.success();
+    retort(&["template", "plan"], "")?
+        .success()
+        .stdout("planning\n");
+    retort(&["send", "--chat", "plan", "--confirm", "next"], "n\n")?
+        .success()
+        .stdout(predicate::str::contains(custom))
+        .stdout(predicate::str::contains(built_in).not());
+
+    // Switching the chat back to the built-in prompt, with a one-off override.
+    retort(&["template", "plan", "default"], "")?
+        .success()
+        .stdout(predicate::str::contains(
+            "The chat tagged 'plan' now uses the 'default' prompt template.",
+        ));
+    retort(&["send", "--chat", "plan", "--confirm", "next"], "n\n")?
+        .success()
+        .stdout(predicate::str::contains(built_in));
+    retort(
+        &[
+            "send",
+            "--chat",
+            "plan",
+            "--template",
+            "planning",
+            "--confirm",
+            "x",
+        ],
+        "n\n",
+    )?
+    .success()
+    .stdout(predicate::str::contains(custom));
+    retort(&["template", "plan"], "")?
+        .success()
+        .stdout("default\n");
+
+    retort(&["template", "plan", "nope"], "")?
+        .failure()
+        .stderr(predicate::str::contains(
+            "Unknown prompt template 'nope'; add it under prompt_templates in the config.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_preview_json() -> Result<()> {
+    let (temp_dir, command) = retort_env("");
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+    fs::write(home_dir.join("notes.txt"), "remember this")?;
+
+    let retort = |args: &[&str]| -> Result<assert_cmd::assert::Assert> {
+        Ok(command(args).env("MOCK_LLM", "1").assert())
+    };
+
+    retort(&["stage", "-r", "notes.txt"])?.success();
+    let output = retort(&["send", "--new", "--preview-json", "what's in my notes?"])?.success();
+    // stdout is nothing but the JSON preview.
+    let preview: serde_json::Value = serde_json::from_slice(&output.get_output().stdout)?;
+    assert!(preview["system"]
+        .as_str()
+        .unwrap()
+        .contains("Act as an expert software developer."));
+    let messages = preview["messages"].as_array().unwrap();
+    assert!(messages[0]["content"]
+        .as_str()
+        .unwrap()
+        .contains("remember this"));
+    assert_eq!(
+        messages.last().unwrap(),
+        &serde_json::json!({"role": "user", "content": "what's in my notes?"})
+    );
+    assert_eq!(
+        preview["files"],
+        serde_json::json!([{"path": "notes.txt", "read_only": true}])
+    );
+
+    // Nothing was stored, and the stage is kept for the real send.
+    let conn = retort::db::setup(db_path.to_str().unwrap())?;
+    assert!(retort::db::get_leaf_messages(&conn)?.is_empty());
+    retort(&["send", "--new", "what's in my notes?"])?
+        .success()
+        .stdout(predicate::str::contains("- notes.txt"));
+
+    // The notes about which message is continued or which tag moves stay off stdout.
+    retort(&["tag", "set", "notes", "-m", "2"])?.success();
+    for args in [
+        &["send", "--latest", "--preview-json", "again"][..],
+        &[
+            "send",
+            "--new",
+            "--chat",
+            "notes",
+            "--preview-json",
+            "again",
+        ],
+        &[
+            "send",
+            "--parent",
+            "1",
+            "--tag",
+            "notes",
+            "--preview-json",
+            "again",
+        ],
+    ] {
+        let output = retort(args)?.success();
+        let preview: serde_json::Value = serde_json::from_slice(&output.get_output().stdout)?;
+        assert_eq!(
+            preview["messages"].as_array().unwrap().last().unwrap()["content"],
+            "again"
+        );
+    }
+
+    Ok(())
+}
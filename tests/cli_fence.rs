@@ -92,7 +92,7 @@ hello rust
         .arg("hook-test")
         .arg("make a change")
         .env("HOME", &home_dir)
-        .env("MOCK_LLM_CONTENT", &mock_response)
+        .env("MOCK_LLM_CONTENT", mock_response)
         .assert()
         .success();
 
@@ -198,7 +198,7 @@ fn test_project_root_enforcement() -> Result<()> {
         .assert()
         .success();
 
-    assert_eq!(fs::read_to_string(&internal_file)?, "new content\n");
+    assert_eq!(fs::read_to_string(&internal_file)?, "new content");
 
     // Test 2: Attempt to write outside project root (should fail)
     let outside_dir = tempdir()?;
@@ -226,5 +226,1386 @@ fn test_project_root_enforcement() -> Result<()> {
     // Verify file was not changed
     assert_eq!(fs::read_to_string(&external_file)?, "external content");
 
+    // A refused new file outside the root doesn't leave its directories behind.
+    let new_outside = outside_dir.path().join("new/deeper/x.rs");
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "write a new file outside"])
+        .env("HOME", &home_dir)
+        .env(
+            "MOCK_LLM_CONTENT",
+            format!(
+                "feat: new outside\n\n{}\n{}\n{}\nmalicious\n{}",
+                new_outside.display(),
+                search_fence,
+                separator,
+                replace_fence
+            ),
+        )
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "which is outside the project root",
+        ));
+    assert!(!outside_dir.path().join("new").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_send_json_events() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    // Setup git repo with a file to edit
+    let file_to_change = project_dir.join("test-file.txt");
+    fs::write(&file_to_change, "hello world\n")?;
+    init_git_repo(project_dir)?;
+
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["stage", "test-file.txt"])
+        .env("HOME", &home_dir)
+        .assert()
+        .success();
+
+    let mock_response = "feat: update test file\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n";
+
+    let output = Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--json-events", "make a change"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .output()?;
+    assert!(output.status.success());
+
+    // Every line on stdout must be a JSON event.
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    let types: Vec<&str> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+    assert_eq!(types, vec!["context", "chunk", "edit", "commit", "done"]);
+
+    assert_eq!(events[0]["files"][0]["path"], "test-file.txt");
+    assert_eq!(events[0]["files"][0]["read_only"], false);
+    assert_eq!(events[1]["text"], mock_response);
+    assert_eq!(events[2]["path"], "test-file.txt");
+    assert_eq!(events[2]["applied"], true);
+    assert_eq!(events[4]["assistant_message_id"], 2);
+
+    let head = Command::new("git")
+        .current_dir(project_dir)
+        .args(["rev-parse", "HEAD"])
+        .output()?;
+    assert_eq!(
+        events[3]["hash"].as_str().unwrap(),
+        String::from_utf8(head.stdout)?.trim()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_json_events_report_conflicts() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    fs::write(project_dir.join("a.txt"), "hello world\n")?;
+    fs::write(project_dir.join("b.txt"), "first\nsecond\nthird\n")?;
+    init_git_repo(project_dir)?;
+
+    // The first edit applies, the second doesn't match, and both are reported.
+    let mock_response = "fix\n\na.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n\nb.txt\n<<<<<<< SEARCH\nsecond\nthrid\n=======\n2\n>>>>>>> REPLACE\n";
+    let output = Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--json-events", "make a change"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .output()?;
+    assert!(!output.status.success());
+
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    let edits: Vec<&serde_json::Value> = events.iter().filter(|e| e["type"] == "edit").collect();
+    assert_eq!(edits.len(), 2);
+    // The first edit was written, then rolled back with the rest, so it isn't reported as applied.
+    assert_eq!(edits[0]["path"], "a.txt");
+    assert_eq!(edits[0]["applied"], false);
+    assert_eq!(
+        edits[0]["error"],
+        "Reverted: another edit in the response could not be applied"
+    );
+    assert!(edits[0].get("conflict").is_none());
+    assert_eq!(edits[1]["path"], "b.txt");
+    assert_eq!(edits[1]["applied"], false);
+    assert_eq!(edits[1]["error"], "SEARCH block not found in file b.txt");
+    assert_eq!(edits[1]["conflict"]["reason"], "not_found");
+    assert_eq!(edits[1]["conflict"]["search"], "second\nthrid");
+    assert_eq!(
+        edits[1]["conflict"]["nearest_lines"],
+        serde_json::json!([{"line": 2, "text": "second"}, {"line": 3, "text": "third"}])
+    );
+
+    // Nothing is left half-applied.
+    assert_eq!(
+        fs::read_to_string(project_dir.join("a.txt"))?,
+        "hello world\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_continue_on_edit_error() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    fs::write(project_dir.join("a.txt"), "hello world\n")?;
+    fs::write(project_dir.join("b.txt"), "first\n")?;
+    init_git_repo(project_dir)?;
+
+    let mock_response = "fix: greet rust\n\na.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n\nb.txt\n<<<<<<< SEARCH\nmissing\n=======\n2\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--continue-on-edit-error", "make a change"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: skipping 1 of 2 edits that could not be applied:\nSEARCH block not found in file b.txt",
+        ));
+
+    assert_eq!(
+        fs::read_to_string(project_dir.join("a.txt"))?,
+        "hello rust\n"
+    );
+    assert_eq!(fs::read_to_string(project_dir.join("b.txt"))?, "first\n");
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["show", "--name-only", "--pretty=%s", "HEAD"])
+        .output()?;
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "fix: greet rust\n\na.txt\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_edits_file_containing_fences() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+    let log_path = home_dir.join("requests.json");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    let readme = project_dir.join("README.md");
+    fs::write(&readme, "## Usage\n\n```bash\nretort send\n```\n")?;
+    init_git_repo(project_dir)?;
+
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["stage", "README.md"])
+        .env("HOME", &home_dir)
+        .assert()
+        .success();
+
+    let mock_response = "Document --new\n\n````markdown\nREADME.md\n<<<<<<< SEARCH\n```bash\nretort send\n```\n=======\n```bash\nretort send\nretort send --new\n```\n>>>>>>> REPLACE\n````\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "document --new"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .env("MOCK_LLM_LOG", &log_path)
+        .assert()
+        .success();
+
+    // The file's own fences didn't end its block, and the system prompt asked for the longer fence.
+    let log = fs::read_to_string(&log_path)?;
+    assert!(log.contains(r"README.md\n````\n## Usage\n\n```bash\nretort send\n```\n\n````"));
+    assert!(log.contains("The closing fence: ````"));
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "## Usage\n\n```bash\nretort send\nretort send --new\n```\n"
+    );
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["log", "-1", "--pretty=%B"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "Document --new");
+
+    Ok(())
+}
+
+/// Initializes a git repo in `dir` and commits everything already in it.
+fn init_git_repo(dir: &Path) -> Result<()> {
+    for args in [
+        vec!["init"],
+        vec!["config", "user.name", "Test User"],
+        vec!["config", "user.email", "test@example.com"],
+        vec!["add", "."],
+        vec!["commit", "--allow-empty", "-m", "initial commit"],
+    ] {
+        Command::new("git")
+            .current_dir(dir)
+            .args(args)
+            .assert()
+            .success();
+    }
+    Ok(())
+}
+
+#[test]
+fn test_send_with_append_block() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    // An existing file without a trailing newline, and one that doesn't exist yet.
+    let changelog = project_dir.join("CHANGELOG.md");
+    fs::write(&changelog, "- first entry")?;
+    let new_log = project_dir.join("logs/new.log");
+    init_git_repo(project_dir)?;
+
+    let mock_response = "docs: add changelog entries\n\nCHANGELOG.md\n<<<<<<< APPEND\n- second entry\n- third entry\n>>>>>>> APPEND\n\nlogs/new.log\n<<<<<<< APPEND\nstarted\n>>>>>>> APPEND\n";
+
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "log it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+
+    // The changelog had no trailing newline, so none is added.
+    assert_eq!(
+        fs::read_to_string(&changelog)?,
+        "- first entry\n- second entry\n- third entry"
+    );
+    assert_eq!(fs::read_to_string(&new_log)?, "started\n");
+
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["log", "-1", "--pretty=%B"])
+        .output()?;
+    assert_eq!(
+        String::from_utf8(output.stdout)?.trim(),
+        "docs: add changelog entries"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_edits_crlf_file() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    let crlf_file = project_dir.join("windows.txt");
+    fs::write(&crlf_file, "line one\r\nline two\r\nline three\r\n")?;
+    init_git_repo(project_dir)?;
+
+    let mock_response = "fix: rename line\n\nwindows.txt\n<<<<<<< SEARCH\nline one\nline two\n=======\nline one\nline 2\n>>>>>>> REPLACE\n";
+
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "edit crlf"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+
+    // The match succeeds and the file keeps its CRLF line endings.
+    assert_eq!(
+        fs::read_to_string(&crlf_file)?,
+        "line one\r\nline 2\r\nline three\r\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_runs_formatters_before_commit() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    // A "formatter" that uppercases each file it's given.
+    let format_script = project_dir.join("format.sh");
+    fs::write(
+        &format_script,
+        "for f in \"$@\"; do tr a-z A-Z < \"$f\" > \"$f.tmp\" && mv \"$f.tmp\" \"$f\"; done\n",
+    )?;
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!(
+            "database_path: {}\nformatters:\n  - pattern: '\\.txt$'\n    command: sh {}\n  - pattern: '\\.txt$'\n    command: 'false'\n",
+            db_path.to_str().unwrap(),
+            format_script.display()
+        ),
+    )?;
+
+    let file_to_change = project_dir.join("test-file.txt");
+    fs::write(&file_to_change, "hello world\n")?;
+    let other_file = project_dir.join("notes.md");
+    init_git_repo(project_dir)?;
+
+    let mock_response = "feat: format me\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n\nnotes.md\n<<<<<<< SEARCH\n=======\nleave me alone\n>>>>>>> REPLACE\n";
+
+    // The failing formatter only warns, so the send still succeeds.
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "make a change"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: formatter 'false' failed",
+        ));
+
+    // Only files matching the pattern are formatted.
+    assert_eq!(fs::read_to_string(&file_to_change)?, "HELLO RUST\n");
+    assert_eq!(fs::read_to_string(&other_file)?, "leave me alone\n");
+
+    // The formatted content is what got committed.
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["show", "HEAD:test-file.txt"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?, "HELLO RUST\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_send_reverts_edits_when_tests_fail() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    // The "test suite" passes only if the file mentions rust.
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!(
+            "database_path: {}\ntest_command: grep -q rust test-file.txt\n",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+
+    let file_to_change = project_dir.join("test-file.txt");
+    fs::write(&file_to_change, "hello world\n")?;
+    let new_file = project_dir.join("new-file.txt");
+    init_git_repo(project_dir)?;
+
+    // 1. An edit that breaks the tests is reverted, including newly created files.
+    let failing_response = "feat: break it\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello python\n>>>>>>> REPLACE\n\nnew-file.txt\n<<<<<<< SEARCH\n=======\nnew\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "break it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", failing_response)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Test command 'grep -q rust test-file.txt' failed",
+        ))
+        .stderr(predicate::str::contains("Reverted edits to 2 file(s)."));
+
+    assert_eq!(fs::read_to_string(&file_to_change)?, "hello world\n");
+    assert!(!new_file.exists());
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["log", "-1", "--pretty=%B"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "initial commit");
+
+    // With --json-events, the reverted edits are reported as not applied.
+    let output = Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--json-events", "break it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", failing_response)
+        .output()?;
+    assert!(!output.status.success());
+    let edits: Vec<serde_json::Value> = String::from_utf8(output.stdout)?
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .filter(|event| event.as_ref().map_or(true, |e| e["type"] == "edit"))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(edits.len(), 2);
+    for edit in &edits {
+        assert_eq!(edit["applied"], false);
+        assert!(edit["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Reverted: Test command 'grep -q rust test-file.txt' failed"));
+    }
+    assert_eq!(fs::read_to_string(&file_to_change)?, "hello world\n");
+
+    // 2. An edit that passes the tests is committed.
+    let passing_response = "feat: fix it\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "fix it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", passing_response)
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_to_string(&file_to_change)?, "hello rust\n");
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["log", "-1", "--pretty=%B"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "feat: fix it");
+
+    Ok(())
+}
+
+#[test]
+fn test_send_refuses_to_edit_read_only_files() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    let read_only_file = project_dir.join("reference.txt");
+    fs::write(&read_only_file, "do not touch\n")?;
+    init_git_repo(project_dir)?;
+
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["stage", "-r", "reference.txt"])
+        .env("HOME", &home_dir)
+        .assert()
+        .success();
+
+    // The path is spelled differently from how it was staged, but resolves to the same file.
+    let mock_response = "feat: sneaky\n\n./reference.txt\n<<<<<<< SEARCH\ndo not touch\n=======\ntouched\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "edit the reference"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Refusing to modify file ./reference.txt which was provided as read-only.",
+        ));
+
+    assert_eq!(fs::read_to_string(&read_only_file)?, "do not touch\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_stage_line_range() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    fs::write(project_dir.join("big.txt"), "one\ntwo\nthree\nfour\n")?;
+    init_git_repo(project_dir)?;
+
+    // Ranges are always staged read-only.
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["stage", "big.txt:2-3"])
+        .env("HOME", &home_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Staged big.txt:2-3 as read-only."));
+
+    // Only those lines are sent, labelled as a fragment.
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--preview-json", "explain"])
+        .env("HOME", &home_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            r"big.txt (lines 2-3 only)\n```\ntwo\nthree\n```",
+        ))
+        .stdout(predicate::str::contains("four").not());
+
+    // The file behind the range is protected from edits.
+    let mock_response = "feat: edit\n\nbig.txt\n<<<<<<< SEARCH\ntwo\n=======\n2\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Refusing to modify file big.txt which was provided as read-only.",
+        ));
+    assert_eq!(
+        fs::read_to_string(project_dir.join("big.txt"))?,
+        "one\ntwo\nthree\nfour\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_diff_context() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+    fs::write(project_dir.join("unstaged.txt"), "old\n")?;
+    fs::write(project_dir.join("staged.txt"), "old\n")?;
+    init_git_repo(project_dir)?;
+
+    fs::write(project_dir.join("unstaged.txt"), "unstaged change\n")?;
+    fs::write(project_dir.join("staged.txt"), "staged change\n")?;
+    Command::new("git")
+        .current_dir(project_dir)
+        .args(["add", "staged.txt"])
+        .assert()
+        .success();
+
+    // 1. --diff sends the unstaged changes as a read-only block.
+    assert_cmd::Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--confirm", "--diff", "review this"])
+        .env("HOME", &home_dir)
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Diff:\n    - git diff\n"))
+        .stdout(predicate::str::contains("Output of `git diff`"))
+        .stdout(predicate::str::contains("+unstaged change"))
+        .stdout(predicate::str::contains("+staged change").not());
+
+    // 2. --staged sends only what is staged.
+    assert_cmd::Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--confirm", "--diff", "--staged", "review"])
+        .env("HOME", &home_dir)
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Output of `git diff --staged`"))
+        .stdout(predicate::str::contains("+staged change"))
+        .stdout(predicate::str::contains("+unstaged change").not());
+
+    // 3. --staged without --diff is rejected.
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--staged", "review"])
+        .env("HOME", &home_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_send_preserves_missing_trailing_newline() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!(
+            "database_path: {}\nnew_file_trailing_newline: false\n",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+
+    let no_newline = project_dir.join("generated.txt");
+    fs::write(&no_newline, "alpha\nbeta")?;
+    let with_newline = project_dir.join("normal.txt");
+    fs::write(&with_newline, "alpha\nbeta\n")?;
+    let new_file = project_dir.join("new.txt");
+    init_git_repo(project_dir)?;
+
+    let mock_response = "fix: rename beta\n\ngenerated.txt\n<<<<<<< SEARCH\nbeta\n=======\ngamma\n>>>>>>> REPLACE\n\nnormal.txt\n<<<<<<< SEARCH\nbeta\n=======\ngamma\n>>>>>>> REPLACE\n\nnew.txt\n<<<<<<< SEARCH\n=======\nfresh\n>>>>>>> REPLACE\n";
+
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "rename"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_to_string(&no_newline)?, "alpha\ngamma");
+    assert_eq!(fs::read_to_string(&with_newline)?, "alpha\ngamma\n");
+    // New files follow new_file_trailing_newline.
+    assert_eq!(fs::read_to_string(&new_file)?, "fresh");
+
+    Ok(())
+}
+
+#[test]
+fn test_replay_edits() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+
+    let file_to_change = project_dir.join("test-file.txt");
+    fs::write(&file_to_change, "hello world\n")?;
+    let other_file = project_dir.join("other.txt");
+    fs::write(&other_file, "one\n")?;
+    init_git_repo(project_dir)?;
+
+    let mock_response = "feat: greet rust\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n\nother.txt\n<<<<<<< SEARCH\none\n=======\ntwo\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "greet rust"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+
+    // Throw the commit away, then bring it back from history.
+    Command::new("git")
+        .current_dir(project_dir)
+        .args(["reset", "--hard", "HEAD~1"])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&file_to_change)?, "hello world\n");
+
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["replay-edits", "2"])
+        .env("HOME", &home_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Replayed 2 edit(s) from message 2.",
+        ));
+    assert_eq!(fs::read_to_string(&file_to_change)?, "hello rust\n");
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["log", "-1", "--pretty=%B"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "feat: greet rust");
+
+    // When one block no longer matches, nothing is left half-applied.
+    Command::new("git")
+        .current_dir(project_dir)
+        .args(["reset", "--hard", "HEAD~1"])
+        .assert()
+        .success();
+    fs::write(&other_file, "diverged\n")?;
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["replay-edits", "2"])
+        .env("HOME", &home_dir)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "SEARCH block not found in file other.txt",
+        ))
+        .stderr(predicate::str::contains("Reverted edits to 2 file(s)."));
+    assert_eq!(fs::read_to_string(&file_to_change)?, "hello world\n");
+
+    // Only assistant messages can be replayed.
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["replay-edits", "1"])
+        .env("HOME", &home_dir)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Message 1 is a user message; only assistant messages have edits to replay.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_retries_commit_after_pre_commit_hook() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+
+    let file_to_change = project_dir.join("test-file.txt");
+    fs::write(&file_to_change, "hello world\n")?;
+    init_git_repo(project_dir)?;
+
+    // A hook that "formats" the file and rejects the commit, like many formatter hooks do,
+    // and always rejects files mentioning python.
+    let hook_path = project_dir.join(".git/hooks/pre-commit");
+    fs::write(
+        &hook_path,
+        "#!/bin/sh\nif grep -q python test-file.txt; then echo 'python is not allowed'; exit 1; fi\nif grep -q '  ' test-file.txt; then sed -i 's/  */ /g' test-file.txt; echo 'reformatted test-file.txt'; exit 1; fi\n",
+    )?;
+    fs::set_permissions(&hook_path, fs::Permissions::from_mode(0o755))?;
+
+    // 1. The retry picks up the hook's changes and succeeds.
+    let mock_response = "feat: spacing\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello   rust\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("git commit failed; re-staging"))
+        .stderr(predicate::str::contains("reformatted test-file.txt"));
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["show", "HEAD:test-file.txt"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?, "hello rust\n");
+
+    // 2. A hook that keeps rejecting the commit surfaces its output.
+    let mock_response = "feat: python\n\ntest-file.txt\n<<<<<<< SEARCH\nhello rust\n=======\nhello python\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "change it again"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "git commit failed:\npython is not allowed",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_reports_git_add_errors() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\nbuild.log\n")?;
+    init_git_repo(project_dir)?;
+
+    let mock_response =
+        "chore: add log\n\nbuild.log\n<<<<<<< SEARCH\n=======\nok\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "write a log"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("git add failed for build.log:"))
+        .stderr(predicate::str::contains(
+            "ignored by one of your .gitignore",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_send_commit_message_override() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+    fs::write(project_dir.join("test-file.txt"), "hello world\n")?;
+    init_git_repo(project_dir)?;
+
+    let mock_response = "I changed the greeting because it felt stale.\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "-m", "Greet rust", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["log", "-1", "--pretty=%B"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "Greet rust");
+
+    // A configured prefix and a conventional commit type are added to the message.
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!(
+            "database_path: {}\ncommit_prefix: \"ai:\"\n",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+    let mock_response = "Greet the world\n\ntest-file.txt\n<<<<<<< SEARCH\nhello rust\n=======\nhello world\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args([
+            "send",
+            "--new",
+            "--commit-type",
+            "feat",
+            "--commit-scope",
+            "greeting",
+            "change it back",
+        ])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["log", "-1", "--pretty=%B"])
+        .output()?;
+    assert_eq!(
+        String::from_utf8(output.stdout)?.trim(),
+        "ai: feat(greeting): Greet the world"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_require_clean_tree() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+    fs::write(project_dir.join("edited.txt"), "hello world\n")?;
+    fs::write(project_dir.join("other.txt"), "unrelated\n")?;
+    init_git_repo(project_dir)?;
+
+    let mock_response = "feat: greet rust\n\nedited.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n";
+
+    // 1. Unrelated uncommitted work blocks the edits.
+    fs::write(project_dir.join("other.txt"), "work in progress\n")?;
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--require-clean-tree", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the working tree has uncommitted changes to other.txt",
+        ));
+    assert_eq!(
+        fs::read_to_string(project_dir.join("edited.txt"))?,
+        "hello world\n"
+    );
+
+    // 2. Untracked files don't count.
+    Command::new("git")
+        .current_dir(project_dir)
+        .args(["checkout", "other.txt"])
+        .assert()
+        .success();
+    fs::write(project_dir.join("scratch.txt"), "notes\n")?;
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--require-clean-tree", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(project_dir.join("edited.txt"))?,
+        "hello rust\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_commits_only_edited_files() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+    fs::write(project_dir.join("edited.txt"), "hello world\n")?;
+    fs::write(project_dir.join("other.txt"), "unrelated\n")?;
+    init_git_repo(project_dir)?;
+
+    // Work the user already staged must not be swept into the commit.
+    fs::write(project_dir.join("other.txt"), "staged work\n")?;
+    Command::new("git")
+        .current_dir(project_dir)
+        .args(["add", "other.txt"])
+        .assert()
+        .success();
+
+    let mock_response = "feat: greet rust\n\nedited.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n\nnew.txt\n<<<<<<< SEARCH\n=======\nfresh\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["show", "--name-only", "--pretty=format:", "HEAD"])
+        .output()?;
+    assert_eq!(
+        String::from_utf8(output.stdout)?.trim(),
+        "edited.txt\nnew.txt"
+    );
+
+    let output = Command::new("git")
+        .current_dir(project_dir)
+        .args(["diff", "--staged", "--name-only"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "other.txt");
+
+    Ok(())
+}
+
+#[test]
+fn test_send_applies_near_miss_search_block() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+    let file = project_dir.join("lib.rs");
+    fs::write(&file, "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n")?;
+    init_git_repo(project_dir)?;
+
+    // The first and last SEARCH lines are truncated.
+    let mock_response = "fix: subtract\n\nlib.rs\n<<<<<<< SEARCH\nfn add(a\n    a + b\n}\n=======\nfn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: SEARCH block for lib.rs matched only after treating its first and last lines as prefixes.",
+        ));
+    assert_eq!(
+        fs::read_to_string(&file)?,
+        "fn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_send_review_response() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+    let file = project_dir.join("test-file.txt");
+    fs::write(&file, "hello world\n")?;
+    init_git_repo(project_dir)?;
+
+    let mock_response = "feat: greet\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello python\n>>>>>>> REPLACE\n";
+    let reviewed = "feat: greet rust\n\ntest-file.txt\n<<<<<<< SEARCH\nhello world\n=======\nhello rust\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--review", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .env("MOCK_REVIEW_CONTENT", reviewed)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Using the reviewed response."));
+    assert_eq!(fs::read_to_string(&file)?, "hello rust\n");
+
+    // The reviewed text is what's stored.
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["show", "2"])
+        .env("HOME", &home_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("hello rust"))
+        .stdout(predicate::str::contains("hello python").not());
+
+    // Emptying the response applies nothing.
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--review", "change it"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .env("MOCK_REVIEW_CONTENT", "\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("The reviewed response is empty"));
+    assert_eq!(fs::read_to_string(&file)?, "hello rust\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_send_max_edits_per_response() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(&home_dir)?;
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!(
+            "database_path: {}\nmax_edits_per_response: 1\n",
+            db_path.to_str().unwrap()
+        ),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+    fs::write(project_dir.join("a.txt"), "a\n")?;
+    fs::write(project_dir.join("b.txt"), "b\n")?;
+    init_git_repo(project_dir)?;
+
+    let mock_response = "chore: rewrite both\n\na.txt\n<<<<<<< SEARCH\na\n=======\nA\n>>>>>>> REPLACE\n\nb.txt\n<<<<<<< SEARCH\nb\n=======\nB\n>>>>>>> REPLACE\n";
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "rewrite"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the response has 2 edit blocks, over max_edits_per_response of 1. Pass --force",
+        ));
+    assert_eq!(fs::read_to_string(project_dir.join("a.txt"))?, "a\n");
+    assert_eq!(fs::read_to_string(project_dir.join("b.txt"))?, "b\n");
+
+    Command::cargo_bin("retort")?
+        .current_dir(project_dir)
+        .args(["send", "--new", "--force", "rewrite"])
+        .env("HOME", &home_dir)
+        .env("MOCK_LLM_CONTENT", mock_response)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(project_dir.join("a.txt"))?, "A\n");
+    assert_eq!(fs::read_to_string(project_dir.join("b.txt"))?, "B\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_replay_history() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let home_dir = temp_dir.path();
+    let db_path = home_dir.join("test.db");
+
+    let config_dir = home_dir.join(".retort");
+    fs::create_dir_all(&config_dir)?;
+    fs::write(
+        config_dir.join("config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let create = "feat: add greeting\n\nsrc/hello.txt\n<<<<<<< SEARCH\n=======\nhello\n>>>>>>> REPLACE\n";
+        let other = "docs: finish notes\n\nnotes.txt\n<<<<<<< SEARCH\ndraft\n=======\nfinal\n>>>>>>> REPLACE\n";
+        let update = "fix: greet the world\n\n./src/hello.txt\n<<<<<<< SEARCH\nhello\n=======\nhello world\n>>>>>>> REPLACE\n\nsrc/hello.txt\n<<<<<<< SEARCH\nmissing\n=======\nnever\n>>>>>>> REPLACE\n";
+        let mut parent = None;
+        for (role, content) in [
+            ("user", "add a greeting"),
+            ("assistant", create),
+            ("user", "notes"),
+            ("assistant", other),
+            ("user", "broaden it"),
+            ("assistant", update),
+        ] {
+            parent = Some(retort::db::add_message(&conn, parent, role, content, None)?);
+        }
+        retort::db::set_chat_tag(&conn, "greet", parent.unwrap())?;
+    }
+
+    Command::cargo_bin("retort")?
+        .args(["replay-history", "greet", "--to", "src/hello.txt"])
+        .env("HOME", home_dir)
+        .env("NO_COLOR", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::diff(
+            "=== src/hello.txt after message 2 (1 edit(s)) ===\nhello\n=== src/hello.txt after message 6 (1 edit(s)) ===\nhello world\n",
+        ))
+        .stderr(predicate::str::contains(
+            "Warning: skipping an edit from message 6: SEARCH block not found in file src/hello.txt",
+        ));
+    // Nothing is written to disk.
+    assert!(!home_dir.join("src/hello.txt").exists());
+
+    // Edits to a file the chat didn't create need the version it started from.
+    let base = home_dir.join("base.txt");
+    fs::write(&base, "draft\nnotes\n")?;
+    Command::cargo_bin("retort")?
+        .args(["replay-history", "greet", "--to", "notes.txt", "--base"])
+        .arg(&base)
+        .env("HOME", home_dir)
+        .env("NO_COLOR", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::diff(
+            "=== notes.txt after message 4 (1 edit(s)) ===\nfinal\nnotes\n",
+        ));
+    Command::cargo_bin("retort")?
+        .args(["replay-history", "greet", "--to", "other.txt"])
+        .env("HOME", home_dir)
+        .assert()
+        .success()
+        .stdout("No edits to other.txt in the history of 'greet'.\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_export_commit() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let project_dir = temp_dir.path();
+    let home_dir = project_dir.join("home");
+    fs::create_dir_all(home_dir.join(".retort"))?;
+    let db_path = home_dir.join("test.db");
+    fs::write(
+        home_dir.join(".retort/config.yaml"),
+        format!("database_path: {}", db_path.to_str().unwrap()),
+    )?;
+    fs::write(project_dir.join(".gitignore"), "home/\n")?;
+    fs::write(project_dir.join("notes.txt"), "one\ntwo\nthree\n")?;
+    init_git_repo(project_dir)?;
+
+    {
+        let conn = retort::db::setup(db_path.to_str().unwrap())?;
+        let user_id = retort::db::add_message(&conn, None, "user", "edit the notes", None)?;
+        let response = "docs: number the notes\n\nnotes.txt\n<<<<<<< SEARCH\ntwo\n=======\n2\n>>>>>>> REPLACE\n\nnotes.txt\n<<<<<<< SEARCH\nthree\n=======\n3\n>>>>>>> REPLACE\n\nnew.txt\n<<<<<<< SEARCH\n=======\nfresh\n>>>>>>> REPLACE\n";
+        retort::db::add_message(&conn, Some(user_id), "assistant", response, None)?;
+    }
+
+    let retort = || -> Result<Command> {
+        let mut command = Command::cargo_bin("retort")?;
+        command.current_dir(project_dir).env("HOME", &home_dir);
+        Ok(command)
+    };
+    retort()?
+        .args(["export-commit", "2"])
+        .assert()
+        .success()
+        .stdout(predicate::str::diff(
+            "docs: number the notes\n\n\
+             diff --git a/notes.txt b/notes.txt\n--- a/notes.txt\n+++ b/notes.txt\n\
+             @@ -1,3 +1,3 @@\n one\n-two\n-three\n+2\n+3\n\
+             diff --git a/new.txt b/new.txt\nnew file mode 100644\n--- /dev/null\n+++ b/new.txt\n\
+             @@ -0,0 +1,1 @@\n+fresh\n",
+        ));
+    // Nothing is written to the working tree.
+    assert_eq!(
+        fs::read_to_string(project_dir.join("notes.txt"))?,
+        "one\ntwo\nthree\n"
+    );
+    assert!(!project_dir.join("new.txt").exists());
+
+    retort()?
+        .args(["export-commit", "2", "--output", "home/edits.patch"])
+        .assert()
+        .success()
+        .stdout("Wrote a patch changing 2 file(s) to home/edits.patch.\n");
+    Command::new("git")
+        .current_dir(project_dir)
+        .args(["apply", "home/edits.patch"])
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(project_dir.join("notes.txt"))?,
+        "one\n2\n3\n"
+    );
+    assert_eq!(fs::read_to_string(project_dir.join("new.txt"))?, "fresh\n");
+
+    // Once applied, the SEARCH blocks no longer match.
+    retort()?
+        .args(["export-commit", "2"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "2 of 3 edits could not be applied",
+        ));
+    retort()?
+        .args(["export-commit", "1"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Message 1 is a user message; only assistant messages have edits to export.",
+        ));
+
     Ok(())
 }
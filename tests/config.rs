@@ -1,5 +1,5 @@
 use anyhow::Result;
-use retort::config::load;
+use retort::config::{init, load, Config, CONFIG_TEMPLATE};
 use std::env;
 use std::sync::Mutex;
 use tempfile::tempdir;
@@ -52,3 +52,114 @@ fn test_load_with_tilde_expansion_in_config() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_load_from_retort_config_env() -> Result<()> {
+    let _lock = ENV_MUTEX.lock().unwrap();
+    let temp_dir = tempdir()?;
+    env::set_var("HOME", temp_dir.path());
+    let dotfiles_dir = temp_dir.path().join("dotfiles");
+    std::fs::create_dir_all(&dotfiles_dir)?;
+    std::fs::write(
+        dotfiles_dir.join("retort.yaml"),
+        "database_path: ~/db/from_env.db",
+    )?;
+
+    // Tilde expansion applies to the env-provided path too.
+    env::set_var("RETORT_CONFIG", "~/dotfiles/retort.yaml");
+    let result = load();
+
+    // An explicit config path that doesn't exist is an error.
+    env::set_var("RETORT_CONFIG", "~/dotfiles/missing.yaml");
+    let missing = load();
+    env::remove_var("RETORT_CONFIG");
+
+    let config = result?;
+    let expected_path = temp_dir.path().join("db/from_env.db");
+    assert_eq!(config.database_path, expected_path.to_str().unwrap());
+    assert!(missing
+        .unwrap_err()
+        .to_string()
+        .contains("(from RETORT_CONFIG) not found."));
+
+    Ok(())
+}
+
+#[test]
+fn test_load_from_retort_home_env() -> Result<()> {
+    let _lock = ENV_MUTEX.lock().unwrap();
+    let temp_dir = tempdir()?;
+    env::set_var("HOME", temp_dir.path());
+    let retort_home = temp_dir.path().join("ci");
+
+    // Without a config file, the database defaults to a path under RETORT_HOME.
+    env::set_var("RETORT_HOME", &retort_home);
+    let default = load();
+
+    // A config file is read from RETORT_HOME, and its keys still win.
+    std::fs::create_dir_all(&retort_home)?;
+    std::fs::write(retort_home.join("config.yaml"), "backend: openai")?;
+    let from_file = load();
+    std::fs::write(
+        retort_home.join("config.yaml"),
+        "database_path: ~/db/mine.db",
+    )?;
+    let overridden = load();
+    env::remove_var("RETORT_HOME");
+
+    let expected_path = retort_home.join("data/retort.db");
+    assert_eq!(default?.database_path, expected_path.to_str().unwrap());
+    let from_file = from_file?;
+    assert_eq!(from_file.backend, "openai");
+    assert_eq!(from_file.database_path, expected_path.to_str().unwrap());
+    let expected_path = temp_dir.path().join("db/mine.db");
+    assert_eq!(overridden?.database_path, expected_path.to_str().unwrap());
+
+    Ok(())
+}
+
+#[test]
+fn test_config_template_lists_every_key() -> Result<()> {
+    // The template must parse, and mention every key, set or commented out.
+    let config: Config = serde_yaml::from_str(CONFIG_TEMPLATE)?;
+    assert_eq!(config.backend, "google");
+    let defaults = serde_yaml::to_value(Config::default())?;
+    for key in defaults.as_mapping().unwrap().keys() {
+        let key = key.as_str().unwrap();
+        assert!(
+            CONFIG_TEMPLATE.contains(&format!("\n{}:", key))
+                || CONFIG_TEMPLATE.contains(&format!("\n# {}:", key)),
+            "{} is missing from the config template",
+            key
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_init_writes_config() -> Result<()> {
+    let _lock = ENV_MUTEX.lock().unwrap();
+    let temp_dir = tempdir()?;
+    env::set_var("HOME", temp_dir.path());
+
+    let path = init(false)?;
+    assert_eq!(path, temp_dir.path().join(".retort/config.yaml"));
+    assert_eq!(std::fs::read_to_string(&path)?, CONFIG_TEMPLATE);
+    assert!(load().is_ok());
+
+    std::fs::write(&path, "database_path: /tmp/mine.db")?;
+    let refused = init(false);
+    assert_eq!(
+        std::fs::read_to_string(&path)?,
+        "database_path: /tmp/mine.db"
+    );
+    assert!(refused
+        .unwrap_err()
+        .to_string()
+        .contains("already exists; pass --force to overwrite it."));
+
+    init(true)?;
+    assert_eq!(std::fs::read_to_string(&path)?, CONFIG_TEMPLATE);
+
+    Ok(())
+}
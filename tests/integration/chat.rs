@@ -1,16 +1,9 @@
 use anyhow::Result;
 use retort::db;
-use rusqlite::Connection;
-
-fn setup_in_memory_db() -> Result<Connection> {
-    // The existing setup function handles schema creation.
-    // Passing ":memory:" to rusqlite creates an in-memory database.
-    db::setup(":memory:")
-}
 
 #[test]
 fn test_chat_flow() -> Result<()> {
-    let conn = setup_in_memory_db()?;
+    let conn = db::setup_in_memory()?;
 
     // 1. Create a root message for a new chat.
     let root_id = db::add_message(&conn, None, "user", "Hello, world!", None)?;
@@ -59,7 +52,7 @@ fn test_chat_flow() -> Result<()> {
 
 #[test]
 fn test_profile_flow() -> Result<()> {
-    let conn = setup_in_memory_db()?;
+    let conn = db::setup_in_memory()?;
 
     // 1. Default profile should exist with no active chat.
     let profile = db::get_profile_by_name(&conn, "default")?;
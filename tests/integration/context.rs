@@ -1,16 +1,9 @@
 use anyhow::Result;
 use retort::db;
-use rusqlite::Connection;
-
-fn setup_in_memory_db() -> Result<Connection> {
-    // The existing setup function handles schema creation.
-    // Passing ":memory:" to rusqlite creates an in-memory database.
-    db::setup(":memory:")
-}
 
 #[test]
 fn test_context_stage_flow() -> Result<()> {
-    let conn = setup_in_memory_db()?;
+    let conn = db::setup_in_memory()?;
 
     // 1. Initial default stage should be empty.
     let stage = db::get_context_stage(&conn, "default")?;
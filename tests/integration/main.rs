@@ -1,16 +1,9 @@
 use anyhow::Result;
 use retort::db;
-use rusqlite::Connection;
-
-fn setup_in_memory_db() -> Result<Connection> {
-    // The existing setup function handles schema creation.
-    // Passing ":memory:" to rusqlite creates an in-memory database.
-    db::setup(":memory:")
-}
 
 #[test]
 fn test_chat_flow() -> Result<()> {
-    let conn = setup_in_memory_db()?;
+    let conn = db::setup_in_memory()?;
 
     // 1. Create a root message for a new chat.
     let root_id = db::add_message(&conn, None, "user", "Hello, world!", None)?;
@@ -47,3 +40,223 @@ fn test_chat_flow() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_history_order_ignores_timestamps() -> Result<()> {
+    let conn = db::setup_in_memory()?;
+
+    let u1 = db::add_message(&conn, None, "user", "first", None)?;
+    let a1 = db::add_message(&conn, Some(u1), "assistant", "second", None)?;
+    let u2 = db::add_message(&conn, Some(a1), "user", "third", None)?;
+
+    // Scramble the timestamps so they disagree with the tree order.
+    conn.execute(
+        "UPDATE messages SET created_at = '2030-01-01 00:00:00' WHERE id = ?1",
+        [u1],
+    )?;
+    conn.execute(
+        "UPDATE messages SET created_at = 'not a timestamp' WHERE id = ?1",
+        [a1],
+    )?;
+
+    let history = db::get_conversation_history(&conn, u2)?;
+    let contents: Vec<&str> = history.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["first", "second", "third"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_leaf_last_user_content() -> Result<()> {
+    let conn = db::setup_in_memory()?;
+
+    let u1 = db::add_message(&conn, None, "user", "question", None)?;
+    let a1 = db::add_message(&conn, Some(u1), "assistant", "answer", None)?;
+    db::add_message(&conn, Some(a1), "system", "note", None)?;
+    db::add_message(&conn, Some(a1), "user", "follow-up", None)?;
+    db::add_message(&conn, None, "assistant", "standalone", None)?;
+
+    let mut previews: Vec<(String, Option<String>)> = db::get_leaf_messages(&conn)?
+        .into_iter()
+        .map(|leaf| (leaf.content, leaf.last_user_content))
+        .collect();
+    previews.sort();
+    assert_eq!(
+        previews,
+        vec![
+            ("follow-up".to_string(), Some("follow-up".to_string())),
+            ("note".to_string(), Some("question".to_string())),
+            ("standalone".to_string(), None),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_context_presets() -> Result<()> {
+    let conn = db::setup_in_memory()?;
+
+    db::add_file_to_stage(&conn, "default", "src/db.rs", false)?;
+    db::add_file_to_stage(&conn, "default", "README.md", true)?;
+    db::remove_file_from_stage(&conn, "default", "src/old.rs")?;
+    let stage = db::get_context_stage(&conn, "default")?;
+
+    // 1. Save a snapshot of the stage, then clear it.
+    assert!(!db::save_context_preset(&conn, "backend-work", &stage)?);
+    db::clear_context_stage(&conn, "default")?;
+
+    // 2. Loading the preset replays its files into the stage.
+    let preset = db::get_context_preset(&conn, "backend-work")?.unwrap();
+    db::load_context_preset(&conn, "default", &preset)?;
+    let loaded = db::get_context_stage(&conn, "default")?;
+    assert_eq!(loaded.read_write_files, vec!["src/db.rs"]);
+    assert_eq!(loaded.read_only_files, vec!["README.md"]);
+    assert_eq!(loaded.dropped_files, vec!["src/old.rs"]);
+
+    // 3. Saving under the same name replaces the preset.
+    assert!(db::save_context_preset(&conn, "backend-work", &loaded)?);
+    let presets = db::list_context_presets(&conn)?;
+    assert_eq!(presets.len(), 1);
+    assert_eq!(presets[0].name, "backend-work");
+
+    // 4. Deleting removes it.
+    assert!(db::delete_context_preset(&conn, "backend-work")?);
+    assert!(!db::delete_context_preset(&conn, "backend-work")?);
+    assert!(db::get_context_preset(&conn, "backend-work")?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_import_keeps_created_at() -> Result<()> {
+    let conn = db::setup_in_memory()?;
+
+    let root = db::add_message_at(
+        &conn,
+        None,
+        "user",
+        "old",
+        None,
+        Some("2020-05-01 12:00:00"),
+    )?;
+    assert_eq!(
+        db::get_message(&conn, root)?.unwrap().created_at,
+        "2020-05-01 12:00:00"
+    );
+
+    let dump: db::ChatDump = serde_json::from_str(
+        r#"{"messages": [
+            {"id": 1, "role": "user", "content": "hi", "created_at": "2021-01-02 03:04:05"},
+            {"id": 2, "parent_id": 1, "role": "assistant", "content": "hello"}
+        ]}"#,
+    )?;
+    db::import_dump(&conn, &dump)?;
+    assert_eq!(
+        db::get_message(&conn, 2)?.unwrap().created_at,
+        "2021-01-02 03:04:05"
+    );
+    // Messages without a timestamp get the import time.
+    assert_ne!(db::get_message(&conn, 3)?.unwrap().created_at, "");
+
+    let bad: db::ChatDump = serde_json::from_str(
+        r#"{"messages": [{"id": 1, "role": "user", "content": "hi", "created_at": "yesterday"}]}"#,
+    )?;
+    let err = db::import_dump(&conn, &bad).unwrap_err().to_string();
+    assert!(err.contains("Invalid created_at 'yesterday' for message 1 in import"));
+
+    // Metadata is checked against the shape its role stores, so a bad value can't break
+    // the chat later.
+    let bad: db::ChatDump = serde_json::from_str(
+        r#"{"messages": [
+            {"id": 1, "role": "user", "content": "hi", "metadata": "{\"read_write_files\": []}"},
+            {"id": 2, "parent_id": 1, "role": "assistant", "content": "hello", "metadata": "{\"streamed\": true}"}
+        ]}"#,
+    )?;
+    let err = db::import_dump(&conn, &bad).unwrap_err().to_string();
+    assert!(err.contains("Invalid metadata for message 1 in import"));
+    let before = db::get_leaf_messages(&conn)?.len();
+    let bad: db::ChatDump = serde_json::from_str(
+        r#"{"messages": [{"id": 1, "role": "assistant", "content": "hello", "metadata": "not json"}]}"#,
+    )?;
+    let err = db::import_dump(&conn, &bad).unwrap_err().to_string();
+    assert!(err.contains("Invalid metadata for message 1 in import"));
+    assert_eq!(db::get_leaf_messages(&conn)?.len(), before);
+
+    Ok(())
+}
+
+#[test]
+fn test_setup_adds_missing_columns() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let db_path = temp_dir.path().join("old.db");
+    let db_path = db_path.to_str().unwrap();
+    {
+        // A database from before conversations had a prompt template.
+        let conn = rusqlite::Connection::open(db_path)?;
+        conn.execute_batch(
+            "CREATE TABLE conversations (root_id INTEGER PRIMARY KEY NOT NULL, title TEXT, notes TEXT);
+             INSERT INTO conversations (root_id, title) VALUES (1, 'Old chat');",
+        )?;
+    }
+
+    let conn = db::setup(db_path)?;
+    let conversation = db::get_conversation(&conn, 1)?.unwrap();
+    assert_eq!(conversation.title.as_deref(), Some("Old chat"));
+    assert_eq!(conversation.prompt_template, None);
+    db::set_conversation_prompt_template(&conn, 1, Some("planning"))?;
+
+    // Running setup again leaves the migrated table alone.
+    let conn = db::setup(db_path)?;
+    let conversation = db::get_conversation(&conn, 1)?.unwrap();
+    assert_eq!(conversation.prompt_template.as_deref(), Some("planning"));
+
+    Ok(())
+}
+
+#[test]
+fn test_setup_restores_compacted_chains() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let db_path = temp_dir.path().join("compacted.db");
+    let db_path = db_path.to_str().unwrap();
+
+    // A chat whose second and third messages were folded into the row keyed by the fourth.
+    {
+        let conn = db::setup(db_path)?;
+        let u1 = db::add_message(&conn, None, "user", "one", None)?;
+        let u2 = db::add_message(&conn, Some(u1), "user", "two", None)?;
+        let u3 = db::add_message(&conn, Some(u2), "user", "three", None)?;
+        let u4 = db::add_message(&conn, Some(u3), "user", "four", None)?;
+        conn.execute_batch(&format!(
+            "
+            CREATE TABLE compacted_chains (
+                head_id INTEGER PRIMARY KEY NOT NULL,
+                messages TEXT NOT NULL,
+                FOREIGN KEY (head_id) REFERENCES messages (id)
+            );
+            INSERT INTO compacted_chains (head_id, messages)
+            SELECT {u4}, json_group_array(json_object(
+                'id', id, 'parent_id', parent_id, 'role', role, 'content', content,
+                'metadata', metadata, 'created_at', created_at))
+            FROM (SELECT * FROM messages WHERE id IN ({u2}, {u3}) ORDER BY id);
+            UPDATE messages SET parent_id = NULL WHERE id = {u4};
+            DELETE FROM messages WHERE id = {u3};
+            DELETE FROM messages WHERE id = {u2};
+            "
+        ))?;
+    }
+
+    let conn = db::setup(db_path)?;
+    assert_eq!(db::get_ancestor_ids(&conn, 4)?, vec![1, 2, 3, 4]);
+    let history = db::get_conversation_history(&conn, 4)?;
+    let contents: Vec<&str> = history.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, ["one", "two", "three", "four"]);
+    let table_count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE name = 'compacted_chains'",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(table_count, 0);
+
+    Ok(())
+}